@@ -0,0 +1,351 @@
+use std::collections::HashSet;
+
+use super::isa::Isa;
+use super::line::*;
+use super::parser::{self, Expr, ExprKind, SourceFormat, Span, Statement};
+use super::pass_one;
+use super::pass_two::{self, ObjectProgram};
+use super::diagnostic::{Code, Diagnostic, Field};
+use super::formatter;
+use super::listing::{self, Layout};
+use super::warnings::{self, WarningSet};
+
+/// Assembles SIC/XE source text in-process.
+///
+/// Options are set builder-style, and the same `Assembler` can be reused for
+/// any number of programs.
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    isa: Isa,
+    text: bool,
+    warnings: WarningSet,
+    source_format: SourceFormat,
+    layout: Layout,
+    defines: Vec<(String, i32)>,
+    override_defines: bool
+}
+
+/// Everything produced by a single assembly run.
+#[derive(Debug, Clone)]
+pub struct AssemblyResult {
+    /// The syntax tree of every source line, in the same order as `lines`.
+    pub statements: Vec<Statement>,
+    /// Every source line, with its location and generated object code.
+    pub lines: Vec<Line>,
+    /// The symbol table built in pass one.
+    pub symbols: Symtab,
+    /// The object program built in pass two.
+    pub program: ObjectProgram,
+    /// The object program, as ASCII records if `text` was set and in the
+    /// binary record format otherwise. Empty when there are errors.
+    pub object: Vec<u8>,
+    /// The assembly listing: each source line with its location and object
+    /// code, followed by the symbol table.
+    pub listing: String,
+    /// Problems found while assembling, sorted by source and line.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The name and text of every source, for rendering diagnostics.
+    pub sources: Vec<(String, String)>,
+    /// The index into `sources` of the source each line came from.
+    pub origin: Vec<usize>
+}
+
+impl AssemblyResult {
+    /// True when no diagnostic is an error.
+    pub fn is_ok(&self) -> bool {
+        !self.diagnostics.iter().any(|d| d.is_error())
+    }
+
+    /// Renders every diagnostic with a snippet of the source it points at.
+    pub fn render_diagnostics(&self) -> String {
+        let source_of = |name: &str| self.sources.iter().find(|s| s.0 == name).map(|s| &*s.1);
+        self.diagnostics.iter().map(|d| d.render(&source_of) + "\n").collect()
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Assembler {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            isa: Isa::sic_xe(),
+            text: false,
+            warnings: WarningSet::new(),
+            source_format: SourceFormat::Auto,
+            layout: Layout::new(),
+            defines: Vec::new(),
+            override_defines: false
+        }
+    }
+
+    /// Uses `isa` instead of the standard SIC/XE instruction set.
+    pub fn isa(mut self, isa: Isa) -> Self {
+        self.isa = isa;
+        self
+    }
+
+    /// The instruction set used to assemble.
+    pub fn instruction_set(&self) -> &Isa {
+        &self.isa
+    }
+
+    /// Generates the object program as ASCII records instead of binary ones.
+    pub fn text(mut self, t: bool) -> Self {
+        self.text = t;
+        self
+    }
+
+    /// Selects which warnings are reported and which are treated as errors.
+    /// Sources can adjust these further with `.pragma` comments.
+    pub fn warnings(mut self, w: WarningSet) -> Self {
+        self.warnings = w;
+        self
+    }
+
+    /// Selects how source lines are laid out. By default the layout of each
+    /// source is detected from its contents.
+    pub fn source_format(mut self, f: SourceFormat) -> Self {
+        self.source_format = f;
+        self
+    }
+
+    /// Sets the page length and width of the listing.
+    pub fn listing_layout(mut self, l: Layout) -> Self {
+        self.layout = l;
+        self
+    }
+
+    /// Defines `name` as an absolute symbol with the value `value`, as if by
+    /// an EQU before the first line.
+    pub fn define(mut self, name: &str, value: i32) -> Self {
+        self.defines.retain(|d| d.0 != name);
+        self.defines.push((name.to_owned(), value));
+        self
+    }
+
+    /// Lets symbols given to `define` replace EQU definitions of the same
+    /// symbol in the source, instead of reporting them as duplicates.
+    pub fn override_defines(mut self, o: bool) -> Self {
+        self.override_defines = o;
+        self
+    }
+
+    pub fn assemble(&self, name: &str, source: &str) -> AssemblyResult {
+        self.assemble_sources(&[(name, source)])
+    }
+
+    /// Rewrites `source` into the canonical layout described in the
+    /// `formatter` module. Sources with syntax errors are left alone, as is
+    /// any source whose meaning the new layout would change.
+    pub fn format(&self, name: &str, source: &str) -> Result<String, Vec<Diagnostic>> {
+        let (statements, errors) = parser::parse(source, &self.isa, self.source_format);
+        if !errors.is_empty() {
+            return Err(errors.into_iter().map(|(_, d)| d.file(name)).collect());
+        }
+        let formatted = formatter::format(source, &statements, &self.isa);
+        let (again, errors) = parser::parse(&formatted, &self.isa, SourceFormat::Auto);
+        let changed = statements.iter().zip(&again).enumerate().find(|(k, (a, b))| {
+            errors.iter().any(|e| e.0 == *k) || formatter::meaning(a, &self.isa) != formatter::meaning(b, &self.isa)
+        });
+        match changed {
+            Some((_, (s, _))) => Err(vec![
+                Diagnostic::error(Code::Syntax, "this line cannot be formatted without changing its meaning")
+                    .file(name)
+                    .line(s.line_no)
+                    .cols(0..s.span.end - s.span.start)
+            ]),
+            None => Ok(formatted)
+        }
+    }
+
+    /// Assembles several named sources, in order, as a single program.
+    ///
+    /// Line numbers restart at 1 in each source, and diagnostics carry the name
+    /// of the source they came from. Both passes run even if earlier stages
+    /// found errors, so that every problem is reported at once.
+    pub fn assemble_sources(&self, sources: &[(&str, &str)]) -> AssemblyResult {
+        let mut statements: Vec<Statement> = Vec::new();
+        let mut found = Vec::new();
+        // The index into `sources` and the text of each entry in `statements`.
+        let mut origin: Vec<usize> = Vec::new();
+        let mut texts: Vec<&str> = Vec::new();
+
+        for (n, &(_, source)) in sources.iter().enumerate() {
+            let (stmts, errors) = parser::parse(source, &self.isa, self.source_format);
+            found.extend(errors.into_iter().map(|(k, d)| (statements.len() + k, d)));
+            for (s, text) in stmts.into_iter().zip(source.lines()) {
+                statements.push(s);
+                origin.push(n);
+                texts.push(text);
+            }
+        }
+        let mut lines: Vec<Line> = statements.iter().map(|s| parser::lower(s, &self.isa)).collect();
+
+        // Only the first error on a line is reported: a line that does not
+        // parse is not checked further, and one that fails pass one is not
+        // reported again by pass two.
+        let mut failed: HashSet<usize> = found.iter().map(|&(k, _)| k).collect();
+        let (symbols, pass_one) = pass_one::run(&mut lines, &self.defines, self.override_defines);
+        found.extend(pass_one.into_iter().filter(|(k, _)| !failed.contains(k)));
+        failed.extend(found.iter().map(|&(k, _)| k));
+        let (program, pass_two) = pass_two::encode(&mut lines, &symbols);
+        found.extend(pass_two.into_iter().filter(|(k, _)| !failed.contains(k)));
+        found.extend(warnings::check(&lines, &origin, &statements, &symbols, &self.warnings));
+        found.sort_by_key(|&(k, _)| k);
+
+        let mut diagnostics: Vec<Diagnostic> = found
+            .into_iter()
+            .map(|(k, d)| match origin.get(k).or_else(|| origin.last()) {
+                Some(&n) => pin(d, sources[n].0, statements.get(k).or_else(|| statements.last())),
+                // Only possible when there are no lines at all.
+                None => d.file(sources.first().map_or("", |s| s.0))
+            })
+            .collect();
+
+        // Related locations name a symbol; point them at the line defining it.
+        for r in diagnostics.iter_mut().flat_map(|d| d.related.iter_mut()) {
+            let hint = match r.hint.take() {
+                Some(h) => h,
+                None => continue
+            };
+            let def = statements.iter().zip(&origin)
+                .find(|(s, _)| s.line_no == r.location.line && s.label.as_ref().is_some_and(|l| l.text == hint));
+            if let Some((s, &n)) = def {
+                r.location.file = sources[n].0.to_owned();
+                r.location.cols = span_of(s, &Field::Label).unwrap_or(0..0);
+            }
+        }
+
+        let files: Vec<&str> = origin.iter().map(|&n| sources[n].0).collect();
+        let listing = listing::render(&lines, &texts, &files, &symbols, &self.layout);
+
+        diagnostics.sort_by_key(|d| (sources.iter().position(|s| s.0 == d.location.file), d.location.line, !d.is_error()));
+
+        let mut result = AssemblyResult {
+            object: if self.text { program.to_text().into_bytes() } else { program.to_binary() },
+            listing,
+            statements,
+            lines,
+            symbols,
+            program,
+            diagnostics,
+            sources: sources.iter().map(|&(n, s)| (n.to_owned(), s.to_owned())).collect(),
+            origin
+        };
+        if !result.is_ok() {
+            result.object.clear();
+        }
+        result
+    }
+}
+
+// The first use of the symbol `name` in `e`.
+fn find_symbol<'a>(e: &'a Expr, name: &str) -> Option<&'a Expr> {
+    match e.kind {
+        ExprKind::Symbol(ref x) if x == name => Some(e),
+        ExprKind::Neg(ref a) => find_symbol(a, name),
+        ExprKind::Binary(_, ref a, ref b) => find_symbol(a, name).or_else(|| find_symbol(b, name)),
+        _ => None
+    }
+}
+
+// The columns of `field` in the line of `s`, if the line has it.
+fn span_of(s: &Statement, field: &Field) -> Option<Span> {
+    let span = match *field {
+        Field::Label => s.label.as_ref()?.span.clone(),
+        Field::Mnemonic => s.mnemonic.as_ref()?.span.clone(),
+        Field::Operand(n) => s.operands.get(n)?.expr.span.clone(),
+        Field::Symbol(ref x) => match s.operands.iter().find_map(|o| find_symbol(&o.expr, x)) {
+            Some(e) => e.span.clone(),
+            None => s.label.as_ref().filter(|l| l.text == *x)?.span.clone()
+        },
+        Field::Comment(ref r) => {
+            let c = s.comment.as_ref()?;
+            c.span.start + r.start..c.span.start + r.end
+        }
+    };
+    Some(span.start - s.span.start..span.end - s.span.start)
+}
+
+// The columns from the label or mnemonic to the last operand of `s`.
+fn code_span(s: &Statement) -> Span {
+    let spans: Vec<&Span> = s.label.iter().map(|l| &l.span)
+        .chain(s.mnemonic.iter().map(|m| &m.span))
+        .chain(s.operands.iter().map(|o| &o.span))
+        .collect();
+    match (spans.first(), spans.last()) {
+        (Some(first), Some(last)) => first.start - s.span.start..last.end - s.span.start,
+        _ => 0..0
+    }
+}
+
+// Fills in the file and columns of a diagnostic raised on the line of `s`.
+fn pin(mut d: Diagnostic, name: &str, s: Option<&Statement>) -> Diagnostic {
+    if let Some(s) = s {
+        let field = d.field.take();
+        if field.is_some() || d.location.cols.is_empty() {
+            d.location.cols = field.and_then(|f| span_of(s, &f)).unwrap_or_else(|| code_span(s));
+        }
+    }
+    d.file(name)
+}
+
+#[test]
+fn assemble_in_process() {
+    let mut quiet = WarningSet::new();
+    quiet.apply("-Wnone").unwrap();
+    let src = "PROG START 1000\nFIRST LDA FIVE\n RSUB\nFIVE WORD 5\n END FIRST\n";
+    let result = Assembler::new().text(true).assemble("prog.asm", src);
+    assert!(result.is_ok());
+    assert_eq!(result.symbols.get("FIVE").unwrap().mem_loc, 1006);
+    assert!(String::from_utf8(result.object).unwrap().starts_with("HPROG"));
+
+    let result = Assembler::new().warnings(quiet.clone()).assemble_sources(&[("a.asm", "PROG START 0\n"), ("b.asm", " FOO X\n END PROG\n")]);
+    assert_eq!(result.diagnostics.len(), 1);
+    assert_eq!(result.diagnostics[0].to_string(), "b.asm:1:2: error[E002]: invalid opcode");
+
+    let result = Assembler::new().warnings(quiet.clone()).assemble("dup.asm", "PROG START 0\nA1 RSUB\n  A1 RSUB\n END PROG\n");
+    let related = &result.diagnostics[0].related[0].location;
+    assert_eq!((&*related.file, related.line, related.cols.clone()), ("dup.asm", 2, 0..2));
+
+    let src = "PROG START 0\n LDA NOPE\n LDA #5000\n STA BUF,A\n SHIFTL A,20\nBUF RESB 4096\n LDA PROG\n";
+    let result = Assembler::new().warnings(quiet.clone()).assemble("bad.asm", src);
+    let codes: Vec<_> = result.diagnostics.iter().map(|d| (d.location.line, d.code.as_str())).collect();
+    assert_eq!(codes, vec![(2, "E005"), (3, "E008"), (4, "E009"), (5, "E008"), (7, "E008"), (7, "E010")]);
+    assert!(result.object.is_empty());
+
+    let result = Assembler::new().warnings(quiet.clone()).assemble("order.asm", "PROG START 0\n LDA NOPE\n FOO\n END PROG\n");
+    let lines: Vec<_> = result.diagnostics.iter().map(|d| d.location.line).collect();
+    assert_eq!(lines, vec![2, 3]);
+    assert!(result.object.is_empty());
+
+    let src = "PROG START 0\n Lda #-1\n J PROG\nUNUSED WORD 1\n.pragma -Werror=fall-into-data\n LDA UNUSED\nLAST RESW 1\n END PROG\n";
+    let result = Assembler::new().assemble("warn.asm", src);
+    let codes: Vec<_> = result.diagnostics.iter().map(|d| (d.location.line, d.code.as_str(), d.is_error())).collect();
+    assert_eq!(codes, vec![(2, "W006", false), (2, "W007", false), (6, "W004", true), (7, "W001", false)]);
+
+    // Columns come from the parsed fields, even when the label holds the same
+    // text.
+    let src = "PROG START 0\nLOOP LDA OOP\nxLda LDA LOOP\nBUF WORD 1\n END PROG\n";
+    let result = Assembler::new().assemble("cols.asm", src);
+    let found: Vec<_> = result.diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(found, vec![
+        "cols.asm:2:10: error[E005]: undefined symbol OOP",
+        "cols.asm:3:1: warning[W001]: label xLda is never referenced [-Wunreferenced-label]",
+        "cols.asm:3:6: warning[W004]: execution falls through from LDA into data [-Wfall-into-data]",
+        "cols.asm:4:1: warning[W001]: label BUF is never referenced [-Wunreferenced-label]"
+    ]);
+
+    let src = "PROG START 0\nSIZE EQU 10\nBUF RESB SIZE\n TD #DEV\n END PROG\n";
+    let defined = Assembler::new().warnings(quiet).define("DEV", 0xF3).define("SIZE", 20);
+    let codes: Vec<_> = defined.assemble("d.asm", src).diagnostics.iter().map(|d| (d.location.line, d.code.as_str())).collect();
+    assert_eq!(codes, vec![(2, "E001")]);
+    let result = defined.override_defines(true).assemble("d.asm", src);
+    assert!(result.is_ok());
+    assert_eq!(result.program.length, 23);
+    assert_eq!(result.lines[3].obj_code, vec![0xE1, 0x00, 0xF3]);
+}
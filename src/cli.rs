@@ -0,0 +1,499 @@
+use yacc::image::Raw;
+use yacc::listing::Layout;
+use yacc::trace;
+use yacc::{SourceFormat, WarningSet};
+
+pub const USAGE: &str = "\
+Usage: yacc [options] <input>...
+       yacc lsp [options]
+       yacc fmt [--check] [options] <input>...
+       yacc run [options] <input>
+       yacc debug [options] <input>
+       yacc gdb [--port <n>] [options] <input>
+
+Assembles each input separately. An input of `-` is read from standard input,
+and an output path of `-` is standard output. `yacc lsp` runs a language server
+over standard input and output, assembling with the options given to it.
+`yacc fmt` rewrites each input in place in canonical columns, or with
+--check, only lists the inputs that would change. `yacc run` assembles one
+input and runs it in a simulator, and `yacc debug` runs it under an
+interactive debugger; type `help` at its prompt for the commands. `yacc gdb`
+lets GDB control the run through its remote protocol, on standard input and
+output or on a local TCP port.
+
+Output:
+-o <path>             - Write the object program to <path>.
+--emit <kind>[=<path>] - Write <kind> of output, to <path> if given. Can be repeated.
+                        Kinds, and where they are written by default:
+                          binary   object program in binary records  <input>_out
+                          text     object program as ASCII records   <input>_out
+                          ihex     Intel HEX                         <input>.hex
+                          srec     Motorola S-records                <input>.srec
+                          raw      flat memory image                 <input>.bin
+                          listing  assembly listing                  <input>.lst
+                          json     the whole assembly result         standard output
+                          xref     cross-reference listing           standard output
+                        Without --emit, the binary object program is written.
+--listing <path>      - Same as --emit listing=<path>.
+-text                 - Same as --emit text.
+--xref                - Same as --emit xref.
+
+Assembly:
+--isa <file>          - Add or replace instructions using the definitions in <file>.
+--format <format>     - Read the source as auto (the default), free or fixed columns.
+-D <name>[=<value>]   - Define <name> as a constant, 1 if no value is given. Values are
+                        decimal, or hexadecimal written as 0x1F or 1Fh.
+--override-defines    - Let -D replace EQU definitions of the same name in the source,
+                        instead of reporting them as duplicates.
+-W<name>, -Wno-<name> - Enable or disable a warning.
+-Wall, -Wnone         - Enable or disable all warnings.
+-Werror[=<name>]      - Treat all warnings, or just <name>, as errors.
+
+Loading (hexadecimal numbers):
+--load <address>      - Load the program at <address> for ihex, srec and raw (default: the START address).
+--fill <byte>         - Fill gaps in the raw image, such as RESB and RESW, with <byte> (default 00).
+--pad <size>          - Pad the raw image with the fill byte to <size> bytes, at most 100000.
+--range <from>:<to>   - Only include the addresses from <from> up to <to>, at most 100000, in the raw image.
+
+Listing:
+--page-length <n>     - Print <n> lines on each page (default 60, 0 for no pages).
+--page-width <n>      - Fit the listing in <n> columns (default 80).
+--explain             - Explain how each instruction was encoded in the listing, which
+                        is written to standard output unless it is emitted elsewhere.
+
+Running (yacc run, yacc debug and yacc gdb):
+--input <dev>=<path>  - Give device <dev> (hexadecimal) the bytes of <path> to read.
+--output <dev>=<path> - Write what is written to device <dev> to <path> instead of
+                        standard output.
+--max-steps <n>       - Stop after <n> instructions (default 1000000).
+--restore <path>      - Start from the machine saved in the snapshot <path> rather
+                        than loading the program afresh.
+--port <n>            - Wait for GDB on 127.0.0.1 port <n> rather than speaking to it on
+                        standard input and output (yacc gdb only).
+--history <n>         - Keep the last <n> instructions for stepping backwards
+                        (default 10000; yacc debug only).
+--snapshot <path>     - Save a snapshot of the machine to <path> when the run stops
+                        (yacc run only).
+--trace               - Trace every instruction run on standard error.
+--trace-json          - Trace as one JSON object per instruction.
+--trace-file <path>   - Write the trace to <path>.
+--trace-range <from>:<to> - Only trace instructions at addresses from <from> up to <to>.
+--trace-sub <label>   - Only trace while the subroutine <label> is running. Can be repeated.
+--profile <path>      - Write the listing with the number of times each line ran,
+                        followed by counts by subroutine and device, to <path>.
+--coverage <path>     - Write an lcov tracefile of the lines and branches run to
+                        <path>, and a summary of what never ran to standard error.
+
+--help                - Print this message.
+--version             - Print the version.
+
+Warnings: unreferenced-label, label-on-base, base-without-ldb, fall-into-data,
+data-in-code, truncated-immediate, mixed-case
+
+Exit status: 0 on success, 1 if a program or instruction set definition has
+errors or a program run does not end normally, 2 for invalid options and 3 if a file cannot be read or written.
+";
+
+pub const EXIT_ERRORS: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_IO: i32 = 3;
+
+/// A kind of output `--emit` can select.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Emit {
+    Binary,
+    Text,
+    IntelHex,
+    Srec,
+    Raw,
+    Listing,
+    Json,
+    Xref
+}
+
+const EMITS: &[(&str, Emit)] = &[
+    ("binary", Emit::Binary),
+    ("text", Emit::Text),
+    ("ihex", Emit::IntelHex),
+    ("srec", Emit::Srec),
+    ("raw", Emit::Raw),
+    ("listing", Emit::Listing),
+    ("json", Emit::Json),
+    ("xref", Emit::Xref)
+];
+
+impl Emit {
+    /// True for the formats of the object program, which `-o` applies to.
+    pub fn is_object(&self) -> bool {
+        !matches!(self, Emit::Listing | Emit::Json | Emit::Xref)
+    }
+
+    /// True for outputs that are written even if the program has errors.
+    pub fn despite_errors(&self) -> bool {
+        matches!(self, Emit::Listing | Emit::Json)
+    }
+
+    // Where the output goes without a path of its own.
+    fn default_path(&self, input: &str) -> String {
+        let suffix = match self {
+            Emit::Binary | Emit::Text => "_out",
+            Emit::IntelHex => ".hex",
+            Emit::Srec => ".srec",
+            Emit::Raw => ".bin",
+            Emit::Listing => ".lst",
+            Emit::Json | Emit::Xref => return "-".to_owned()
+        };
+        if input == "-" { "-".to_owned() } else { input.to_owned() + suffix }
+    }
+}
+
+/// The options of `yacc run`.
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub inputs: Vec<(u8, String)>,
+    pub outputs: Vec<(u8, String)>,
+    pub max_steps: u64,
+    pub trace: Option<trace::Format>,
+    /// Where the trace goes; standard error if `None`.
+    pub trace_path: Option<String>,
+    pub trace_range: Option<(u32, u32)>,
+    pub trace_subroutines: Vec<String>,
+    /// Where the profile goes, if one is wanted.
+    pub profile: Option<String>,
+    /// Where the lcov tracefile goes, if coverage is wanted.
+    pub coverage: Option<String>,
+    /// A snapshot to start from instead of the loaded program.
+    pub restore: Option<String>,
+    /// Where to save a snapshot when the run stops.
+    pub snapshot: Option<String>,
+    /// The number of steps the debugger keeps for running backwards.
+    pub history: usize,
+    /// The TCP port to wait for GDB on, instead of standard input and output.
+    pub port: Option<u16>
+}
+
+/// Everything given on the command line for an assembly run.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub inputs: Vec<String>,
+    pub isa_files: Vec<String>,
+    pub warnings: WarningSet,
+    pub format: SourceFormat,
+    pub layout: Layout,
+    pub load_address: Option<u32>,
+    pub raw: Raw,
+    pub defines: Vec<(String, i32)>,
+    pub override_defines: bool,
+    /// For `fmt`, only report the inputs that are not formatted.
+    pub check: bool,
+    pub run: Run,
+    output: Option<String>,
+    emits: Vec<(Emit, Option<String>)>
+}
+
+impl Options {
+    /// The outputs to write for `input`, and the path of each.
+    pub fn outputs(&self, input: &str) -> Vec<(Emit, String)> {
+        self.emits.iter().map(|(e, path)| {
+            let path = path.clone().or_else(|| self.output.clone().filter(|_| e.is_object()));
+            (*e, path.unwrap_or_else(|| e.default_path(input)))
+        }).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Assemble(Box<Options>),
+    Lsp(Box<Options>),
+    Fmt(Box<Options>),
+    Run(Box<Options>),
+    Debug(Box<Options>),
+    Gdb(Box<Options>),
+    Help,
+    Version
+}
+
+// A number in hexadecimal, written as `1000`, `0x1000` or `1000h`.
+fn hex_number(x: &str) -> Option<u32> {
+    let digits = x.strip_prefix("0x").unwrap_or(x);
+    u32::from_str_radix(digits.strip_suffix(['h', 'H']).unwrap_or(digits), 16).ok()
+}
+
+// A hexadecimal range, `<from>:<to>`.
+fn hex_range(x: &str) -> Option<(u32, u32)> {
+    x.split_once(':')
+        .and_then(|(from, to)| Some((hex_number(from)?, hex_number(to)?)))
+        .filter(|(from, to)| from <= to)
+}
+
+// A device and a path, `<dev>=<path>`.
+fn device_path(x: &str, option: &str) -> Result<(u8, String), String> {
+    match x.split_once('=') {
+        Some((dev, path)) if !path.is_empty() => match hex_number(dev).filter(|d| *d <= 0xFF) {
+            Some(d) => Ok((d as u8, path.to_owned())),
+            None => Err(format!("{} requires a hexadecimal device number, not `{}`", option, dev))
+        },
+        _ => Err(format!("{} requires <dev>=<path>", option))
+    }
+}
+
+// A `-D` definition: `NAME` or `NAME=value`.
+fn definition(d: &str) -> Result<(String, i32), String> {
+    let (name, value) = d.split_once('=').unwrap_or((d, "1"));
+    let mut chars = name.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("-D requires a symbol name, not `{}`", name));
+    }
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, value)
+    };
+    let n = if digits.starts_with("0x") || digits.ends_with(['h', 'H']) { hex_number(digits) } else { digits.parse().ok() };
+    match n.map(|n| if negative { -(n as i64) } else { n as i64 }) {
+        Some(n) if (-0x800000..=0xFFFFFF).contains(&n) => Ok((name.to_owned(), n as i32)),
+        _ => Err(format!("the value of {} must be a number that fits in 24 bits, not `{}`", name, value))
+    }
+}
+
+/// Parses the arguments that follow the program name.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let mut opts = Options {
+        inputs: Vec::new(),
+        isa_files: Vec::new(),
+        warnings: WarningSet::new(),
+        format: SourceFormat::Auto,
+        layout: Layout::new(),
+        load_address: None,
+        raw: Raw::new(),
+        defines: Vec::new(),
+        override_defines: false,
+        check: false,
+        run: Run {
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            max_steps: 1_000_000,
+            trace: None,
+            trace_path: None,
+            trace_range: None,
+            trace_subroutines: Vec::new(),
+            profile: None,
+            coverage: None,
+            restore: None,
+            snapshot: None,
+            history: 10_000,
+            port: None
+        },
+        output: None,
+        emits: Vec::new()
+    };
+    let subcommand = args.first().map(|a| a.as_str()).filter(|a| ["lsp", "fmt", "run", "debug", "gdb"].contains(a));
+    let running = matches!(subcommand, Some("run") | Some("debug") | Some("gdb"));
+    let tracing = subcommand == Some("run");
+    let mut rest = args[subcommand.is_some() as usize..].iter();
+    while let Some(a) = rest.next() {
+        let mut value = || rest.next().map(|v| v.as_str()).ok_or(format!("{} requires a value", a));
+        match a.as_str() {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--version" | "-V" => return Ok(Command::Version),
+            "-o" => opts.output = Some(value()?.to_owned()),
+            "--emit" => {
+                let v = value()?;
+                let (name, path) = match v.split_once('=') {
+                    Some((name, path)) => (name, Some(path.to_owned())),
+                    None => (v, None)
+                };
+                let kind = EMITS.iter().find(|e| e.0 == name).map(|e| e.1).ok_or_else(|| {
+                    let names: Vec<&str> = EMITS.iter().map(|e| e.0).collect();
+                    format!("unknown output kind `{}`, expected one of {}", name, names.join(", "))
+                })?;
+                opts.emits.push((kind, path));
+            }
+            "--listing" => opts.emits.push((Emit::Listing, Some(value()?.to_owned()))),
+            "-text" => opts.emits.push((Emit::Text, None)),
+            "--xref" => opts.emits.push((Emit::Xref, None)),
+            "--isa" => opts.isa_files.push(value()?.to_owned()),
+            "--format" => {
+                opts.format = match value()? {
+                    "auto" => SourceFormat::Auto,
+                    "free" => SourceFormat::Free,
+                    "fixed" => SourceFormat::Fixed,
+                    _ => return Err("--format requires one of auto, free or fixed".to_owned())
+                };
+            }
+            "--load" => {
+                let addr = hex_number(value()?).filter(|x| *x <= 0x0FFFFF);
+                opts.load_address = Some(addr.ok_or("--load requires a hexadecimal address between 0 and FFFFF")?);
+            }
+            "--fill" => {
+                let b = hex_number(value()?).filter(|b| *b <= 0xFF).ok_or("--fill requires a hexadecimal byte")?;
+                opts.raw = opts.raw.fill(b as u8);
+            }
+            "--pad" => {
+                let n = hex_number(value()?).filter(|n| *n <= 0x100000).ok_or("--pad requires a hexadecimal size of at most 100000")?;
+                opts.raw = opts.raw.size(n as usize);
+            }
+            "--range" => {
+                let range = hex_range(value()?).filter(|r| r.1 <= 0x100000)
+                    .ok_or("--range requires a hexadecimal range within 0:100000, such as 1000:2000")?;
+                opts.raw = opts.raw.range(range.0, range.1);
+            }
+            "--page-length" | "--page-width" => {
+                let n = value()?.parse::<usize>().map_err(|_| format!("{} requires a number", a))?;
+                opts.layout = if a == "--page-length" { opts.layout.page_length(n) } else { opts.layout.page_width(n) };
+            }
+            "--explain" => opts.layout = opts.layout.explain(true),
+            "-D" => opts.defines.push(definition(value()?)?),
+            x if x.starts_with("-D") => opts.defines.push(definition(&x[2..])?),
+            "--override-defines" => opts.override_defines = true,
+            "--check" if subcommand == Some("fmt") => opts.check = true,
+            "--input" if running => opts.run.inputs.push(device_path(value()?, a)?),
+            "--output" if running => opts.run.outputs.push(device_path(value()?, a)?),
+            "--max-steps" if running => {
+                opts.run.max_steps = value()?.parse().map_err(|_| "--max-steps requires a number".to_owned())?;
+            }
+            "--trace" if tracing => opts.run.trace = Some(trace::Format::Text),
+            "--trace-json" if tracing => opts.run.trace = Some(trace::Format::Json),
+            "--trace-file" if tracing => opts.run.trace_path = Some(value()?.to_owned()),
+            "--trace-range" if tracing => {
+                let range = hex_range(value()?).ok_or("--trace-range requires a hexadecimal range such as 1000:2000")?;
+                opts.run.trace_range = Some(range);
+            }
+            "--trace-sub" if tracing => opts.run.trace_subroutines.push(value()?.to_owned()),
+            "--profile" if tracing => opts.run.profile = Some(value()?.to_owned()),
+            "--coverage" if tracing => opts.run.coverage = Some(value()?.to_owned()),
+            "--snapshot" if tracing => opts.run.snapshot = Some(value()?.to_owned()),
+            "--history" if subcommand == Some("debug") => {
+                opts.run.history = value()?.parse().map_err(|_| "--history requires a number".to_owned())?;
+            }
+            "--port" if subcommand == Some("gdb") => {
+                opts.run.port = Some(value()?.parse().map_err(|_| "--port requires a port number".to_owned())?);
+            }
+            "--restore" if running => opts.run.restore = Some(value()?.to_owned()),
+            x if x.starts_with("-W") => opts.warnings.apply(x)?,
+            "-" => opts.inputs.push("-".to_owned()),
+            x if x.starts_with('-') => return Err(format!("unknown option {}", x)),
+            x => opts.inputs.push(x.to_owned())
+        }
+    }
+
+    if subcommand == Some("lsp") {
+        if !opts.inputs.is_empty() || !opts.emits.is_empty() || opts.output.is_some() {
+            return Err("lsp reads documents from the editor and does not take inputs or outputs".to_owned());
+        }
+        return Ok(Command::Lsp(Box::new(opts)));
+    }
+    if opts.inputs.is_empty() {
+        return Err("no input files".to_owned());
+    }
+    if opts.inputs.iter().filter(|i| *i == "-").count() > 1 {
+        return Err("standard input can only be read once".to_owned());
+    }
+    if subcommand == Some("fmt") {
+        if !opts.emits.is_empty() || opts.output.is_some() {
+            return Err("fmt rewrites its inputs in place and does not take outputs".to_owned());
+        }
+        return Ok(Command::Fmt(Box::new(opts)));
+    }
+    if running {
+        if opts.inputs.len() > 1 || !opts.emits.is_empty() || opts.output.is_some() {
+            return Err(format!("{} takes a single input and writes no outputs", subcommand.unwrap_or_default()));
+        }
+        if subcommand == Some("debug") {
+            return Ok(Command::Debug(Box::new(opts)));
+        }
+        if subcommand == Some("gdb") {
+            return Ok(Command::Gdb(Box::new(opts)));
+        }
+        let r = &mut opts.run;
+        if r.trace.is_none() && (r.trace_path.is_some() || r.trace_range.is_some() || !r.trace_subroutines.is_empty()) {
+            r.trace = Some(trace::Format::Text);
+        }
+        return Ok(Command::Run(Box::new(opts)));
+    }
+    if opts.emits.is_empty() {
+        opts.emits.push((Emit::Binary, None));
+    }
+    if opts.layout != opts.layout.explain(false) && !opts.emits.iter().any(|(e, _)| *e == Emit::Listing) {
+        opts.emits.push((Emit::Listing, Some("-".to_owned())));
+    }
+    if opts.output.is_some() && opts.emits.iter().filter(|(e, p)| e.is_object() && p.is_none()).count() > 1 {
+        return Err("-o can only be used with one object format; use --emit <kind>=<path> for the others".to_owned());
+    }
+    for input in &opts.inputs {
+        let outputs = opts.outputs(input);
+        for (k, (_, path)) in outputs.iter().enumerate() {
+            if path != "-" && outputs[..k].iter().any(|(_, p)| p == path) {
+                return Err(format!("more than one output would be written to {}", path));
+            }
+        }
+    }
+    if opts.inputs.len() > 1 && opts.emits.iter().any(|(e, p)| p.as_deref().is_some_and(|p| p != "-") || (opts.output.is_some() && e.is_object())) {
+        return Err("output paths cannot be given when assembling more than one input".to_owned());
+    }
+    Ok(Command::Assemble(Box::new(opts)))
+}
+
+#[test]
+fn command_line() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(|a| a.to_owned()).collect() };
+    let outputs = |s: &str| match parse(&args(s)) {
+        Ok(Command::Assemble(o)) => Ok(o.inputs.iter().map(|i| o.outputs(i)).collect::<Vec<_>>()),
+        Ok(c) => Err(format!("{:?}", c)),
+        Err(e) => Err(e)
+    };
+    let paths = |v: &[(Emit, &str)]| v.iter().map(|(e, p)| (*e, p.to_string())).collect::<Vec<_>>();
+
+    assert_eq!(outputs("a.asm"), Ok(vec![paths(&[(Emit::Binary, "a.asm_out")])]));
+    assert_eq!(
+        outputs("-o a.obj --emit srec --emit json --listing a.lst a.asm"),
+        Ok(vec![paths(&[(Emit::Srec, "a.obj"), (Emit::Json, "-"), (Emit::Listing, "a.lst")])])
+    );
+    assert_eq!(outputs("a.asm --explain"), Ok(vec![paths(&[(Emit::Binary, "a.asm_out"), (Emit::Listing, "-")])]));
+    assert_eq!(outputs("- --emit text"), Ok(vec![paths(&[(Emit::Text, "-")])]));
+    assert_eq!(
+        outputs("a.asm b.asm --emit ihex"),
+        Ok(vec![paths(&[(Emit::IntelHex, "a.asm.hex")]), paths(&[(Emit::IntelHex, "b.asm.hex")])])
+    );
+    assert!(matches!(parse(&args("--version a.asm")), Ok(Command::Version)));
+    assert!(matches!(parse(&args("lsp --isa x.isa -Wall")), Ok(Command::Lsp(_))));
+    assert!(parse(&args("lsp a.asm")).is_err());
+    assert!(matches!(parse(&args("fmt --check a.asm")), Ok(Command::Fmt(o)) if o.check));
+    assert!(parse(&args("--check a.asm")).is_err());
+    match parse(&args("run a.asm --input f1=in.txt --trace-sub RDREC --trace-range 1000:2000h --profile p.txt --coverage lcov.info")) {
+        Ok(Command::Run(o)) => {
+            assert_eq!(o.run.profile.as_deref(), Some("p.txt"));
+            assert_eq!(o.run.coverage.as_deref(), Some("lcov.info"));
+            assert_eq!(o.run.inputs, vec![(0xF1, "in.txt".to_owned())]);
+            assert_eq!((o.run.trace, o.run.trace_range), (Some(trace::Format::Text), Some((0x1000, 0x2000))));
+        }
+        _ => panic!("run not accepted")
+    }
+    assert!(parse(&args("run a.asm b.asm")).is_err());
+    assert!(parse(&args("a.asm --emit raw --range 0:100000 --pad 100000")).is_ok());
+    assert!(parse(&args("a.asm --emit raw --range 0:FFFFFFFF")).is_err());
+    assert!(parse(&args("a.asm --emit raw --pad 100001")).is_err());
+    assert!(parse(&args("a.asm --trace")).is_err());
+    match parse(&args("debug a.asm --restore s.txt --history 50")) {
+        Ok(Command::Debug(o)) => assert_eq!((o.run.restore.as_deref(), o.run.history), (Some("s.txt"), 50)),
+        _ => panic!("debug not accepted")
+    }
+    assert!(parse(&args("debug a.asm --trace")).is_err());
+    match parse(&args("gdb --port 1234 a.asm")) {
+        Ok(Command::Gdb(o)) => assert_eq!(o.run.port, Some(1234)),
+        _ => panic!("gdb not accepted")
+    }
+    assert!(parse(&args("debug --port 1234 a.asm")).is_err());
+    match parse(&args("a.asm -D DEV=0xF3 -DSIZE=-20 -D DEBUG")) {
+        Ok(Command::Assemble(o)) => assert_eq!(o.defines, vec![("DEV".to_owned(), 0xF3), ("SIZE".to_owned(), -20), ("DEBUG".to_owned(), 1)]),
+        _ => panic!("-D not accepted")
+    }
+    assert!(outputs("a.asm -D 1X=2").is_err());
+    assert!(outputs("a.asm -D X=1000000h").is_err());
+
+    assert!(outputs("").is_err());
+    assert!(outputs("a.asm --bogus").is_err());
+    assert!(outputs("a.asm --emit elf").is_err());
+    assert!(outputs("a.asm --emit binary --emit text").is_err());
+    assert!(outputs("-o x a.asm --emit ihex --emit srec").is_err());
+    assert!(outputs("-o x a.asm b.asm").is_err());
+}
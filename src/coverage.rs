@@ -0,0 +1,194 @@
+//! Code coverage of simulated programs.
+//!
+//! A `Coverage` records which instructions a run executed and which way each
+//! conditional jump went. `report` maps the addresses back to the source
+//! lines they were assembled from, and the `Report` is printed as a summary
+//! of what was never run or as an lcov tracefile for coverage tools.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::sim::Step;
+use super::AssemblyResult;
+
+/// The instructions and branches executed over a run.
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    /// Instructions executed, by address.
+    pub hits: BTreeMap<u32, u64>,
+    /// The number of times each conditional jump was taken and not taken, by
+    /// address.
+    pub branches: BTreeMap<u32, (u64, u64)>
+}
+
+/// An instruction line and what ran of it.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct LineCoverage {
+    /// The name of the source the line is in.
+    pub file: String,
+    pub line_no: u32,
+    pub text: String,
+    pub hits: u64,
+    /// For a conditional jump, the number of times it was taken and not
+    /// taken.
+    pub branch: Option<(u64, u64)>
+}
+
+/// The coverage of every instruction line of a program.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Report {
+    pub lines: Vec<LineCoverage>
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Adds what `step` ran.
+    pub fn record(&mut self, step: &Step) {
+        *self.hits.entry(step.address).or_default() += 1;
+        if step.is_branch() {
+            let next = (step.address + step.instruction.size()) as u64;
+            let jumped = step.registers.iter().any(|(r, _, new)| *r == super::sim::Register::PC && *new != next);
+            let b = self.branches.entry(step.address).or_default();
+            if jumped { b.0 += 1 } else { b.1 += 1 }
+        }
+    }
+
+    /// The coverage of the instruction lines of `result`, loaded `offset`
+    /// bytes from where it was assembled.
+    pub fn report(&self, result: &AssemblyResult, offset: u32) -> Report {
+        let lines = result.lines.iter().enumerate().filter(|(_, l)| l.is_instruction()).map(|(k, l)| {
+            let (file, source) = &result.sources[result.origin[k]];
+            let addr = l.mem_loc.wrapping_add(offset);
+            let conditional = matches!(l.obj_code.first().map(|b| b & 0xFC), Some(0x30) | Some(0x34) | Some(0x38));
+            LineCoverage {
+                file: file.clone(),
+                line_no: l.line_no,
+                text: source.lines().nth(l.line_no as usize - 1).unwrap_or("").trim().to_owned(),
+                hits: self.hits.get(&addr).cloned().unwrap_or(0),
+                branch: if conditional { Some(self.branches.get(&addr).cloned().unwrap_or((0, 0))) } else { None }
+            }
+        }).collect();
+        Report { lines }
+    }
+}
+
+// `part` of `whole` as a percentage, with one decimal.
+fn percent(part: usize, whole: usize) -> String {
+    if whole == 0 {
+        return "100.0%".to_owned();
+    }
+    format!("{:.1}%", part as f64 * 100.0 / whole as f64)
+}
+
+impl Report {
+    // The names of the sources, in the order their lines appear.
+    fn files(&self) -> Vec<&str> {
+        let mut files: Vec<&str> = Vec::new();
+        for l in &self.lines {
+            if !files.contains(&&*l.file) {
+                files.push(&l.file);
+            }
+        }
+        files
+    }
+
+    /// The lines and branch directions covered in each source, followed by
+    /// the lines never executed and the branches only ever going one way.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for file in self.files() {
+            let lines: Vec<&LineCoverage> = self.lines.iter().filter(|l| l.file == file).collect();
+            let hit = lines.iter().filter(|l| l.hits > 0).count();
+            let branches: Vec<(u64, u64)> = lines.iter().filter_map(|l| l.branch).collect();
+            let taken = branches.iter().map(|b| (b.0 > 0) as usize + (b.1 > 0) as usize).sum::<usize>();
+            let _ = writeln!(
+                out,
+                "{}: {} of {} lines ({}), {} of {} branches ({})",
+                file,
+                hit,
+                lines.len(),
+                percent(hit, lines.len()),
+                taken,
+                2 * branches.len(),
+                percent(taken, 2 * branches.len())
+            );
+        }
+        for l in &self.lines {
+            let what = match l.branch {
+                _ if l.hits == 0 => "never executed",
+                Some((0, _)) => "branch never taken",
+                Some((_, 0)) => "branch always taken",
+                _ => continue
+            };
+            let _ = writeln!(out, "{}:{}: {}: {}", l.file, l.line_no, what, l.text);
+        }
+        out
+    }
+
+    /// The report as an lcov tracefile, with a record for each source.
+    pub fn lcov(&self) -> String {
+        let mut out = String::from("TN:\n");
+        for file in self.files() {
+            let lines: Vec<&LineCoverage> = self.lines.iter().filter(|l| l.file == file).collect();
+            let _ = writeln!(out, "SF:{}", file);
+            let (mut found, mut hit) = (0, 0);
+            for l in &lines {
+                if let Some((taken, not_taken)) = l.branch {
+                    for (n, count) in [taken, not_taken].iter().enumerate() {
+                        let count = if l.hits == 0 { "-".to_owned() } else { count.to_string() };
+                        let _ = writeln!(out, "BRDA:{},0,{},{}", l.line_no, n, count);
+                    }
+                    found += 2;
+                    hit += (taken > 0) as usize + (not_taken > 0) as usize;
+                }
+            }
+            let _ = writeln!(out, "BRF:{}\nBRH:{}", found, hit);
+            for l in &lines {
+                let _ = writeln!(out, "DA:{},{}", l.line_no, l.hits);
+            }
+            let _ = writeln!(out, "LF:{}\nLH:{}", lines.len(), lines.iter().filter(|l| l.hits > 0).count());
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+}
+
+#[test]
+fn coverage_report() {
+    let source = "\
+MAIN  START 0
+      STL   RET
+      LDA   #3
+LOOP  SUB   #1
+      COMP  #0
+      JGT   LOOP
+      JLT   NEVER
+      LDL   RET
+      RSUB
+NEVER LDA   #0
+      RSUB
+RET   RESW  1
+      END   MAIN
+";
+    let result = super::Assembler::new().assemble("main.asm", source);
+    let mut m = super::sim::Machine::new();
+    m.load(&result.program, 0).unwrap();
+    let mut coverage = Coverage::new();
+    m.run(100, |_, s| coverage.record(s));
+    assert_eq!(coverage.branches[&0xC], (2, 1));
+
+    let report = coverage.report(&result, 0);
+    assert_eq!(report.lines.len(), 10);
+    assert_eq!(report.summary(), "\
+main.asm: 8 of 10 lines (80.0%), 3 of 4 branches (75.0%)
+main.asm:7: branch never taken: JLT   NEVER
+main.asm:10: never executed: NEVER LDA   #0
+main.asm:11: never executed: RSUB
+");
+    let lcov = report.lcov();
+    assert!(lcov.starts_with("TN:\nSF:main.asm\nBRDA:6,0,0,2\nBRDA:6,0,1,1\nBRDA:7,0,0,0\nBRDA:7,0,1,1\nBRF:4\nBRH:3\nDA:2,1\n"));
+    assert!(lcov.ends_with("DA:10,0\nDA:11,0\nLF:10\nLH:8\nend_of_record\n"));
+}
@@ -0,0 +1,438 @@
+//! An interactive debugger for simulated programs.
+//!
+//! The debugger reads one command per line: stepping and continuing to
+//! breakpoints, looking at and changing registers and memory, and saving and
+//! loading snapshots of the machine. Addresses can be given as labels of the
+//! program or in hexadecimal.
+//!
+//! The steps run are kept in a history of bounded size, so that the debugger
+//! can step backwards, run backwards to a breakpoint, and find the
+//! instruction that last wrote an address. Changing a register or loading a
+//! snapshot clears the history.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use super::sim::{Machine, Register, Step, Stop, EXIT_ADDRESS};
+use super::snapshot;
+use super::trace::{Format, Tracer};
+
+const HELP: &str = "\
+step [n]            run one instruction, or n, showing each
+continue            run until a breakpoint or the end of the program
+step-back [n]       undo the last instruction, or the last n
+reverse-continue    undo instructions back to a breakpoint or the oldest one kept
+last-write <addr>   show the last instruction kept that wrote to addr
+break [addr]        stop before the instruction at addr, or list breakpoints
+delete <addr>       remove the breakpoint at addr
+where               show the next instruction
+registers           show the registers
+memory <addr> [n]   show n bytes of memory (16 by default)
+set <reg> <value>   change a register, in hexadecimal
+save <path>         write a snapshot of the machine to path
+load <path>         replace the machine with the snapshot in path
+quit                leave the debugger
+";
+
+/// A machine under the control of the debugger.
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    pub machine: Machine,
+    labels: BTreeMap<u32, String>,
+    breakpoints: BTreeSet<u32>,
+    max_steps: u64,
+    tracer: Tracer,
+    // The steps run, oldest first, at most `history_size` of them.
+    history: VecDeque<Step>,
+    history_size: usize,
+    // The number of bytes of each device's output shown so far.
+    shown: BTreeMap<u8, usize>
+}
+
+impl Debugger {
+    /// Debugs `machine`, naming addresses with `labels`.
+    pub fn new(machine: Machine, labels: BTreeMap<u32, String>) -> Debugger {
+        let shown = machine.devices.iter().map(|(n, d)| (*n, d.output.len())).collect();
+        Debugger {
+            machine,
+            tracer: Tracer::new(Format::Text, labels.clone()),
+            labels,
+            breakpoints: BTreeSet::new(),
+            max_steps: 1_000_000,
+            history: VecDeque::new(),
+            history_size: 10_000,
+            shown
+        }
+    }
+
+    /// The most instructions `continue` runs before giving up.
+    pub fn max_steps(mut self, n: u64) -> Self {
+        self.max_steps = n;
+        self
+    }
+
+    /// The most steps kept for running backwards; 0 keeps none.
+    pub fn history(mut self, n: usize) -> Self {
+        self.history_size = n;
+        self
+    }
+
+    // The address written as a label or in hexadecimal.
+    fn address(&self, text: &str) -> Result<u32, String> {
+        if let Some((addr, _)) = self.labels.iter().find(|(_, l)| *l == text) {
+            return Ok(*addr);
+        }
+        u32::from_str_radix(text.trim_end_matches(['h', 'H']), 16)
+            .ok()
+            .filter(|a| *a <= 0xFFFFFF)
+            .ok_or_else(|| format!("`{}` is neither a label nor an address", text))
+    }
+
+    fn describe(&self, addr: u32) -> String {
+        match self.labels.get(&addr) {
+            Some(l) => format!("{:06X} ({})", addr, l),
+            None => format!("{:06X}", addr)
+        }
+    }
+
+    // The next instruction, as `where` shows it.
+    fn next(&self) -> String {
+        let pc = self.machine.pc();
+        let label = self.labels.get(&pc).cloned().unwrap_or_default();
+        match self.machine.decode(pc) {
+            Ok(i) => format!("{:06X}  {:<8} {}\n", pc, label, i.disassemble(&|a| self.labels.get(&a).cloned())),
+            Err(e) => format!("{:06X}  {:<8} {}\n", pc, label, e)
+        }
+    }
+
+    // Output written to devices since it was last shown.
+    fn output(&mut self, out: &mut String) {
+        for (n, d) in &self.machine.devices {
+            let shown = self.shown.entry(*n).or_insert(0);
+            if d.output.len() > *shown {
+                let _ = writeln!(out, "output {:02X}: {:?}", n, String::from_utf8_lossy(&d.output[*shown..]));
+                *shown = d.output.len();
+            }
+        }
+    }
+
+    // Keeps `step` in the history, dropping the oldest step if it is full.
+    fn remember(history: &mut VecDeque<Step>, size: usize, step: &Step) {
+        if size == 0 {
+            return;
+        }
+        if history.len() == size {
+            history.pop_front();
+        }
+        history.push_back(step.clone());
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        if self.machine.pc() == EXIT_ADDRESS {
+            return Err("the program has returned".to_owned());
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, n: u64) -> Result<String, String> {
+        self.check_running()?;
+        let mut out = String::new();
+        for _ in 0..n {
+            let (tracer, history, size) = (&mut self.tracer, &mut self.history, self.history_size);
+            let stop = self.machine.run(1, |_, s| {
+                Debugger::remember(history, size, s);
+                if let Some(line) = tracer.record(s) {
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+            });
+            if stop != Stop::StepLimit {
+                let _ = writeln!(out, "{}", stop);
+                break;
+            }
+        }
+        self.output(&mut out);
+        Ok(out)
+    }
+
+    fn resume(&mut self) -> Result<String, String> {
+        self.check_running()?;
+        let mut out = String::new();
+        let mut stop = Stop::StepLimit;
+        for n in 0..self.max_steps {
+            let pc = self.machine.pc();
+            if n > 0 && self.breakpoints.contains(&pc) {
+                let _ = writeln!(out, "breakpoint at {}", self.describe(pc));
+                break;
+            }
+            let (tracer, history, size) = (&mut self.tracer, &mut self.history, self.history_size);
+            stop = self.machine.run(1, |_, s| {
+                Debugger::remember(history, size, s);
+                tracer.record(s);
+            });
+            if stop != Stop::StepLimit {
+                break;
+            }
+        }
+        if stop != Stop::StepLimit || out.is_empty() {
+            let _ = writeln!(out, "{}", stop);
+        }
+        self.output(&mut out);
+        if self.machine.pc() != EXIT_ADDRESS {
+            out.push_str(&self.next());
+        }
+        Ok(out)
+    }
+
+    // Undoes up to `n` steps, or with `to_breakpoint`, as many as it takes to
+    // get back to a breakpoint.
+    fn back(&mut self, n: u64, to_breakpoint: bool) -> Result<String, String> {
+        if self.history.is_empty() {
+            return Err("there are no steps to go back over".to_owned());
+        }
+        let mut out = String::new();
+        for _ in 0..n {
+            let step = match self.history.pop_back() {
+                Some(s) => s,
+                None => {
+                    let _ = writeln!(out, "reached the oldest step kept");
+                    break;
+                }
+            };
+            self.machine.undo(&step);
+            if to_breakpoint && self.breakpoints.contains(&step.address) {
+                let _ = writeln!(out, "breakpoint at {}", self.describe(step.address));
+                break;
+            }
+        }
+        for (n, d) in &self.machine.devices {
+            self.shown.entry(*n).and_modify(|s| *s = (*s).min(d.output.len()));
+        }
+        out.push_str(&self.next());
+        Ok(out)
+    }
+
+    // The last step kept that wrote to `addr`.
+    fn last_write(&self, addr: u32) -> String {
+        let found = self.history.iter().enumerate().rev().find_map(|(k, s)| {
+            s.writes.iter().find(|w| (w.0..w.0 + w.2.len() as u32).contains(&addr)).map(|w| (k, s, w))
+        });
+        let (k, step, (at, old, new)) = match found {
+            Some(f) => f,
+            None => return format!("no write to {} in the last {} steps\n", self.describe(addr), self.history.len())
+        };
+        let hex = |b: &[u8]| b.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+        format!(
+            "{} steps ago, {:06X}  {}  wrote {} over {} at {}\n",
+            self.history.len() - k,
+            step.address,
+            step.instruction.disassemble(&|a| self.labels.get(&a).cloned()),
+            hex(new),
+            hex(old),
+            self.describe(*at)
+        )
+    }
+
+    fn registers(&self) -> String {
+        let mut out = String::new();
+        for r in Register::ALL.iter() {
+            let v = self.machine.register(*r);
+            if *r == Register::F {
+                let _ = write!(out, "F={:012X} ", v);
+            } else {
+                let _ = write!(out, "{}={:06X} ", r.name(), v);
+            }
+        }
+        format!("{}CC={}\n", out, self.machine.cc().symbol())
+    }
+
+    fn memory(&self, addr: u32, n: usize) -> Result<String, String> {
+        let bytes = (addr as usize).checked_add(n).and_then(|end| self.machine.memory().get(addr as usize..end))
+            .ok_or_else(|| format!("{:06X} is outside memory", addr))?;
+        let mut out = String::new();
+        for (k, row) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            let _ = writeln!(out, "{:06X}  {}", addr as usize + 16 * k, hex.join(" "));
+        }
+        Ok(out)
+    }
+
+    /// Runs one command, returning what it prints or why it failed.
+    pub fn execute(&mut self, command: &str) -> Result<String, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let count = |w: Option<&&str>, default: u64| match w {
+            Some(w) => w.parse::<u64>().map_err(|_| format!("`{}` is not a number", w)),
+            None => Ok(default)
+        };
+        match words[..] {
+            [] => Ok(String::new()),
+            ["step", ..] | ["s", ..] => {
+                let n = count(words.get(1), 1)?;
+                self.step(n)
+            }
+            ["continue"] | ["c"] => self.resume(),
+            ["step-back", ..] | ["sb", ..] => {
+                let n = count(words.get(1), 1)?;
+                self.back(n, false)
+            }
+            ["reverse-continue"] | ["rc"] => self.back(u64::MAX, true),
+            ["last-write", at] => {
+                let addr = self.address(at)?;
+                Ok(self.last_write(addr))
+            }
+            ["break"] | ["b"] => {
+                Ok(self.breakpoints.iter().map(|a| format!("breakpoint at {}\n", self.describe(*a))).collect())
+            }
+            ["break", at] | ["b", at] => {
+                let addr = self.address(at)?;
+                self.breakpoints.insert(addr);
+                Ok(format!("breakpoint at {}\n", self.describe(addr)))
+            }
+            ["delete", at] => {
+                let addr = self.address(at)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("there is no breakpoint at {}", self.describe(addr)));
+                }
+                Ok(String::new())
+            }
+            ["where"] => Ok(self.next()),
+            ["registers"] | ["r"] => Ok(self.registers()),
+            ["memory", at, ..] | ["x", at, ..] => {
+                let addr = self.address(at)?;
+                let n = count(words.get(2), 16)?;
+                self.memory(addr, n as usize)
+            }
+            ["set", reg, value] => {
+                let r = Register::from_name(reg).ok_or_else(|| format!("there is no register {}", reg))?;
+                let v = u64::from_str_radix(value, 16).map_err(|_| format!("`{}` is not a hexadecimal number", value))?;
+                self.machine.set_register(r, v);
+                self.history.clear();
+                Ok(String::new())
+            }
+            ["save", path] => {
+                fs::write(path, snapshot::save(&self.machine)).map_err(|e| format!("{}: {}", path, e))?;
+                Ok(String::new())
+            }
+            ["load", path] => {
+                let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                self.machine = snapshot::restore(&text).map_err(|e| format!("{}: {}", path, e))?;
+                self.history.clear();
+                self.shown = self.machine.devices.iter().map(|(n, d)| (*n, d.output.len())).collect();
+                Ok(self.next())
+            }
+            ["help"] => Ok(HELP.to_owned()),
+            _ => Err(format!("unknown command `{}`; try `help`", command.trim()))
+        }
+    }
+}
+
+/// Reads commands from `input` until `quit` or the end of the input, writing
+/// a prompt before each one and what it printed after.
+pub fn repl(debugger: &mut Debugger, input: &mut impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    write!(out, "{}", debugger.next())?;
+    loop {
+        write!(out, "(yacc) ")?;
+        out.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        match line.trim() {
+            "quit" | "q" => return Ok(()),
+            command => match debugger.execute(command) {
+                Ok(text) => write!(out, "{}", text)?,
+                Err(e) => writeln!(out, "error: {}", e)?
+            }
+        }
+    }
+}
+
+#[test]
+fn debugger_commands() {
+    let source = "\
+MAIN  START 0
+      LDA   #3
+LOOP  SUB   #1
+      WD    DEV
+      COMP  #0
+      JGT   LOOP
+      RSUB
+DEV   BYTE  X'05'
+      END   MAIN
+";
+    let result = super::Assembler::new().assemble("main.asm", source);
+    let mut m = Machine::new();
+    m.load(&result.program, 0).unwrap();
+    let mut d = Debugger::new(m, super::trace::labels(&result.symbols, 0));
+
+    assert_eq!(d.execute("step").unwrap(), "000000  MAIN     LDA #3                         CC=<  A=000003\n");
+    assert_eq!(d.execute("break LOOP").unwrap(), "breakpoint at 000003 (LOOP)\n");
+    assert_eq!(d.execute("c").unwrap(), "breakpoint at 000003 (LOOP)\noutput 05: \"\\u{2}\"\n000003  LOOP     SUB #1\n");
+    assert_eq!(d.execute("memory DEV 2").unwrap(), "000012  05 00\n");
+    d.execute("set a 1").unwrap();
+    assert!(d.execute("registers").unwrap().starts_with("A=000001 X=000000 L=FFFFFF"));
+
+    let path = std::env::temp_dir().join(format!("yacc-debugger-{}.snapshot", std::process::id()));
+    let path = path.to_str().unwrap();
+    d.execute(&format!("save {}", path)).unwrap();
+    assert_eq!(d.execute("delete LOOP").unwrap(), "");
+    assert_eq!(d.execute("continue").unwrap(), "the program returned\noutput 05: \"\\0\"\n");
+    assert_eq!(d.execute("step").unwrap_err(), "the program has returned");
+    assert_eq!(d.execute(&format!("load {}", path)).unwrap(), "000003  LOOP     SUB #1\n");
+    assert_eq!(d.execute("registers").unwrap().get(..8), Some("A=000001"));
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(d.execute("memory 1 18446744073709551615").unwrap_err(), "000001 is outside memory");
+    assert!(d.execute("delete 1234").is_err());
+    assert!(d.execute("frobnicate").is_err());
+    let mut out = Vec::new();
+    repl(&mut d, &mut "step 2\nquit\nstep\n".as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("000003  LOOP     SUB #1\n(yacc) 000003  LOOP     SUB #1"));
+    assert!(out.ends_with("(yacc) "));
+}
+
+#[test]
+fn reverse_execution() {
+    let source = "\
+MAIN  START 0
+      LDA   #3
+LOOP  STA   COUNT
+      WD    DEV
+      SUB   #1
+      COMP  #0
+      JGT   LOOP
+      RSUB
+COUNT RESW  1
+DEV   BYTE  X'05'
+      END   MAIN
+";
+    let result = super::Assembler::new().assemble("main.asm", source);
+    let mut m = Machine::new();
+    m.load(&result.program, 0).unwrap();
+    let mut d = Debugger::new(m, super::trace::labels(&result.symbols, 0)).history(8);
+
+    assert!(d.execute("step-back").is_err());
+    d.execute("continue").unwrap();
+    assert_eq!(d.machine.devices[&5].output, vec![3, 2, 1]);
+    assert_eq!(d.execute("last-write COUNT").unwrap(), "6 steps ago, 000003  STA COUNT  wrote 000001 over 000002 at 000015 (COUNT)\n");
+    assert_eq!(d.execute("last-write 16").unwrap(), "6 steps ago, 000003  STA COUNT  wrote 000001 over 000002 at 000015 (COUNT)\n");
+    assert_eq!(d.execute("last-write DEV").unwrap(), "no write to 000018 (DEV) in the last 8 steps\n");
+
+    // Going back over WD takes its byte back from the device.
+    assert_eq!(d.execute("sb 3").unwrap(), "00000C           COMP #0\n");
+    assert_eq!(d.machine.devices[&5].output, vec![3, 2, 1]);
+    d.execute("sb 2").unwrap();
+    assert_eq!(d.machine.devices[&5].output, vec![3, 2]);
+    d.execute("break LOOP").unwrap();
+    assert_eq!(d.execute("rc").unwrap(), "breakpoint at 000003 (LOOP)\n000003  LOOP     STA COUNT\n");
+    assert_eq!(d.machine.register(Register::A), 1);
+    assert_eq!(d.execute("rc").unwrap(), "reached the oldest step kept\n00000C           COMP #0\n");
+
+    // The history is bounded, and going forward again gives the same run.
+    assert_eq!(d.execute("c").unwrap(), "breakpoint at 000003 (LOOP)\n000003  LOOP     STA COUNT\n");
+    assert_eq!(d.execute("c").unwrap(), "the program returned\noutput 05: \"\\u{1}\"\n");
+    assert_eq!(d.machine.devices[&5].output, vec![3, 2, 1]);
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+use super::warnings::Warning;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Note    => write!(f, "note"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error   => write!(f, "error")
+        }
+    }
+}
+
+/// Identifies the kind of problem a diagnostic reports.
+///
+/// The string returned by `as_str` is stable across releases, so tools can
+/// match on it instead of on the message text.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Code {
+    DuplicateSymbol,
+    InvalidOpcode,
+    MissingLabel,
+    InvalidArgument,
+    UndefinedSymbol,
+    OperandShape,
+    IsaDefinition,
+    OperandOutOfRange,
+    BadIndexRegister,
+    MissingEnd,
+    InvalidPragma,
+    CircularDefinition,
+    Syntax,
+    Warning(Warning)
+}
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::DuplicateSymbol => "E001",
+            Code::InvalidOpcode   => "E002",
+            Code::MissingLabel    => "E003",
+            Code::InvalidArgument => "E004",
+            Code::UndefinedSymbol => "E005",
+            Code::OperandShape    => "E006",
+            Code::IsaDefinition   => "E007",
+            Code::OperandOutOfRange => "E008",
+            Code::BadIndexRegister  => "E009",
+            Code::MissingEnd        => "E010",
+            Code::InvalidPragma     => "E011",
+            Code::CircularDefinition => "E012",
+            Code::Syntax            => "E013",
+            Code::Warning(w)        => w.code()
+        }
+    }
+}
+
+/// The part of a source line a diagnostic points at, turned into columns
+/// once the parsed statement of the line is known.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub(crate) enum Field {
+    Label,
+    Mnemonic,
+    /// The expression of the operand with this index, without its `#`, `@`
+    /// or `=`.
+    Operand(usize),
+    /// The first use of a symbol in the operands.
+    Symbol(String),
+    /// A range of bytes in the text of the comment.
+    Comment(Range<usize>)
+}
+
+/// A position in a source file: a 1-based line number and the 0-based byte
+/// range of the text of interest within that line.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+    pub cols: Range<usize>
+}
+
+/// A secondary location attached to a diagnostic, e.g. "first defined here".
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Related {
+    pub location: Location,
+    pub message: String,
+    pub(crate) hint: Option<String>
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub location: Location,
+    pub related: Vec<Related>,
+    // The field the diagnostic points at, used to fill in `location.cols`
+    // once the line it came from is known.
+    pub(crate) field: Option<Field>
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: Code, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code,
+            message: message.to_owned(),
+            location: Location { file: String::new(), line: 0, cols: 0..0 },
+            related: Vec::new(),
+            field: None
+        }
+    }
+
+    pub fn error(code: Code, message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Error, code, message)
+    }
+
+    pub fn file(mut self, f: &str) -> Self {
+        self.location.file = f.to_owned();
+        self
+    }
+
+    pub fn line(mut self, l: u32) -> Self {
+        self.location.line = l;
+        self
+    }
+
+    pub fn cols(mut self, c: Range<usize>) -> Self {
+        self.location.cols = c;
+        self
+    }
+
+    pub(crate) fn at(mut self, f: Field) -> Self {
+        self.field = Some(f);
+        self
+    }
+
+    /// Adds a secondary location, e.g. "first defined here".
+    pub fn note(mut self, location: Location, message: &str) -> Self {
+        self.related.push(Related { location, message: message.to_owned(), hint: None });
+        self
+    }
+
+    /// Adds a secondary location pointing at `token` on `line`.
+    pub(crate) fn related(mut self, line: u32, token: &str, message: &str) -> Self {
+        self.related.push(Related {
+            location: Location { file: self.location.file.clone(), line, cols: 0..0 },
+            message: message.to_owned(),
+            hint: Some(token.to_owned())
+        });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // The option controlling this diagnostic, shown after the message.
+    fn flag(&self) -> String {
+        match self.code {
+            Code::Warning(w) if self.is_error() => format!(" [-Werror={}]", w.name()),
+            Code::Warning(w) => format!(" [-W{}]", w.name()),
+            _ => String::new()
+        }
+    }
+
+    /// Renders the diagnostic with a snippet of the offending line and a caret
+    /// under the reported columns, followed by any related locations.
+    ///
+    /// `source_of` maps a file name to its text; locations in files it does not
+    /// know about are printed without a snippet.
+    pub fn render<'a>(&self, source_of: &dyn Fn(&str) -> Option<&'a str>) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{}[{}]: {}{}", self.severity, self.code.as_str(), self.message, self.flag());
+        render_location(&mut out, &self.location, '^', source_of);
+        for r in &self.related {
+            let _ = writeln!(out, "{}: {}", Severity::Note, r.message);
+            render_location(&mut out, &r.location, '-', source_of);
+        }
+        out
+    }
+}
+
+fn render_location<'a>(out: &mut String, loc: &Location, mark: char, source_of: &dyn Fn(&str) -> Option<&'a str>) {
+    let _ = writeln!(out, " --> {}:{}:{}", loc.file, loc.line, loc.cols.start + 1);
+    let text = match source_of(&loc.file).and_then(|s| s.lines().nth(loc.line.wrapping_sub(1) as usize)) {
+        Some(t) => t,
+        None => return
+    };
+    let gutter = " ".repeat(loc.line.to_string().len());
+    let end = loc.cols.end.min(text.len()).max(loc.cols.start + 1);
+    // Keep tabs in the padding so the caret lines up with the source.
+    let pad: String = text.bytes().take(loc.cols.start).map(|c| if c == b'\t' { '\t' } else { ' ' }).collect();
+    let _ = writeln!(out, "{} |", gutter);
+    let _ = writeln!(out, "{} | {}", loc.line, text);
+    let _ = writeln!(out, "{} | {}{}", gutter, pad, mark.to_string().repeat(end - loc.cols.start));
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}[{}]: {}{}",
+            self.location.file,
+            self.location.line,
+            self.location.cols.start + 1,
+            self.severity,
+            self.code.as_str(),
+            self.message,
+            self.flag()
+        )
+    }
+}
+
+#[test]
+fn render_snippet() {
+    let src = "FIRST\tLDA  BUFFER\nFIRST STA BUFFER\n";
+    let d = Diagnostic::error(Code::DuplicateSymbol, "duplicate definition of symbol FIRST")
+        .file("a.asm")
+        .line(2)
+        .cols(0..5)
+        .note(
+            Location { file: "a.asm".to_owned(), line: 1, cols: 0..5 },
+            "first defined here"
+        );
+    let text = d.render(&|f| if f == "a.asm" { Some(src) } else { None });
+    assert_eq!(
+        text,
+        "error[E001]: duplicate definition of symbol FIRST\n \
+         --> a.asm:2:1\n  |\n2 | FIRST STA BUFFER\n  | ^^^^^\n\
+         note: first defined here\n \
+         --> a.asm:1:1\n  |\n1 | FIRST\tLDA  BUFFER\n  | -----\n"
+    );
+    assert_eq!(d.to_string(), "a.asm:2:1: error[E001]: duplicate definition of symbol FIRST");
+}
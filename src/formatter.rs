@@ -0,0 +1,137 @@
+//! Rewrites sources into a canonical layout.
+//!
+//! Fields go in the columns of the fixed format: the label from column 1,
+//! `+` in column 9, the mnemonic from column 10, `#`, `@` or `=` in column 16
+//! and the operands from column 17. A file with labels or mnemonics too long
+//! for those columns has every field moved right by the same amount. Mnemonics
+//! and register names are written in upper case, trailing comments start with
+//! `. ` at a common column, and lines holding only a comment are kept as they
+//! are, but moved to column 1.
+
+use super::isa::{Isa, Operands, REGISTERS};
+use super::parser::{self, ExprKind, Statement};
+
+// The narrowest label and mnemonic fields, and the column trailing comments
+// start at when every line fits before it, counting from 0.
+const LABEL_WIDTH: usize = 8;
+const MNEMONIC_WIDTH: usize = 6;
+const COMMENT_COLUMN: usize = 36;
+
+// True if operand `k` of `s` names a register rather than a symbol.
+fn is_register(s: &Statement, k: usize, isa: &Isa) -> bool {
+    let def = s.mnemonic.as_ref().and_then(|m| isa.get(&m.name));
+    let position = def.is_some_and(|d| d.format == 2 || (d.operands == Operands::Memory && k > 0));
+    match s.operands[k].expr.kind {
+        ExprKind::Symbol(ref x) => position && REGISTERS.contains(&&*x.to_ascii_uppercase()),
+        _ => false
+    }
+}
+
+// The text of the operands of `s`, separated by commas.
+fn operands(s: &Statement, source: &str, isa: &Isa) -> String {
+    let texts: Vec<String> = s.operands.iter().enumerate().map(|(k, o)| {
+        let text = source[o.span.clone()].replace('\t', " ");
+        if is_register(s, k, isa) { text.to_ascii_uppercase() } else { text }
+    }).collect();
+    texts.join(",")
+}
+
+/// What a statement means to the assembler, for checking that formatting
+/// changed nothing but the layout.
+pub fn meaning(s: &Statement, isa: &Isa) -> String {
+    let ops: Vec<String> = s.operands.iter().enumerate().map(|(k, o)| match o.expr.kind {
+        ExprKind::Symbol(ref x) if is_register(s, k, isa) => x.to_ascii_uppercase(),
+        _ => format!("{:?} {:?}", o.mode, parser::lower_expr(&o.expr))
+    }).collect();
+    format!(
+        "{:?} {:?} {:?}",
+        s.label.as_ref().map(|l| &l.text),
+        s.mnemonic.as_ref().map(|m| (m.name.to_ascii_uppercase(), m.extended)),
+        ops
+    )
+}
+
+/// The statements of `source`, which must have parsed without errors, laid
+/// out canonically.
+pub fn format(source: &str, statements: &[Statement], isa: &Isa) -> String {
+    let label_width = statements.iter()
+        .filter_map(|s| s.label.as_ref().map(|l| l.text.len() + 1))
+        .fold(LABEL_WIDTH, usize::max);
+    let mnemonic_width = statements.iter()
+        .filter_map(|s| s.mnemonic.as_ref().map(|m| m.name.len()))
+        .fold(MNEMONIC_WIDTH, usize::max);
+
+    let code: Vec<String> = statements.iter().map(|s| {
+        let mut line = s.label.as_ref().map_or(String::new(), |l| l.text.clone());
+        if let Some(ref m) = s.mnemonic {
+            line = format!("{:<w$}{}{}", line, if m.extended { "+" } else { " " }, m.name.to_ascii_uppercase(), w = label_width);
+        }
+        if !s.operands.is_empty() {
+            let prefix = &source[s.operands[0].span.start..s.operands[0].expr.span.start];
+            let ops = operands(s, source, isa);
+            let ops = if prefix.is_empty() { format!(" {}", ops) } else { ops };
+            line = format!("{:<w$}{}", line, ops, w = label_width + 1 + mnemonic_width);
+        }
+        line
+    }).collect();
+
+    let comment_column = statements.iter().zip(&code)
+        .filter(|(s, _)| !s.is_blank() && s.comment.is_some())
+        .map(|(_, c)| c.chars().count() + 1)
+        .fold(COMMENT_COLUMN, usize::max);
+
+    let mut out = String::new();
+    for (k, s) in statements.iter().enumerate() {
+        let line = match s.comment {
+            Some(ref c) if s.is_blank() => format!(".{}", c.text.trim_end()),
+            Some(ref c) => format!("{:<w$}. {}", code[k], c.text.trim(), w = comment_column).trim_end().to_owned(),
+            None => code[k].clone()
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[test]
+fn canonical_layout() {
+    let source = "\
+. Reads a record
+copy\tstart 1000h
+FIRST  stl RETADR   . save return address
+\t+ldt #4096
+LOOP\ttd =x'F1' . test device
+   jeq LOOP
+ \tCLEAR x
+   stch buffer, x
+       .  indented comment
+   rsub
+\n   END FIRST
+";
+    let isa = Isa::sic_xe();
+    let (statements, errors) = parser::parse(source, &isa, parser::SourceFormat::Auto);
+    assert!(errors.is_empty());
+    let formatted = format(source, &statements, &isa);
+    assert_eq!(formatted, "\
+. Reads a record
+copy     START  1000h
+FIRST    STL    RETADR              . save return address
+        +LDT   #4096
+LOOP     TD    =x'F1'               . test device
+         JEQ    LOOP
+         CLEAR  X
+         STCH   buffer,X
+.  indented comment
+         RSUB
+
+         END    FIRST
+");
+    // The result is in the fixed format, and means the same.
+    assert_eq!(parser::detect_format(&formatted), parser::SourceFormat::Fixed);
+    let (again, errors) = parser::parse(&formatted, &isa, parser::SourceFormat::Auto);
+    assert!(errors.is_empty());
+    for (a, b) in statements.iter().zip(&again) {
+        assert_eq!(meaning(a, &isa), meaning(b, &isa));
+    }
+    assert_eq!(format(&formatted, &again, &isa), formatted);
+}
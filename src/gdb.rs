@@ -0,0 +1,370 @@
+//! A GDB remote serial protocol stub for simulated programs.
+//!
+//! The stub lets GDB, or any front-end speaking its remote protocol, control
+//! a `Machine`: read and write registers and memory, set breakpoints, step
+//! and continue. It describes the SIC/XE registers to the debugger with a
+//! target description, in register number order, big-endian: A, X, L, B, S, T
+//! and SW are 24 bits, F is 48, and PC is the program counter.
+//!
+//! Runs stop on a breakpoint or a step with SIGTRAP, on a fault with SIGILL,
+//! SIGSEGV or SIGFPE, and after the step limit with SIGINT, since the stub
+//! cannot be interrupted while it runs. A program that returns or halts
+//! exits with status 0. Output written to devices is shown on the GDB
+//! console.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+
+use super::sim::{Fault, Machine, Register, Stop, MEMORY_SIZE};
+use super::snapshot::{from_hex, hex};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.sicxe.core">
+    <reg name="a" bitsize="24" type="int" regnum="0"/>
+    <reg name="x" bitsize="24" type="int"/>
+    <reg name="l" bitsize="24" type="code_ptr"/>
+    <reg name="b" bitsize="24" type="int"/>
+    <reg name="s" bitsize="24" type="int"/>
+    <reg name="t" bitsize="24" type="int"/>
+    <reg name="f" bitsize="48" type="int"/>
+    <reg name="pc" bitsize="24" type="code_ptr"/>
+    <reg name="sw" bitsize="24" type="int"/>
+  </feature>
+</target>
+"#;
+
+// The most packet data the stub accepts, as advertised in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+// The signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+fn width(r: Register) -> usize {
+    if r == Register::F { 6 } else { 3 }
+}
+
+// A packet ready to send: `$`, the data, `#` and the checksum.
+fn frame(data: &str) -> String {
+    let sum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+    format!("${}#{:02x}", data, sum)
+}
+
+/// A machine controlled through the GDB remote protocol.
+#[derive(Debug, Clone)]
+pub struct Stub {
+    pub machine: Machine,
+    breakpoints: BTreeSet<u32>,
+    max_steps: u64,
+    // The number of bytes of each device's output sent to the console.
+    shown: BTreeMap<u8, usize>,
+    no_ack: bool
+}
+
+impl Stub {
+    pub fn new(machine: Machine) -> Stub {
+        let shown = machine.devices.iter().map(|(n, d)| (*n, d.output.len())).collect();
+        Stub { machine, breakpoints: BTreeSet::new(), max_steps: 1_000_000, shown, no_ack: false }
+    }
+
+    /// The most instructions a continue runs before stopping with SIGINT.
+    pub fn max_steps(mut self, n: u64) -> Self {
+        self.max_steps = n;
+        self
+    }
+
+    fn stop_reply(stop: &Stop) -> String {
+        let signal = match stop {
+            Stop::Exited | Stop::Halted => return "W00".to_owned(),
+            Stop::StepLimit | Stop::Fault(_, Fault::Idle) => SIGINT,
+            Stop::Fault(_, Fault::Address(_)) | Stop::Fault(_, Fault::Protection(_)) => SIGSEGV,
+            Stop::Fault(_, Fault::DivideByZero) => SIGFPE,
+            Stop::Fault(_, _) => SIGILL
+        };
+        format!("S{:02x}", signal)
+    }
+
+    // Runs one instruction, or with `resume`, until a breakpoint. Returns
+    // console output packets followed by the stop reply.
+    fn run(&mut self, resume: bool) -> Vec<String> {
+        let limit = if resume { self.max_steps } else { 1 };
+        let mut stop = Stop::StepLimit;
+        for n in 0..limit {
+            if n > 0 && self.breakpoints.contains(&self.machine.pc()) {
+                break;
+            }
+            stop = self.machine.run(1, |_, _| {});
+            if stop != Stop::StepLimit {
+                break;
+            }
+        }
+        let mut replies = Vec::new();
+        for (dev, d) in &self.machine.devices {
+            let shown = self.shown.entry(*dev).or_insert(0);
+            if d.output.len() > *shown {
+                replies.push(format!("O{}", hex(&d.output[*shown..], false)));
+                *shown = d.output.len();
+            }
+        }
+        let stopped = match stop {
+            // Stopped at a breakpoint, or after the single step asked for.
+            Stop::StepLimit if !resume || self.breakpoints.contains(&self.machine.pc()) => format!("S{:02x}", SIGTRAP),
+            ref s => Stub::stop_reply(s)
+        };
+        replies.push(stopped);
+        replies
+    }
+
+    fn register_bytes(&self, r: Register) -> Vec<u8> {
+        let v = self.machine.register(r);
+        (0..width(r)).rev().map(|k| (v >> (8 * k)) as u8).collect()
+    }
+
+    fn set_register_bytes(&mut self, r: Register, bytes: &[u8]) {
+        self.machine.set_register(r, bytes.iter().fold(0u64, |v, b| v << 8 | *b as u64));
+    }
+
+    // `addr,len` in hexadecimal, checked against the size of memory.
+    fn span(text: &str) -> Option<(u32, usize)> {
+        let (addr, len) = text.split_once(',')?;
+        let (addr, len) = (u32::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?);
+        Some((addr, len)).filter(|(a, l)| (*a as usize).checked_add(*l).is_some_and(|end| end <= MEMORY_SIZE))
+    }
+
+    /// The replies to the packet holding `data`, without their framing. An
+    /// empty reply means the packet is not supported. Returns `None` for a
+    /// kill request, which has no reply.
+    pub fn handle(&mut self, data: &str) -> Option<Vec<String>> {
+        const ERROR: &str = "E01";
+        let reply = |s: &str| Some(vec![s.to_owned()]);
+        let mut chars = data.chars();
+        let command = chars.next();
+        let rest = chars.as_str();
+        match command {
+            Some('?') => reply(&format!("S{:02x}", SIGTRAP)),
+            Some('g') => reply(&Register::ALL.iter().map(|r| hex(&self.register_bytes(*r), false)).collect::<String>()),
+            Some('G') => match from_hex(rest) {
+                Some(ref bytes) if bytes.len() == Register::ALL.iter().map(|r| width(*r)).sum::<usize>() => {
+                    let mut at = 0;
+                    for r in Register::ALL.iter() {
+                        self.set_register_bytes(*r, &bytes[at..at + width(*r)]);
+                        at += width(*r);
+                    }
+                    reply("OK")
+                }
+                _ => reply(ERROR)
+            },
+            Some('p') => match usize::from_str_radix(rest, 16).ok().and_then(|n| Register::ALL.get(n)) {
+                Some(r) => reply(&hex(&self.register_bytes(*r), false)),
+                None => reply(ERROR)
+            },
+            Some('P') => {
+                let set = rest.split_once('=').and_then(|(n, v)| {
+                    let r = *Register::ALL.get(usize::from_str_radix(n, 16).ok()?)?;
+                    Some((r, from_hex(v).filter(|b| b.len() == width(r))?))
+                });
+                match set {
+                    Some((r, bytes)) => {
+                        self.set_register_bytes(r, &bytes);
+                        reply("OK")
+                    }
+                    None => reply(ERROR)
+                }
+            }
+            Some('m') => match Stub::span(rest) {
+                Some((addr, len)) => reply(&hex(&self.machine.memory()[addr as usize..addr as usize + len], false)),
+                None => reply(ERROR)
+            },
+            Some('M') => {
+                let write = rest.split_once(':').and_then(|(span, data)| {
+                    let (addr, len) = Stub::span(span)?;
+                    Some((addr, from_hex(data).filter(|d| d.len() == len)?))
+                });
+                match write.map(|(addr, bytes)| self.machine.write_memory(addr, &bytes)) {
+                    Some(Ok(())) => reply("OK"),
+                    _ => reply(ERROR)
+                }
+            }
+            Some('Z') | Some('z') => {
+                let fields: Vec<&str> = rest.split(',').collect();
+                match (fields.first(), fields.get(1).and_then(|a| u32::from_str_radix(a, 16).ok())) {
+                    (Some(&"0"), Some(addr)) | (Some(&"1"), Some(addr)) => {
+                        if command == Some('Z') {
+                            self.breakpoints.insert(addr);
+                        } else {
+                            self.breakpoints.remove(&addr);
+                        }
+                        reply("OK")
+                    }
+                    // Watchpoints are not supported.
+                    _ => reply("")
+                }
+            }
+            Some('s') | Some('c') => {
+                if let Some(addr) = (!rest.is_empty()).then(|| u32::from_str_radix(rest, 16).ok()) {
+                    match addr {
+                        Some(a) => self.machine.set_register(Register::PC, a as u64),
+                        None => return reply(ERROR)
+                    }
+                }
+                Some(self.run(command == Some('c')))
+            }
+            Some('H') => reply("OK"),
+            Some('k') => None,
+            Some('D') => reply("OK"),
+            _ => match data {
+                "QStartNoAckMode" => {
+                    self.no_ack = true;
+                    reply("OK")
+                }
+                "qAttached" => reply("1"),
+                "qC" => reply("QC1"),
+                "qfThreadInfo" => reply("m1"),
+                "qsThreadInfo" => reply("l"),
+                _ if data.starts_with("qSupported") => reply(&format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)),
+                _ if data.starts_with("qXfer:features:read:target.xml:") => {
+                    let span = data.rsplit(':').next().and_then(|s| s.split_once(','));
+                    let (offset, len) = match span.and_then(|(o, l)| Some((usize::from_str_radix(o, 16).ok()?, usize::from_str_radix(l, 16).ok()?))) {
+                        Some(s) => s,
+                        None => return reply(ERROR)
+                    };
+                    let part = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+                    let more = part.len() > len;
+                    reply(&format!("{}{}", if more { "m" } else { "l" }, &part[..part.len().min(len)]))
+                }
+                _ => reply("")
+            }
+        }
+    }
+}
+
+// Reads bytes up to and including `end`, or to the end of the input.
+// Reads up to `end`, keeping at most one byte more than `limit` so that an
+// overlong packet can be told apart without storing all of it.
+fn read_until(input: &mut impl Read, end: u8, limit: usize, into: &mut Vec<u8>) -> io::Result<bool> {
+    let mut b = [0u8];
+    loop {
+        if input.read(&mut b)? == 0 {
+            return Ok(false);
+        }
+        if b[0] == end {
+            return Ok(true);
+        }
+        if into.len() <= limit {
+            into.push(b[0]);
+        }
+    }
+}
+
+/// Serves one GDB session on `input` and `output` until GDB kills the
+/// program, detaches or disconnects.
+pub fn serve(stub: &mut Stub, input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+    let mut b = [0u8];
+    loop {
+        if input.read(&mut b)? == 0 {
+            return Ok(());
+        }
+        match b[0] {
+            b'$' => {}
+            // Acknowledgements, and anything between packets. This includes
+            // interrupts, since the program is always stopped while the stub
+            // reads.
+            _ => continue
+        }
+        let mut data = Vec::new();
+        let mut sum = Vec::new();
+        if !read_until(input, b'#', PACKET_SIZE, &mut data)? {
+            return Ok(());
+        }
+        for _ in 0..2 {
+            if input.read(&mut b)? == 0 {
+                return Ok(());
+            }
+            sum.push(b[0]);
+        }
+        let expected = data.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        let valid = data.len() <= PACKET_SIZE && std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok()) == Some(expected);
+        if !stub.no_ack {
+            output.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if !valid {
+            output.flush()?;
+            continue;
+        }
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let replies = match stub.handle(&data) {
+            Some(r) => r,
+            None => return Ok(())
+        };
+        let mut out = String::new();
+        for r in &replies {
+            let _ = write!(out, "{}", frame(r));
+        }
+        output.write_all(out.as_bytes())?;
+        output.flush()?;
+        if data == "D" {
+            return Ok(());
+        }
+    }
+}
+
+#[test]
+fn remote_protocol() {
+    let source = "\
+MAIN  START 1000h
+      LDA   #65
+      WD    DEV
+      J     NEXT
+NEXT  RSUB
+DEV   BYTE  X'05'
+      END   MAIN
+";
+    let result = super::Assembler::new().assemble("main.asm", source);
+    let mut m = Machine::new();
+    m.load(&result.program, 0x1000).unwrap();
+    let mut stub = Stub::new(m);
+    let one = |stub: &mut Stub, p: &str| stub.handle(p).unwrap().join(" ");
+
+    assert!(one(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+    let xml = one(&mut stub, "qXfer:features:read:target.xml:0,20");
+    assert_eq!(xml, "m<?xml version=\"1.0\"?>\n<!DOCTYPE ");
+    assert!(one(&mut stub, "qXfer:features:read:target.xml:20,1000").ends_with("</target>\n"));
+    assert_eq!(one(&mut stub, "g"), "000000000000ffffff000000000000000000000000000000001000800000");
+    assert_eq!(one(&mut stub, "p7"), "001000");
+    assert_eq!(one(&mut stub, "m1000,3"), "010041");
+    assert_eq!(one(&mut stub, "mfffff,2"), "E01");
+    assert_eq!(one(&mut stub, "m1,ffffffffffffffff"), "E01");
+    assert_eq!(one(&mut stub, "M1,ffffffffffffffff:00"), "E01");
+
+    assert_eq!(one(&mut stub, "s"), "S05");
+    assert_eq!(one(&mut stub, "p0"), "000041");
+    assert_eq!(one(&mut stub, "Z0,1009,3"), "OK");
+    assert_eq!(one(&mut stub, "c"), "O41 S05");
+    assert_eq!(one(&mut stub, "p7"), "001009");
+    assert_eq!(one(&mut stub, "P0=000007"), "OK");
+    assert_eq!(one(&mut stub, "M100c,1:07"), "OK");
+    assert_eq!(one(&mut stub, "z0,1009,3"), "OK");
+    assert_eq!(one(&mut stub, "c"), "W00");
+    assert_eq!(one(&mut stub, "vMustReplyEmpty"), "");
+    assert_eq!(one(&mut stub, "é"), "");
+    assert!(stub.handle("k").is_none());
+
+    // Framing, acknowledgements and checksums.
+    let mut out = Vec::new();
+    serve(&mut Stub::new(Machine::new()), &mut &b"+$?#3f\x03$qC#00$qC#b4$D#44"[..], &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "+$S05#b8-+$QC1#c5+$OK#9a");
+    // Packets longer than the advertised size are refused.
+    let input = format!("${}#30", "0".repeat(PACKET_SIZE + 1));
+    let mut out = Vec::new();
+    serve(&mut Stub::new(Machine::new()), &mut input.as_bytes(), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "-");
+    let mut out = Vec::new();
+    serve(&mut Stub::new(Machine::new()), &mut "$é#6c".as_bytes(), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "+$#00");
+}
@@ -0,0 +1,240 @@
+use std::fmt::Write;
+
+use super::pass_two::ObjectProgram;
+use super::sim::MEMORY_SIZE;
+
+/// The bytes of `program` as a loader would place them in memory when the
+/// program is loaded at `at` instead of its START address: runs of
+/// consecutive bytes with the address of the first, in address order, with
+/// every modification record applied.
+pub fn load(program: &ObjectProgram, at: u32) -> Vec<(u32, Vec<u8>)> {
+    let offset = at.wrapping_sub(program.start);
+    let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (addr, bytes) in &program.text {
+        match runs.last_mut() {
+            Some((a, b)) if *a + b.len() as u32 == *addr => b.extend_from_slice(bytes),
+            _ => runs.push((*addr, bytes.clone()))
+        }
+    }
+
+    for m in &program.modifications {
+        // The field is `length` half-bytes ending on a byte boundary.
+        let size = (m.length as usize).div_ceil(2);
+        let mask = (1u32 << (4 * m.length as u32)) - 1;
+        let run = runs.iter_mut().find(|(a, b)| *a <= m.mem_loc && m.mem_loc + size as u32 <= *a + b.len() as u32);
+        if let Some((a, b)) = run {
+            let field = &mut b[(m.mem_loc - *a) as usize..][..size];
+            let old = field.iter().fold(0u32, |v, x| v << 8 | *x as u32);
+            let moved = if m.pos { (old & mask).wrapping_add(offset) } else { (old & mask).wrapping_sub(offset) };
+            let new = (old & !mask) | (moved & mask);
+            for (k, x) in field.iter_mut().enumerate() {
+                *x = (new >> (8 * (size - 1 - k))) as u8;
+            }
+        }
+    }
+
+    for (a, _) in &mut runs {
+        *a = a.wrapping_add(offset);
+    }
+    runs
+}
+
+// The most data bytes in one Intel HEX or S-record.
+const RECORD_DATA: usize = 16;
+
+// Splits the runs into pieces of at most `RECORD_DATA` bytes that do not
+// cross a 64 KiB boundary.
+fn records(runs: &[(u32, Vec<u8>)]) -> Vec<(u32, &[u8])> {
+    let mut out = Vec::new();
+    for (addr, bytes) in runs {
+        let mut addr = *addr;
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let to_boundary = 0x10000 - (addr & 0xFFFF) as usize;
+            let n = rest.len().min(RECORD_DATA).min(to_boundary);
+            out.push((addr, &rest[..n]));
+            addr += n as u32;
+            rest = &rest[n..];
+        }
+    }
+    out
+}
+
+fn hex_record(out: &mut String, start: &str, fields: &[u8], checksum: u8) {
+    out.push_str(start);
+    for b in fields {
+        let _ = write!(out, "{:02X}", b);
+    }
+    let _ = writeln!(out, "{:02X}", checksum);
+}
+
+fn ihex_record(out: &mut String, kind: u8, addr: u16, data: &[u8]) {
+    let mut fields = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    fields.extend_from_slice(data);
+    let sum = fields.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    hex_record(out, ":", &fields, sum.wrapping_neg());
+}
+
+/// The program loaded at `at`, as Intel HEX: data records of at most 16
+/// bytes, an extended linear address record whenever the upper 16 bits of
+/// the address change, the entry point as a start linear address record, and
+/// an end of file record.
+pub fn intel_hex(program: &ObjectProgram, at: u32) -> String {
+    let mut out = String::new();
+    let mut upper = 0u32;
+    for (addr, data) in records(&load(program, at)) {
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            ihex_record(&mut out, 0x04, 0, &(upper as u16).to_be_bytes());
+        }
+        ihex_record(&mut out, 0x00, addr as u16, data);
+    }
+    let entry = program.entry.wrapping_sub(program.start).wrapping_add(at);
+    ihex_record(&mut out, 0x05, 0, &entry.to_be_bytes());
+    ihex_record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+// An S-record of type `kind` with an address of `width` bytes.
+fn srec_record(out: &mut String, kind: u8, width: usize, addr: u32, data: &[u8]) {
+    let mut fields = vec![(width + data.len() + 1) as u8];
+    fields.extend_from_slice(&addr.to_be_bytes()[4 - width..]);
+    fields.extend_from_slice(data);
+    let sum = fields.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    hex_record(out, &format!("S{}", kind), &fields, !sum);
+}
+
+/// The program loaded at `at`, as Motorola S-records: a header with the
+/// program name, data records of at most 16 bytes, a count of the data
+/// records and the entry point. Addresses are 16 bits (S1/S9), or 24 bits
+/// (S2/S8) if any byte lies above 64 KiB.
+pub fn srec(program: &ObjectProgram, at: u32) -> String {
+    let runs = load(program, at);
+    let entry = program.entry.wrapping_sub(program.start).wrapping_add(at);
+    let top = runs.iter().map(|(a, b)| a + b.len() as u32).max().unwrap_or(0).max(entry + 1);
+    let (width, data, end) = if top > 0x10000 { (3, 2, 8) } else { (2, 1, 9) };
+
+    let mut out = String::new();
+    srec_record(&mut out, 0, 2, 0, program.name.as_bytes());
+    let records = records(&runs);
+    for (addr, bytes) in &records {
+        srec_record(&mut out, data, width, *addr, bytes);
+    }
+    if records.len() <= 0xFFFF {
+        srec_record(&mut out, 5, 2, records.len() as u32, &[]);
+    }
+    srec_record(&mut out, end, width, entry, &[]);
+    out
+}
+
+/// The layout of a flat memory image, set builder-style.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Raw {
+    fill: u8,
+    size: Option<usize>,
+    range: Option<(u32, u32)>
+}
+
+impl Raw {
+    /// An image from the lowest to the highest loaded address, with gaps
+    /// filled with zeros.
+    pub fn new() -> Raw {
+        Raw::default()
+    }
+
+    /// The byte written where the program has no code, such as the space
+    /// reserved by RESB and RESW.
+    pub fn fill(mut self, b: u8) -> Self {
+        self.fill = b;
+        self
+    }
+
+    /// Pads the image with the fill byte to `n` bytes.
+    pub fn size(mut self, n: usize) -> Self {
+        self.size = Some(n);
+        self
+    }
+
+    /// Only includes the addresses from `from` up to, but not including, `to`.
+    pub fn range(mut self, from: u32, to: u32) -> Self {
+        self.range = Some((from, to));
+        self
+    }
+
+    /// The program loaded at `at`, as one block of bytes starting at the first
+    /// address of the range.
+    pub fn image(&self, program: &ObjectProgram, at: u32) -> Result<Vec<u8>, String> {
+        let runs = load(program, at);
+        let (from, to) = match self.range {
+            Some(r) => r,
+            None => (
+                runs.iter().map(|r| r.0).min().unwrap_or(0),
+                runs.iter().map(|(a, b)| a + b.len() as u32).max().unwrap_or(0)
+            )
+        };
+        if self.range.is_some_and(|r| r.1 as usize > MEMORY_SIZE) || self.size.is_some_and(|n| n > MEMORY_SIZE) {
+            return Err(format!("the image cannot be larger than the {} bytes of memory", MEMORY_SIZE));
+        }
+        let mut out = vec![self.fill; to.saturating_sub(from) as usize];
+        for (addr, bytes) in &runs {
+            for (k, b) in bytes.iter().enumerate() {
+                let a = addr + k as u32;
+                if (from..to).contains(&a) {
+                    out[(a - from) as usize] = *b;
+                }
+            }
+        }
+        match self.size {
+            Some(n) if out.len() > n => Err(format!("the image is {} bytes, more than the {} it is padded to", out.len(), n)),
+            Some(n) => {
+                out.resize(n, self.fill);
+                Ok(out)
+            }
+            None => Ok(out)
+        }
+    }
+}
+
+#[test]
+fn hex_formats() {
+    let source = "PROG START 1000h\nFIRST +JSUB FIRST\nPTR WORD PTR\n RESB 5\nLAST BYTE X'AA'\n END FIRST\n";
+    let result = super::Assembler::new().assemble("p.asm", source);
+    let program = &result.program;
+
+    // Loaded where it was assembled, the image is the text records.
+    assert_eq!(load(program, 0x1000), vec![(0x1000, vec![0x4B, 0x10, 0x10, 0x00, 0x00, 0x10, 0x04]), (0x100C, vec![0xAA])]);
+    // Loaded elsewhere, the +JSUB target and the WORD move with it.
+    assert_eq!(load(program, 0x12345), vec![(0x12345, vec![0x4B, 0x11, 0x23, 0x45, 0x01, 0x23, 0x49]), (0x12351, vec![0xAA])]);
+
+    assert_eq!(intel_hex(program, 0x1000), "\
+:071000004B1010000010046A
+:01100C00AA39
+:0400000500001000E7
+:00000001FF
+");
+    assert_eq!(intel_hex(program, 0x12345), "\
+:020000040001F9
+:072345004B11234501234960
+:01235100AAE1
+:04000005000123458E
+:00000001FF
+");
+
+    assert_eq!(srec(program, 0x1000), "\
+S007000050524F47C0
+S10A10004B10100000100466
+S104100CAA35
+S5030002FA
+S9031000EC
+");
+    assert!(srec(program, 0x12345).lines().all(|l| !l.starts_with("S1") && !l.starts_with("S9")));
+    assert!(srec(program, 0x12345).ends_with("S80401234592\n"));
+
+    let raw = |r: Raw| r.image(program, 0x1000);
+    let code = [0x4B, 0x10, 0x10, 0x00, 0x00, 0x10, 0x04];
+    assert_eq!(raw(Raw::new().fill(0xFF)), Ok([&code[..], &[0xFF; 5], &[0xAA]].concat()));
+    assert_eq!(raw(Raw::new().range(0x1004, 0x100E).size(12)), Ok([&code[4..], &[0; 5], &[0xAA], &[0; 3]].concat()));
+    assert!(raw(Raw::new().range(0, 0xFFFFFFFF)).is_err());
+    assert!(raw(Raw::new().size(0xFFFFFFFF)).is_err());
+    assert!(raw(Raw::new().size(4)).is_err());
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+
+use super::line::*;
+use super::diagnostic::{Diagnostic, Code, Location};
+
+/// The operand shape an instruction expects, as written in the source.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Operands {
+    /// No operands (`RSUB`, `HIO`).
+    None,
+    /// A memory operand with an optional `,X` (`LDA BUFFER,X`).
+    Memory,
+    /// A single register (`CLEAR X`).
+    Register,
+    /// Two registers (`COMPR A,S`).
+    RegisterPair,
+    /// A register and a count (`SHIFTL A,4`).
+    RegisterNumber,
+    /// A number that fits in a nibble (`SVC 2`).
+    Number
+}
+
+impl Operands {
+    pub fn parse(s: &str) -> Option<Operands> {
+        match s {
+            "-"   => Some(Operands::None),
+            "m"   => Some(Operands::Memory),
+            "r"   => Some(Operands::Register),
+            "r,r" => Some(Operands::RegisterPair),
+            "r,n" => Some(Operands::RegisterNumber),
+            "n"   => Some(Operands::Number),
+            _     => None
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Operands::None           => "no operands",
+            Operands::Memory         => "a memory operand",
+            Operands::Register       => "a register",
+            Operands::RegisterPair   => "two registers",
+            Operands::RegisterNumber => "a register and a number",
+            Operands::Number         => "a number"
+        }
+    }
+
+    /// Checks that `args` has the shape this instruction expects.
+    pub fn accepts(&self, args: &[arg_struct]) -> bool {
+        let is_reg = |a: &arg_struct| a.reg_code != 0xFF;
+        let is_num = |a: &arg_struct| a.val.unwrap_as_int().is_some();
+        match self {
+            Operands::None           => args.is_empty(),
+            Operands::Memory         => args.len() == 1 || args.len() == 2,
+            Operands::Register       => args.len() == 1 && is_reg(&args[0]),
+            Operands::RegisterPair   => args.len() == 2 && is_reg(&args[0]) && is_reg(&args[1]),
+            Operands::RegisterNumber => args.len() == 2 && is_reg(&args[0]) && is_num(&args[1]),
+            Operands::Number         => args.len() == 1 && is_num(&args[0])
+        }
+    }
+}
+
+/// A single machine instruction: mnemonic, opcode, format and operand shape.
+///
+/// `format` is 1, 2 or 3, where 3 stands for formats 3 and 4 (selected with `+`).
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct InstrDef {
+    pub name: String,
+    pub opcode: u8,
+    pub format: u8,
+    pub operands: Operands
+}
+
+impl InstrDef {
+    pub fn to_op(&self) -> op_struct {
+        op_struct::new(self.opcode, &self.name).shape(self.format, self.operands)
+    }
+}
+
+pub const DIRECTIVES: &[&str] = &[
+    "START", "END", "BYTE", "WORD", "RESB", "RESW", "BASE", "NOBASE", "EQU"
+];
+
+pub const REGISTERS: &[&str] = &["A", "X", "L", "B", "S", "T", "F", "PC", "SW"];
+
+const SIC_XE: &[(&str, u8, u8, Operands)] = &[
+    ("ADD"   , 0x18, 3, Operands::Memory),
+  //("ADDF"  , 0x58, 3, Operands::Memory),
+    ("ADDR"  , 0x90, 2, Operands::RegisterPair),
+    ("AND"   , 0x40, 3, Operands::Memory),
+    ("CLEAR" , 0xB4, 2, Operands::Register),
+    ("COMP"  , 0x28, 3, Operands::Memory),
+    ("COMPR" , 0xA0, 2, Operands::RegisterPair),
+    ("DIV"   , 0x24, 3, Operands::Memory),
+  //("DIVF"  , 0x64, 3, Operands::Memory),
+    ("DIVR"  , 0x9C, 2, Operands::RegisterPair),
+  //("FIX"   , 0xC4, 1, Operands::None),
+  //("FLOAT" , 0xC0, 1, Operands::None),
+    ("HIO"   , 0xF4, 1, Operands::None),
+    ("J"     , 0x3C, 3, Operands::Memory),
+    ("JEQ"   , 0x30, 3, Operands::Memory),
+    ("JGT"   , 0x34, 3, Operands::Memory),
+    ("JLT"   , 0x38, 3, Operands::Memory),
+    ("JSUB"  , 0x48, 3, Operands::Memory),
+    ("LDA"   , 0x00, 3, Operands::Memory),
+    ("LDB"   , 0x68, 3, Operands::Memory),
+    ("LDCH"  , 0x50, 3, Operands::Memory),
+  //("LDF"   , 0x70, 3, Operands::Memory),
+    ("LDL"   , 0x08, 3, Operands::Memory),
+    ("LDS"   , 0x6C, 3, Operands::Memory),
+    ("LDT"   , 0x74, 3, Operands::Memory),
+    ("LDX"   , 0x04, 3, Operands::Memory),
+    ("LPS"   , 0xD0, 3, Operands::Memory),
+    ("MUL"   , 0x20, 3, Operands::Memory),
+  //("MULF"  , 0x60, 3, Operands::Memory),
+    ("MULR"  , 0x98, 2, Operands::RegisterPair),
+  //("NORM"  , 0xC8, 1, Operands::None),
+    ("OR"    , 0x44, 3, Operands::Memory),
+    ("RD"    , 0xD8, 3, Operands::Memory),
+    ("RMO"   , 0xAC, 2, Operands::RegisterPair),
+    ("RSUB"  , 0x4C, 3, Operands::None),
+    ("SHIFTL", 0xA4, 2, Operands::RegisterNumber),
+    ("SHIFTR", 0xA8, 2, Operands::RegisterNumber),
+    ("SIO"   , 0xF0, 1, Operands::None),
+    ("SSK"   , 0xEC, 3, Operands::Memory),
+    ("STA"   , 0x0C, 3, Operands::Memory),
+    ("STB"   , 0x78, 3, Operands::Memory),
+    ("STCH"  , 0x54, 3, Operands::Memory),
+  //("STF"   , 0x80, 3, Operands::Memory),
+    ("STI"   , 0xD4, 3, Operands::Memory),
+    ("STL"   , 0x14, 3, Operands::Memory),
+    ("STS"   , 0x7C, 3, Operands::Memory),
+    ("STSW"  , 0xE8, 3, Operands::Memory),
+    ("STT"   , 0x84, 3, Operands::Memory),
+    ("STX"   , 0x10, 3, Operands::Memory),
+    ("SUB"   , 0x1C, 3, Operands::Memory),
+  //("SUBF"  , 0x5C, 3, Operands::Memory),
+    ("SUBR"  , 0x94, 2, Operands::RegisterPair),
+    ("SVC"   , 0xB0, 2, Operands::Number),
+    ("TD"    , 0xE0, 3, Operands::Memory),
+    ("TIO"   , 0xF8, 1, Operands::None),
+    ("TIX"   , 0x2C, 3, Operands::Memory),
+    ("TIXR"  , 0xB8, 2, Operands::Register),
+    ("WD"    , 0xDC, 3, Operands::Memory),
+];
+
+/// The set of instructions the assembler recognises.
+///
+/// Starts out as the standard SIC/XE set and can be extended or partially
+/// replaced from a definition file (see `extend_from_str` for the format).
+#[derive(Debug, Clone)]
+pub struct Isa {
+    defs: HashMap<String, InstrDef>
+}
+
+impl Isa {
+    pub fn sic_xe() -> Isa {
+        let mut defs = HashMap::new();
+        for &(name, opcode, format, operands) in SIC_XE {
+            defs.insert(name.to_owned(), InstrDef { name: name.to_owned(), opcode, format, operands });
+        }
+        Isa { defs }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InstrDef> {
+        self.defs.get(&name.to_uppercase())
+    }
+
+    /// Every instruction, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &InstrDef> {
+        self.defs.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.defs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defs.is_empty()
+    }
+
+    pub fn extend_from_file(&mut self, path: &str) -> Result<(), Vec<Diagnostic>> {
+        let mut src = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .map_err(|e| vec![Diagnostic::error(Code::IsaDefinition, &e.to_string()).file(path)])?;
+        self.extend_from_str(&src, path)
+    }
+
+    /// Adds the definitions in `src` to this set, replacing any instruction
+    /// with the same mnemonic.
+    ///
+    /// Each non-blank line holds `MNEMONIC OPCODE FORMAT OPERANDS`, where the
+    /// opcode is hexadecimal (an `0x` prefix is optional), the format is `1`, `2`
+    /// or `3` (also written `3/4`) and the operands are one of `-`, `m`, `r`,
+    /// `r,r`, `r,n` or `n`. Everything after a `#` is ignored.
+    ///
+    /// The whole file is validated before anything is added; on failure every
+    /// problem found is returned, located in `origin`.
+    pub fn extend_from_str(&mut self, src: &str, origin: &str) -> Result<(), Vec<Diagnostic>> {
+        let mut errs = Vec::new();
+        let mut new_defs: Vec<(u32, InstrDef)> = Vec::new();
+
+        for (no, text) in src.lines().enumerate().map(|(i, l)| (i as u32 + 1, l)) {
+            let line = text.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |field: Option<usize>, msg: &str| {
+                Diagnostic::error(Code::IsaDefinition, msg).file(origin).line(no).cols(field_cols(text, field))
+            };
+            match parse_def(line) {
+                Ok(def) => {
+                    if let Some(&(first, _)) = new_defs.iter().find(|(_, d)| d.name == def.name) {
+                        let first_text = src.lines().nth(first as usize - 1).unwrap_or("");
+                        errs.push(
+                            err(Some(0), &format!("{} is already defined", def.name))
+                                .note(
+                                    Location { file: origin.to_owned(), line: first, cols: field_cols(first_text, Some(0)) },
+                                    "first defined here"
+                                )
+                        );
+                    } else {
+                        new_defs.push((no, def));
+                    }
+                }
+                Err((field, e)) => errs.push(err(field, &e))
+            }
+        }
+
+        if !errs.is_empty() {
+            return Err(errs);
+        }
+        for (_, def) in new_defs {
+            self.defs.insert(def.name.clone(), def);
+        }
+        Ok(())
+    }
+}
+
+// The columns of field `n` of a definition line, or of the whole definition
+// if `n` is `None`.
+fn field_cols(text: &str, n: Option<usize>) -> Range<usize> {
+    let code = text.split('#').next().unwrap_or("");
+    let mut fields: Vec<Range<usize>> = Vec::new();
+    let mut start = None;
+    for (k, c) in code.char_indices().chain(std::iter::once((code.len(), ' '))) {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                fields.push(s..k);
+                start = None;
+            }
+            (false, None) => start = Some(k),
+            _ => {}
+        }
+    }
+    match (n.and_then(|n| fields.get(n)), fields.first(), fields.last()) {
+        (Some(f), _, _) => f.clone(),
+        (None, Some(first), Some(last)) => first.start..last.end,
+        _ => 0..0
+    }
+}
+
+// On failure, returns the index of the offending field along with the message.
+fn parse_def(line: &str) -> Result<InstrDef, (Option<usize>, String)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 4 {
+        return Err((None, format!("expected `MNEMONIC OPCODE FORMAT OPERANDS`, found {} field(s)", fields.len())));
+    }
+
+    let name = fields[0].to_uppercase();
+    let valid_name = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return Err((Some(0), format!("`{}` is not a valid mnemonic", fields[0])));
+    }
+    if DIRECTIVES.contains(&&*name) || REGISTERS.contains(&&*name) {
+        return Err((Some(0), format!("`{}` is reserved and cannot be used as a mnemonic", name)));
+    }
+
+    let digits = fields[1].strip_prefix("0x").or_else(|| fields[1].strip_prefix("0X")).unwrap_or(fields[1]);
+    let opcode = Some(digits)
+        .filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|d| u8::from_str_radix(d, 16).ok())
+        .ok_or_else(|| (Some(1), format!("`{}` is not a valid opcode, expected a hex byte", fields[1])))?;
+
+    let format = match fields[2] {
+        "1" => 1,
+        "2" => 2,
+        "3" | "4" | "3/4" => 3,
+        f => return Err((Some(2), format!("`{}` is not a valid format, expected 1, 2 or 3/4", f)))
+    };
+
+    let operands = Operands::parse(fields[3])
+        .ok_or_else(|| (Some(3), format!("`{}` is not a valid operand shape, expected one of -, m, r, r,r, r,n, n", fields[3])))?;
+
+    let shape_ok = match format {
+        1 => operands == Operands::None,
+        2 => operands != Operands::None && operands != Operands::Memory,
+        _ => operands == Operands::None || operands == Operands::Memory
+    };
+    if !shape_ok {
+        return Err((Some(3), format!("format {} instructions cannot take {}", fields[2], operands.describe())));
+    }
+    if format == 3 && opcode & 0x03 != 0 {
+        return Err((Some(1), format!("format 3/4 opcode {:02X} must have its low two bits clear", opcode)));
+    }
+
+    Ok(InstrDef { name, opcode, format, operands })
+}
+
+#[test]
+fn isa_file() {
+    let mut isa = Isa::sic_xe();
+    let n = isa.len();
+    isa.extend_from_str("# custom ops\nMOV 0xC8 2 r,r\nlda 0x04 3/4 m  # swapped\n", "test.isa").unwrap();
+    assert_eq!(isa.len(), n + 1);
+    assert_eq!(isa.get("mov").unwrap().operands, Operands::RegisterPair);
+    assert_eq!(isa.get("LDA").unwrap().opcode, 0x04);
+
+    let errs = isa.extend_from_str("BCD 0xC9 3 m\nBYTE 0x10 1 -\nFOO 0x10 2 m\nBAR zz 1 -\n", "bad.isa").unwrap_err();
+    assert_eq!(errs.len(), 4);
+    assert_eq!(errs[0].to_string(), "bad.isa:1:5: error[E007]: format 3/4 opcode C9 must have its low two bits clear");
+    assert!(isa.get("BCD").is_none());
+
+    // A single `0x` prefix, then hex digits only.
+    let errs = isa.extend_from_str("OK1 0X10 1 -\nTWO 0x0x10 1 -\nPLUS +10 1 -\n", "prefix.isa").unwrap_err();
+    assert_eq!(errs.len(), 2);
+    assert_eq!(errs[0].to_string(), "prefix.isa:2:5: error[E007]: `0x0x10` is not a valid opcode, expected a hex byte");
+    assert_eq!(errs[1].to_string(), "prefix.isa:3:6: error[E007]: `+10` is not a valid opcode, expected a hex byte");
+}
@@ -0,0 +1,605 @@
+//! The JSON form of an assembly result, written by `--emit json`, and a small
+//! JSON reader for the language server.
+//!
+//! The schema is versioned, and only changes in ways that keep existing
+//! consumers working (new fields may be added) unless `version` changes.
+//! Addresses, values and lengths are plain numbers, object code is a string
+//! of hexadecimal digits, and absent values are `null`.
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "ok": true,                       // no diagnostic is an error
+//!   "program": { "name": "COPY", "start": 0, "length": 4218, "entry": 0 },
+//!   "lines": [ <line>, ... ],         // every source line, in order
+//!   "symbols": [ <symbol>, ... ],     // sorted by name
+//!   "literals": [ <literal>, ... ],
+//!   "modifications": [ <modification>, ... ],
+//!   "diagnostics": [ <diagnostic>, ... ]
+//! }
+//!
+//! <line> = {
+//!   "file": "copy.asm", "line": 2,    // line numbers start at 1 in each file
+//!   "text": "FIRST STL RETADR",       // the source line as written
+//!   "label": "FIRST",                 // or null
+//!   "operation": <operation>,         // null for blank and comment lines
+//!   "operands": [ <operand>, ... ],
+//!   "comment": "save return address", // or null
+//!   "address": 0,
+//!   "format": 3,                      // 1, 2, 3 or 4 for instructions, else null
+//!   "flags": { "n": true, "i": true, "x": false, "b": false, "p": true, "e": false },
+//!                                     // for encoded format 3 and 4 instructions, else null
+//!   "object": "17202D"                // empty if the line generates no code
+//! }
+//! <operation> = { "kind": "instruction", "name": "STL", "opcode": 20 }
+//!             | { "kind": "directive", "name": "WORD" }
+//!             | { "kind": "invalid" }
+//! <operand> = {
+//!   "text": "#LENGTH",                // as written, including the mode prefix
+//!   "mode": "direct" | "immediate" | "indirect" | "literal",
+//!   "register": 1,                    // the register code, or null
+//!   "value": <expr>
+//! }
+//! <expr> = { "kind": "number", "value": 4096 }     // 24 bits, two's complement
+//!        | { "kind": "chars", "value": "EOF" }
+//!        | { "kind": "symbol", "name": "BUFFER" }
+//!        | { "kind": "here" }                       // `*`
+//!        | { "kind": "binary", "op": "-", "lhs": <expr>, "rhs": <expr> }
+//! <symbol> = { "name": "BUFFER", "value": 54, "relative": true, "line": 20 }
+//! <literal> = { "text": "=C'EOF'", "address": null, "lines": [11] }
+//! <modification> = { "address": 7, "length": 5, "sign": "+", "symbol": null }
+//!                                     // length in half-bytes; symbol is null when
+//!                                     // the field is relative to the program start
+//! <diagnostic> = {
+//!   "severity": "error" | "warning" | "note",
+//!   "code": "E001", "message": "...",
+//!   "file": "copy.asm", "line": 3,
+//!   "columns": [9, 15],               // byte offsets in the line, end excluded
+//!   "related": [ { "file": ..., "line": ..., "columns": ..., "message": ... }, ... ]
+//! }
+//! ```
+//!
+//! Literal pools are not supported yet, so every literal is also reported as
+//! an error and its address is always `null`.
+
+use std::fmt;
+
+use super::assembler::AssemblyResult;
+use super::diagnostic::{Diagnostic, Location};
+use super::line::*;
+use super::xref;
+
+/// A JSON value. Object fields keep the order they were added in.
+///
+/// Numbers are integers: fractions are truncated when parsing.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>)
+}
+
+/// An object with the given fields, in order.
+pub fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+}
+
+impl Value {
+    /// Parses a complete JSON text.
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let mut p = Parser { text: text.as_bytes(), pos: 0, depth: 0 };
+        let v = p.value()?;
+        p.space();
+        if p.pos < p.text.len() {
+            return Err(p.error("unexpected text after the value"));
+        }
+        Ok(v)
+    }
+
+    /// The field `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(v) => v.iter().find(|f| f.0 == key).map(|f| &f.1),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(v) => Some(v),
+            _ => None
+        }
+    }
+}
+
+// How deeply arrays and objects can be nested, so that parsing hostile input
+// cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    // The arrays and objects the parser is inside.
+    depth: usize
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at byte {}", msg, self.pos)
+    }
+
+    fn space(&mut self) {
+        while self.text.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.space();
+        let found = self.text.get(self.pos) == Some(&c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.eat(c) { Ok(()) } else { Err(self.error(&format!("expected `{}`", c as char))) }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.space();
+        let rest = &self.text[self.pos..];
+        for (word, v) in [("null", Value::Null), ("true", Value::Bool(true)), ("false", Value::Bool(false))] {
+            if rest.starts_with(word.as_bytes()) {
+                self.pos += word.len();
+                return Ok(v);
+            }
+        }
+        match rest.first() {
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') | Some(b'{') if self.depth == MAX_DEPTH => Err(self.error("too deeply nested")),
+            Some(b'[') => {
+                self.depth += 1;
+                self.pos += 1;
+                let mut v = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        v.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                self.depth -= 1;
+                Ok(Value::Array(v))
+            }
+            Some(b'{') => {
+                self.depth += 1;
+                self.pos += 1;
+                let mut v = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.space();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        v.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                self.depth -= 1;
+                Ok(Value::Object(v))
+            }
+            Some(c) if *c == b'-' || c.is_ascii_digit() => {
+                let n = rest.iter().take_while(|c| c.is_ascii_digit() || b"+-.eE".contains(c)).count();
+                let text = std::str::from_utf8(&rest[..n]).unwrap_or("");
+                let v = text.parse::<i64>().ok().or_else(|| text.parse::<f64>().ok().map(|f| f as i64));
+                self.pos += n;
+                v.map(Value::Number).ok_or_else(|| self.error("invalid number"))
+            }
+            _ => Err(self.error("expected a value"))
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).and_then(|d| std::str::from_utf8(d).ok());
+        let n = digits.and_then(|d| u32::from_str_radix(d, 16).ok()).ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.text.get(self.pos) != Some(&b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = *self.text.get(self.pos).ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let e = *self.text.get(self.pos).ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    let c = match e {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let mut n = self.hex4()?;
+                            // A surrogate pair encodes one character.
+                            if (0xD800..0xDC00).contains(&n) && self.text[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                n = 0x10000 + ((n - 0xD800) << 10) + (self.hex4()? & 0x3FF);
+                            }
+                            char::from_u32(n).unwrap_or('\u{FFFD}')
+                        }
+                        c => c as char
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c => out.push(c)
+            }
+        }
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8 in string"))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Value {
+        Value::Number(n as i64)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Value {
+        Value::Number(n as i64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Value {
+        Value::Number(n as i64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Value {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Value {
+        Value::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_str(f, s),
+            Value::Array(v) => {
+                write!(f, "[")?;
+                for (k, x) in v.iter().enumerate() {
+                    if k > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(v) => {
+                write!(f, "{{")?;
+                for (k, (name, x)) in v.iter().enumerate() {
+                    if k > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, name)?;
+                    write!(f, ":{}", x)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Types with a documented JSON form.
+pub trait ToJson {
+    fn to_json(&self) -> Value;
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> Value {
+        Value::Array(self.iter().map(ToJson::to_json).collect())
+    }
+}
+
+impl ToJson for arg {
+    fn to_json(&self) -> Value {
+        match self {
+            arg::IntLit(x) => object(vec![("kind", "number".into()), ("value", (*x).into())]),
+            arg::StrLit(x) => object(vec![("kind", "chars".into()), ("value", x.as_str().into())]),
+            arg::Label(x) if x == "*" => object(vec![("kind", "here".into())]),
+            arg::Label(x) => object(vec![("kind", "symbol".into()), ("name", x.as_str().into())]),
+            arg::Expr(e) => object(vec![
+                ("kind", "binary".into()),
+                ("op", (e.op as char).to_string().as_str().into()),
+                ("lhs", e.lhs.to_json()),
+                ("rhs", e.rhs.to_json())
+            ])
+        }
+    }
+}
+
+impl ToJson for arg_struct {
+    fn to_json(&self) -> Value {
+        let mode = match self.modifier {
+            addr_mod::Direct => "direct",
+            addr_mod::Immediate => "immediate",
+            addr_mod::Indirect => "indirect",
+            addr_mod::Literal => "literal"
+        };
+        object(vec![
+            ("mode", mode.into()),
+            ("register", Some(self.reg_code).filter(|r| *r != 0xFF).map(|r| r as u32).into()),
+            ("value", self.val.to_json())
+        ])
+    }
+}
+
+impl ToJson for source_op {
+    fn to_json(&self) -> Value {
+        match self {
+            source_op::Instruction(x) => object(vec![
+                ("kind", "instruction".into()),
+                ("name", x.name.as_str().into()),
+                ("opcode", (x.opcode as u32).into())
+            ]),
+            source_op::Directive(x) => object(vec![("kind", "directive".into()), ("name", x.name.as_str().into())]),
+            source_op::Error => object(vec![("kind", "invalid".into())]),
+            source_op::Neh => Value::Null
+        }
+    }
+}
+
+impl ToJson for mod_rec {
+    fn to_json(&self) -> Value {
+        object(vec![
+            ("address", self.mem_loc.into()),
+            ("length", (self.length as u32).into()),
+            ("sign", if self.pos { "+" } else { "-" }.into()),
+            ("symbol", Some(&*self.symbol).filter(|s| !s.is_empty()).into())
+        ])
+    }
+}
+
+fn location(l: &Location) -> Vec<(&'static str, Value)> {
+    vec![
+        ("file", l.file.as_str().into()),
+        ("line", l.line.into()),
+        ("columns", vec![l.cols.start, l.cols.end].into())
+    ]
+}
+
+impl ToJson for Diagnostic {
+    fn to_json(&self) -> Value {
+        let mut v = vec![
+            ("severity", self.severity.to_string().as_str().into()),
+            ("code", self.code.as_str().into()),
+            ("message", self.message.as_str().into())
+        ];
+        v.extend(location(&self.location));
+        let related = self.related.iter().map(|r| {
+            let mut v = location(&r.location);
+            v.push(("message", r.message.as_str().into()));
+            object(v)
+        });
+        v.push(("related", Value::Array(related.collect())));
+        object(v)
+    }
+}
+
+// The instruction format of an instruction line.
+fn instruction_format(l: &Line) -> Option<u32> {
+    match l.operation {
+        source_op::Instruction(ref x) if x.format == 1 || x.format == 2 => Some(x.format as u32),
+        source_op::Instruction(ref x) => Some(if x.long { 4 } else { 3 }),
+        _ => None
+    }
+}
+
+impl ToJson for Line {
+    /// Everything but `file`, `text` and the text of each operand, which the
+    /// line does not record.
+    fn to_json(&self) -> Value {
+        let format = instruction_format(self);
+        let flags = match (format, &*self.obj_code) {
+            (Some(3 | 4), [first, second, ..]) => {
+                let bit = |b: u8, m: u8| Value::Bool(b & m != 0);
+                object(vec![
+                    ("n", bit(*first, 0x02)),
+                    ("i", bit(*first, 0x01)),
+                    ("x", bit(*second, 0x80)),
+                    ("b", bit(*second, 0x40)),
+                    ("p", bit(*second, 0x20)),
+                    ("e", bit(*second, 0x10))
+                ])
+            }
+            _ => Value::Null
+        };
+        let code: String = self.obj_code.iter().map(|b| format!("{:02X}", b)).collect();
+        object(vec![
+            ("line", self.line_no.into()),
+            ("label", self.label.as_deref().into()),
+            ("operation", self.operation.to_json()),
+            ("operands", self.args.to_json()),
+            ("comment", Some(&*self.comment).filter(|c| !c.is_empty()).into()),
+            ("address", self.mem_loc.into()),
+            ("format", format.into()),
+            ("flags", flags),
+            ("object", code.as_str().into())
+        ])
+    }
+}
+
+impl ToJson for AssemblyResult {
+    fn to_json(&self) -> Value {
+        let texts: Vec<Vec<&str>> = self.sources.iter().map(|s| s.1.lines().collect()).collect();
+        // The text of operand `i` of line `k`, as written.
+        let operand_text = |k: usize, i: usize| {
+            let span = self.statements.get(k).and_then(|s| s.operands.get(i)).map(|o| o.span.clone());
+            span.and_then(|r| self.sources[self.origin[k]].1.get(r)).unwrap_or("")
+        };
+        let lines = self.lines.iter().enumerate().map(|(k, l)| {
+            let n = self.origin[k];
+            let mut v = match l.to_json() {
+                Value::Object(v) => v,
+                _ => unreachable!()
+            };
+            for (field, x) in v.iter_mut() {
+                if let (true, Value::Array(ops)) = (field == "operands", x) {
+                    for (i, o) in ops.iter_mut().enumerate() {
+                        if let Value::Object(o) = o {
+                            o.insert(0, ("text".to_owned(), operand_text(k, i).into()));
+                        }
+                    }
+                }
+            }
+            let text = texts[n].get(l.line_no as usize - 1).cloned().unwrap_or("");
+            v.insert(0, ("file".to_owned(), self.sources[n].0.as_str().into()));
+            v.insert(2, ("text".to_owned(), text.into()));
+            Value::Object(v)
+        });
+
+        let symbols = xref::build(&self.lines, &self.symbols).into_iter().map(|e| object(vec![
+            ("name", e.name.as_str().into()),
+            ("value", e.value.into()),
+            ("relative", e.relative.into()),
+            ("line", e.defined.into())
+        ]));
+
+        let mut literals: Vec<(String, Vec<u32>)> = Vec::new();
+        for (k, l) in self.lines.iter().enumerate() {
+            for (i, _) in l.args.iter().enumerate().filter(|(_, a)| a.modifier == addr_mod::Literal) {
+                let text = operand_text(k, i).to_owned();
+                match literals.iter_mut().find(|(t, _)| *t == text) {
+                    Some((_, lines)) => lines.push(l.line_no),
+                    None => literals.push((text, vec![l.line_no]))
+                }
+            }
+        }
+        let literals = literals.into_iter().map(|(text, lines)| object(vec![
+            ("text", text.as_str().into()),
+            ("address", Value::Null),
+            ("lines", lines.into())
+        ]));
+
+        let p = &self.program;
+        object(vec![
+            ("version", 1u32.into()),
+            ("ok", self.is_ok().into()),
+            ("program", object(vec![
+                ("name", p.name.as_str().into()),
+                ("start", p.start.into()),
+                ("length", p.length.into()),
+                ("entry", p.entry.into())
+            ])),
+            ("lines", Value::Array(lines.collect())),
+            ("symbols", Value::Array(symbols.collect())),
+            ("literals", Value::Array(literals.collect())),
+            ("modifications", self.program.modifications.to_json()),
+            ("diagnostics", self.diagnostics.to_json())
+        ])
+    }
+}
+
+#[test]
+fn assembly_as_json() {
+    let source = "PROG START 0\nFIRST LDA #3 . load\n STA =C'A\"'\n END FIRST\n";
+    let result = super::Assembler::new().assemble("p.asm", source);
+    let json = result.to_json().to_string();
+
+    assert!(json.starts_with(r#"{"version":1,"ok":false,"program":{"name":"PROG","start":0,"#));
+    assert!(json.contains(concat!(
+        r#"{"file":"p.asm","line":2,"text":"FIRST LDA #3 . load","label":"FIRST","#,
+        r#""operation":{"kind":"instruction","name":"LDA","opcode":0},"#,
+        r##""operands":[{"text":"#3","mode":"immediate","register":null,"value":{"kind":"number","value":3}}],"##,
+        r#""comment":" load","address":0,"format":3,"#,
+        r#""flags":{"n":false,"i":true,"x":false,"b":false,"p":false,"e":false},"object":"010003"}"#
+    )));
+    assert!(json.contains(r#""literals":[{"text":"=C'A\"'","address":null,"lines":[3]}]"#));
+    assert!(json.contains(r#""symbols":[{"name":"FIRST","value":0,"relative":true,"line":2},"#));
+    assert!(json.contains(r#""code":"E004","#));
+
+    let parsed = Value::parse(&json).unwrap();
+    assert_eq!(parsed.to_string(), json);
+    assert_eq!(parsed.get("program").and_then(|p| p.get("name")).and_then(Value::as_str), Some("PROG"));
+    let escapes = Value::parse(r#" [1.5, -2, "a\"\u00e9\ud83d\ude00", {}, null] "#).unwrap();
+    assert_eq!(escapes.to_string(), "[1,-2,\"a\\\"é😀\",{},null]");
+    assert!(Value::parse("[1,]").is_err());
+    assert!(Value::parse(&format!("{}{}", "[".repeat(128), "]".repeat(128))).is_ok());
+    assert!(Value::parse(&"[".repeat(100000)).unwrap_err().contains("too deeply nested"));
+}
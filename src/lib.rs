@@ -0,0 +1,42 @@
+// The types of the intermediate representation are named after the fields of
+// a source line, and diagnostics are returned by value throughout.
+#![allow(non_camel_case_types, clippy::result_large_err)]
+
+//! An SIC/XE assembler.
+//!
+//! The `Assembler` type runs both passes over one or more sources and hands
+//! back everything it produced in an `AssemblyResult`:
+//!
+//! ```no_run
+//! let result = yacc::Assembler::new().text(true).assemble("copy.asm", "COPY START 0\n END COPY\n");
+//! if result.is_ok() {
+//!     println!("{}", String::from_utf8_lossy(&result.object));
+//! }
+//! ```
+
+pub mod parser;
+pub mod line;
+pub mod isa;
+pub mod diagnostic;
+pub mod warnings;
+pub mod pass_one;
+pub mod pass_two;
+pub mod xref;
+pub mod listing;
+pub mod json;
+pub mod image;
+pub mod lsp;
+pub mod formatter;
+pub mod sim;
+pub mod trace;
+pub mod profile;
+pub mod coverage;
+pub mod snapshot;
+pub mod debugger;
+pub mod gdb;
+mod assembler;
+
+pub use assembler::{Assembler, AssemblyResult};
+pub use diagnostic::{Diagnostic, Severity};
+pub use parser::SourceFormat;
+pub use warnings::{Warning, WarningSet};
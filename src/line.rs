@@ -2,15 +2,20 @@ use std::mem::discriminant;
 use std::collections::HashMap;
 
 
-use std::fmt::Write;
 use std::fmt;
 
-#[derive(Debug)]
+use super::isa::Operands;
+use super::pass_two::Encoding;
+
+#[derive(Debug, Clone)]
 pub struct Pos { pub line_no: u32, pub mem_loc: u32, pub val: Option<i32> }
 
 pub type Symtab = HashMap<String, Pos>;
-pub type Modtab = HashMap<u32, mod_rec>;
+pub type Modtab = Vec<mod_rec>;
 
+/// A modification record: `length` half-bytes starting at `mem_loc` hold an
+/// address the loader must relocate.
+#[derive(Debug, Clone)]
 pub struct mod_rec {
     pub mem_loc: u32,
     pub length: u8,
@@ -18,6 +23,12 @@ pub struct mod_rec {
     pub symbol: String
 }
 
+impl Default for mod_rec {
+    fn default() -> mod_rec {
+        mod_rec::new()
+    }
+}
+
 impl mod_rec {
     pub fn new() -> mod_rec {
         mod_rec {
@@ -76,6 +87,8 @@ pub struct Line {
     pub mem_loc: u32,
     pub format: format,
     pub obj_code: Vec<u8>,
+    /// How pass two encoded the operand of a format 3 or 4 instruction.
+    pub encoding: Option<Encoding>,
 }
 
 impl PartialEq for Line {
@@ -89,6 +102,12 @@ impl PartialEq for Line {
     }
 }
 
+impl Default for Line {
+    fn default() -> Line {
+        Line::new()
+    }
+}
+
 impl Line {
     pub fn new() -> Line {
         Line {
@@ -99,7 +118,8 @@ impl Line {
             line_no: 0,
             mem_loc: 0,
             format: format::None,
-            obj_code: Vec::new()
+            obj_code: Vec::new(),
+            encoding: None
         }
     }
 
@@ -138,11 +158,21 @@ impl Line {
         self
     }
 
+    /// True if the line assembles to a machine instruction.
+    pub fn is_instruction(&self) -> bool {
+        matches!(self.format, format::Opless | format::Register | format::Normal | format::Long)
+    }
+
+    /// The index of `a`, one of the operands of the line.
+    pub fn arg_index(&self, a: &arg_struct) -> usize {
+        self.args.iter().position(|b| std::ptr::eq(a, b)).unwrap_or(0)
+    }
+
 }
 
 #[derive(Debug, Eq, Clone)]
 pub enum addr_mod {
-    Direct = 0x00,
+    Direct = 0x03,
     Indirect = 0x02,
     Immediate = 0x01,
     Literal = 0x12
@@ -155,29 +185,6 @@ pub struct arg_struct {
     pub modifier: addr_mod
 }
 
-pub fn display_vec<T: fmt::Display>(v: &Vec<T>) -> String {
-        let mut a = String::new();
-        for i in v {
-            write!(a, "{}", i);
-        }
-        for i in 0..(if v.len() < 2 { 2 - v.len() } else { 1 }) {
-            write!(a, "      ");
-        }
-
-        a
-}
-
-pub fn display_vec_nums(v: &Vec<u8>) -> String {
-        let mut a = String::new();
-        for i in v {
-            write!(a, "{:02X}", i);
-        }
-        for i in 0..(if v.len() < 2 { 2 - v.len() } else { 0 }) {
-            write!(a, "      ");
-        }
-        a
-}
-
 impl fmt::Display for arg_struct {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:<8},", self.val)
@@ -213,10 +220,88 @@ impl arg {
             _ => ""
         }
     }
+
+    /// The symbols this operand refers to, not counting `*`.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            arg::Label(ref x) if x != "*" => vec![x],
+            arg::Expr(ref e) => {
+                let mut v = e.lhs.symbols();
+                v.extend(e.rhs.symbols());
+                v
+            }
+            _ => Vec::new()
+        }
+    }
+
+    /// Evaluates the operand, taking `*` to be `here`.
+    ///
+    /// Returns the 24-bit value and whether it is an address, which moves with
+    /// the program, rather than a constant. Following the usual rules for
+    /// relocation, the difference of two addresses is a constant, an address
+    /// plus or minus a constant is an address, and any other combination of
+    /// addresses is an error.
+    pub fn eval(&self, symtab: &Symtab, here: u32) -> Result<(i32, bool), EvalError> {
+        match self.eval_terms(symtab, here)? {
+            (v, 0) => Ok((v as i32 & 0x00FFFFFF, false)),
+            (v, 1) => Ok((v as i32 & 0x00FFFFFF, true)),
+            _ => Err(EvalError::Relocation)
+        }
+    }
+
+    // The signed value, and the number of addresses added minus the number
+    // subtracted. Every intermediate value must fit in 24 bits, as a signed
+    // constant or an address.
+    fn eval_terms(&self, symtab: &Symtab, here: u32) -> Result<(i64, i32), EvalError> {
+        // Values are stored in 24 bits; widen negative ones.
+        let signed = |v: i32| if v & 0x00800000 != 0 { (v & 0x00FFFFFF) as i64 - 0x01000000 } else { v as i64 };
+        let (v, relative) = match self {
+            arg::IntLit(x) => (signed(*x), 0),
+            arg::Label(ref x) if x == "*" => (here as i64, 1),
+            arg::Label(ref x) => match symtab.get(x) {
+                Some(Pos { val: Some(v), .. }) => (signed(*v), 0),
+                Some(p) => (p.mem_loc as i64, 1),
+                None => return Err(EvalError::Undefined(x.clone()))
+            },
+            arg::StrLit(_) => return Err(EvalError::NotANumber),
+            arg::Expr(ref e) => {
+                let (l, lr) = e.lhs.eval_terms(symtab, here)?;
+                let (r, rr) = e.rhs.eval_terms(symtab, here)?;
+                match e.op {
+                    b'+' => (l + r, lr + rr),
+                    b'-' => (l - r, lr - rr),
+                    b'*' | b'/' if lr != 0 || rr != 0 => return Err(EvalError::Relocation),
+                    b'*' => (l * r, 0),
+                    b'/' if r == 0 => return Err(EvalError::DivideByZero),
+                    b'/' => (l / r, 0),
+                    _ => return Err(EvalError::NotANumber)
+                }
+            }
+        };
+        if !(-0x00800000..=0x00FFFFFF).contains(&v) {
+            return Err(EvalError::Overflow);
+        }
+        Ok((v, relative))
+    }
+}
+
+/// Why an operand has no value.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum EvalError {
+    /// A symbol that is not in the symbol table.
+    Undefined(String),
+    /// A string of characters where a number was expected.
+    NotANumber,
+    /// An expression that is neither a constant nor a single address, such as
+    /// the sum of two addresses.
+    Relocation,
+    DivideByZero,
+    /// A value that does not fit in 24 bits, at any step of the expression.
+    Overflow
 }
 
 #[derive(Debug, Eq, Clone)]
-pub struct op_struct { pub opcode: u8, pub name: &'static str, pub long: bool }
+pub struct op_struct { pub opcode: u8, pub name: String, pub long: bool, pub format: u8, pub operands: Operands }
 
 #[derive(Debug, Eq, Clone)]
 pub enum source_op {
@@ -248,7 +333,13 @@ impl fmt::Display for arg {
             arg::Label(x) => write!(f, "{:<8}", x),
             arg::StrLit(x) => write!(f, "{X:<8}", X = format!(r#""{}""#, x)),
             arg::IntLit(x) => write!(f, "{:<8}", x),
-            arg::Expr(_) => write!(f, "Comment")
+            arg::Expr(ref e) => {
+                let part = |a: &arg| match a {
+                    arg::Expr(_) => format!("({})", a.to_string().trim()),
+                    _ => a.to_string().trim().to_owned()
+                };
+                write!(f, "{:<8}", format!("{}{}{}", part(&e.lhs), e.op as char, part(&e.rhs)))
+            }
         }
     }
 }
@@ -284,7 +375,7 @@ impl PartialEq for source_op {
 
 impl PartialEq for op_struct {
     fn eq(&self, other: &op_struct) -> bool {
-        self.name == other.name && self.opcode == other.opcode && self.long == other.long
+        self.name == other.name && self.opcode == other.opcode && self.long == other.long && self.format == other.format
     }
 }
 
@@ -302,14 +393,22 @@ impl PartialEq for expr_struct {
 
 
 impl op_struct {
-    pub fn new(oc: u8, n: &'static str) -> Self {
+    pub fn new(oc: u8, n: &str) -> Self {
         op_struct {
             opcode: oc,
-            name: n,
-            long: false
+            name: n.to_owned(),
+            long: false,
+            format: 0,
+            operands: Operands::None
         }
     }
 
+    pub fn shape(mut self, f: u8, o: Operands) -> Self {
+        self.format = f;
+        self.operands = o;
+        self
+    }
+
     pub fn long(mut self, l: bool) -> Self {
         self.long = l;
         self
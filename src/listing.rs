@@ -0,0 +1,271 @@
+use std::fmt::Write;
+
+use super::line::*;
+use super::pass_two;
+use super::xref;
+
+/// The page layout of the assembly listing.
+///
+/// Options are set builder-style. A page length of 0 prints the whole listing
+/// as one page, with a single header.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Layout {
+    page_length: usize,
+    page_width: usize,
+    explain: bool
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::new()
+    }
+}
+
+impl Layout {
+    /// Pages of 60 lines, 80 columns wide.
+    pub fn new() -> Layout {
+        Layout { page_length: 60, page_width: 80, explain: false }
+    }
+
+    /// The number of lines on a page, headers included.
+    pub fn page_length(mut self, n: usize) -> Self {
+        self.page_length = n;
+        self
+    }
+
+    /// The number of columns on a page. Source text that does not fit is
+    /// continued on the next line.
+    pub fn page_width(mut self, n: usize) -> Self {
+        self.page_width = n.max(MIN_WIDTH);
+        self
+    }
+
+    /// Follows each instruction with an explanation of how its object code
+    /// was worked out.
+    pub fn explain(mut self, e: bool) -> Self {
+        self.explain = e;
+        self
+    }
+}
+
+// The width of the line number, location and object code columns, and the
+// spaces between them.
+const SOURCE_COLUMN: usize = 5 + 2 + 6 + 2 + 8 + 2;
+const MIN_WIDTH: usize = SOURCE_COLUMN + 20;
+// The number of bytes of object code shown on each line.
+const CODE_BYTES: usize = 4;
+
+// The title, the column headings and a blank line.
+const HEADER_LINES: usize = 3;
+
+const LINE_COLUMNS: &str = " Line  Loc     Object    Source";
+const SYMBOL_COLUMNS: &str = "Symbol    Value   Type   Line";
+
+// Splits output into pages, each starting with a header.
+struct Pages<'a> {
+    out: String,
+    layout: &'a Layout,
+    title: String,
+    columns: &'static str,
+    page: usize,
+    used: usize
+}
+
+impl<'a> Pages<'a> {
+    // Adds the rows printed for one line, on the next page if they would not
+    // all fit on this one.
+    fn push(&mut self, rows: &[String]) {
+        let length = self.layout.page_length;
+        let fits = |used: usize| used + rows.len() <= length || used == HEADER_LINES;
+        if self.page == 0 || (length > 0 && !fits(self.used)) {
+            self.header();
+        }
+        for r in rows {
+            if length > 0 && self.used >= length {
+                self.header();
+            }
+            let _ = writeln!(self.out, "{}", r.trim_end());
+            self.used += 1;
+        }
+    }
+
+    fn header(&mut self) {
+        if self.page > 0 {
+            self.out.push('\x0C');
+        }
+        self.page += 1;
+        let number = format!("Page {}", self.page);
+        let room = self.layout.page_width.saturating_sub(number.len() + 1);
+        let title: String = self.title.chars().take(room).collect();
+        let _ = writeln!(self.out, "{:<w$} {}", title, number, w = room);
+        let _ = writeln!(self.out, "{}", self.columns);
+        let _ = writeln!(self.out);
+        self.used = HEADER_LINES;
+    }
+
+    // Starts a new part of the listing with its own title and column headings:
+    // on a new page if there are pages, and after a blank line otherwise.
+    fn section(&mut self, title: String, columns: &'static str) {
+        let changed = columns != self.columns;
+        self.title = title;
+        self.columns = columns;
+        if self.page == 0 {
+            return;
+        }
+        if self.layout.page_length > 0 {
+            self.used = self.layout.page_length;
+        } else if changed {
+            let _ = writeln!(self.out, "\n{}\n", columns);
+        }
+    }
+}
+
+// Replaces tabs with spaces up to the next multiple of 8 columns.
+fn expand_tabs(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if c == '\t' {
+            out.push(' ');
+            while !out.chars().count().is_multiple_of(8) {
+                out.push(' ');
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Splits `text` into pieces of at most `width` characters.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars.chunks(width).map(|c| c.iter().collect()).collect()
+}
+
+// Splits `text` into lines of at most `width` characters, between words
+// where possible.
+fn wrap_words(text: &str, width: usize) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for word in text.split(' ') {
+        match out.last_mut() {
+            Some(l) if l.chars().count() + 1 + word.chars().count() <= width => {
+                l.push(' ');
+                l.push_str(word);
+            }
+            _ => out.extend(wrap(word, width))
+        }
+    }
+    out
+}
+
+/// Formats the assembly listing: every source line with its line number,
+/// location and object code, followed by the symbol table. With
+/// `Layout::explain`, instructions are followed by the explanation from
+/// `pass_two::explain`.
+///
+/// `texts[k]` is the original text of `lines[k]` and `files[k]` the name of
+/// the source it came from. Object code longer than four bytes, and source
+/// text wider than the page, is continued on the lines that follow.
+pub fn render(lines: &[Line], texts: &[&str], files: &[&str], symtab: &Symtab, layout: &Layout) -> String {
+    let program = lines.iter()
+        .find(|l| l.operation.unwrap_as_directive() == "START")
+        .and_then(|l| l.label.clone())
+        .unwrap_or_default();
+    let title = |file: &str| if program.is_empty() { file.to_owned() } else { format!("{}  {}", program, file) };
+    let mut pages = Pages {
+        out: String::new(),
+        layout,
+        title: title(files.first().cloned().unwrap_or("")),
+        columns: LINE_COLUMNS,
+        page: 0,
+        used: 0
+    };
+
+    for (k, l) in lines.iter().enumerate() {
+        if k > 0 && files[k] != files[k - 1] {
+            pages.section(title(files[k]), LINE_COLUMNS);
+        }
+        let source = wrap(&expand_tabs(texts[k]), layout.page_width - SOURCE_COLUMN);
+        let code: Vec<&[u8]> = l.obj_code.chunks(CODE_BYTES).collect();
+        let mut rows = Vec::new();
+        for n in 0..source.len().max(code.len()) {
+            let number = if n == 0 { l.line_no.to_string() } else { String::new() };
+            let loc = match code.get(n) {
+                _ if l.format == format::Comment => String::new(),
+                Some(_) => format!("{:06X}", l.mem_loc as usize + n * CODE_BYTES),
+                None if n == 0 => format!("{:06X}", l.mem_loc),
+                None => String::new()
+            };
+            let hex: String = code.get(n).map_or(String::new(), |c| c.iter().map(|b| format!("{:02X}", b)).collect());
+            let text = source.get(n).map_or("", |s| s.as_str());
+            rows.push(format!("{:>5}  {:<6}  {:<8}  {}", number, loc, hex, text));
+        }
+        if layout.explain {
+            for e in pass_two::explain(l) {
+                for part in wrap_words(&e, layout.page_width - SOURCE_COLUMN - 2) {
+                    rows.push(format!("{:w$}| {}", "", part, w = SOURCE_COLUMN));
+                }
+            }
+        }
+        pages.push(&rows);
+    }
+
+    let title = pages.title.clone();
+    pages.section(title, SYMBOL_COLUMNS);
+    for e in xref::build(lines, symtab) {
+        pages.push(&[format!(
+            "{:<8}  {:06X}  {:<4}  {:>5}",
+            e.name,
+            e.value,
+            if e.relative { "R" } else { "A" },
+            e.defined
+        )]);
+    }
+    pages.out
+}
+
+#[test]
+fn listing_layout() {
+    let source = "\
+PROG START 1000h
+. read one record from the device
+FIRST LDA #3
+MSG BYTE C'HELLO'
+ END FIRST
+";
+    let result = super::Assembler::new().assemble("prog.asm", source);
+    let texts: Vec<&str> = source.lines().collect();
+    let files = vec!["prog.asm"; texts.len()];
+    let list = |layout: &Layout| render(&result.lines, &texts, &files, &result.symbols, layout);
+
+    let single = list(&Layout::new().page_length(0));
+    let expected = "\
+PROG  prog.asm                                                            Page 1
+ Line  Loc     Object    Source
+
+    1  001000            PROG START 1000h
+    2                    . read one record from the device
+    3  001000  010003    FIRST LDA #3
+    4  001003  48454C4C  MSG BYTE C'HELLO'
+       001007  4F
+    5  001008             END FIRST
+
+Symbol    Value   Type   Line
+
+FIRST     001000  R         3
+MSG       001003  R         4
+PROG      001000  R         1
+";
+    assert_eq!(single, expected);
+
+    // Three lines of header leave two for the program on each page. The
+    // source text wraps at the page width, and the rows of one line are kept
+    // on the same page: 1 | 2 | 3 | 4 | 5 | FIRST, MSG | PROG.
+    let paged = list(&Layout::new().page_length(5).page_width(MIN_WIDTH));
+    assert_eq!(paged.matches('\x0C').count(), 6);
+    assert!(paged.contains("    1  001000            PROG START 1000h\n"));
+    assert!(paged.contains("    2                    . read one record fr\n                         om the device\n"));
+}
@@ -0,0 +1,455 @@
+//! A language server for SIC/XE sources, speaking the Language Server
+//! Protocol over standard input and output.
+//!
+//! Documents are assembled whenever they are opened or saved, and the
+//! diagnostics published. The server also answers go to definition, find
+//! references and hover requests for labels, hover for mnemonics, and offers
+//! the mnemonics and directives as completions.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+
+use super::assembler::{Assembler, AssemblyResult};
+use super::diagnostic::{Diagnostic, Severity};
+use super::isa::{REGISTERS, DIRECTIVES};
+use super::json::{object, Value};
+use super::parser::{Expr, ExprKind, Span, Statement};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// What each directive does, for hover and completion.
+const DIRECTIVE_HELP: &[(&str, &str)] = &[
+    ("START", "names the program and sets its start address"),
+    ("END", "ends the program, optionally giving the first instruction to execute"),
+    ("BYTE", "stores character or hexadecimal constants"),
+    ("WORD", "stores one-word constants or addresses"),
+    ("RESB", "reserves a number of bytes"),
+    ("RESW", "reserves a number of words"),
+    ("BASE", "tells the assembler the value of register B for base-relative addressing"),
+    ("NOBASE", "stops base-relative addressing"),
+    ("EQU", "defines the label as the value of an expression")
+];
+
+/// The state of a language server session: the open documents and the
+/// assembler options used for them.
+pub struct Server {
+    assembler: Assembler,
+    documents: HashMap<String, String>,
+    shutdown: bool
+}
+
+// Reads one message: headers, a blank line, then `Content-Length` bytes.
+// The largest message body read, in bytes.
+const MAX_MESSAGE: usize = 1 << 24;
+
+// Reads the next message. A message that cannot be used gives the error code
+// and message to reply with, and the session carries on.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Result<Value, (i64, String)>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    if length > MAX_MESSAGE {
+        io::copy(&mut input.take(length as u64), &mut io::sink())?;
+        return Ok(Some(Err((INVALID_REQUEST, format!("the message is larger than {} bytes", MAX_MESSAGE)))));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let text = String::from_utf8_lossy(&body);
+    Ok(Some(Value::parse(&text).map_err(|e| (PARSE_ERROR, e))))
+}
+
+fn write_message(output: &mut impl Write, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Runs a session with documents assembled by `assembler`, reading requests
+/// from `input` and writing responses and notifications to `output`, until
+/// the client sends `exit` or closes the input. Returns whether the client
+/// asked the server to shut down first, as it should.
+pub fn serve(assembler: Assembler, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<bool> {
+    let mut server = Server::new(assembler);
+    while let Some(msg) = read_message(input)? {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err((code, e)) => {
+                write_message(output, &error_response(&Value::Null, code, &e))?;
+                continue;
+            }
+        };
+        for reply in server.handle(&msg) {
+            write_message(output, &reply)?;
+        }
+        if msg.get("method").and_then(Value::as_str) == Some("exit") {
+            break;
+        }
+    }
+    Ok(server.shutdown)
+}
+
+// The byte offset of the start of each line of `text`.
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(text.match_indices('\n').map(|(k, _)| k + 1));
+    starts
+}
+
+// The LSP position of byte `offset`: a line and a count of UTF-16 code units.
+fn position(text: &str, starts: &[usize], offset: usize) -> Value {
+    let line = starts.partition_point(|s| *s <= offset) - 1;
+    let prefix = text.get(starts[line]..offset).unwrap_or("");
+    object(vec![("line", line.into()), ("character", prefix.encode_utf16().count().into())])
+}
+
+fn range(text: &str, span: &Span) -> Value {
+    let starts = line_starts(text);
+    object(vec![("start", position(text, &starts, span.start)), ("end", position(text, &starts, span.end))])
+}
+
+// The byte offset of an LSP position, if it is inside `text`.
+fn offset(text: &str, pos: &Value) -> Option<usize> {
+    let line = pos.get("line")?.as_i64()? as usize;
+    let character = pos.get("character")?.as_i64()? as usize;
+    let start = *line_starts(text).get(line)?;
+    let line_text = text[start..].split('\n').next().unwrap_or("");
+    let mut units = 0;
+    for (k, c) in line_text.char_indices() {
+        if units >= character {
+            return Some(start + k);
+        }
+        units += c.len_utf16();
+    }
+    Some(start + line_text.len())
+}
+
+// Every symbol named in `e`, with its span.
+fn expr_symbols<'a>(e: &'a Expr, out: &mut Vec<(&'a str, Span)>) {
+    match e.kind {
+        ExprKind::Symbol(ref x) => out.push((x, e.span.clone())),
+        ExprKind::Neg(ref x) => expr_symbols(x, out),
+        ExprKind::Binary(_, ref l, ref r) => {
+            expr_symbols(l, out);
+            expr_symbols(r, out);
+        }
+        _ => {}
+    }
+}
+
+// Every label and symbol use in the program: the name, its span and whether
+// it is a label. Register names are left out unless the program defines them.
+fn occurrences(result: &AssemblyResult) -> Vec<(&str, Span, bool)> {
+    let mut out = Vec::new();
+    for s in &result.statements {
+        if let Some(ref l) = s.label {
+            out.push((&*l.text, l.span.clone(), true));
+        }
+        let mut uses = Vec::new();
+        for o in &s.operands {
+            expr_symbols(&o.expr, &mut uses);
+        }
+        out.extend(uses.into_iter().map(|(x, span)| (x, span, false)));
+    }
+    out.retain(|(x, _, _)| !REGISTERS.contains(x) || result.symbols.contains_key(*x));
+    out
+}
+
+fn diagnostic(text: &str, d: &Diagnostic) -> Value {
+    let to_range = |line: u32, cols: &Span| {
+        let start = line_starts(text).get(line.saturating_sub(1) as usize).cloned().unwrap_or(text.len());
+        range(text, &(start + cols.start..start + cols.end))
+    };
+    let severity = match d.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+        Severity::Note => 3
+    };
+    let related = d.related.iter().map(|r| object(vec![
+        ("location", object(vec![
+            ("uri", r.location.file.as_str().into()),
+            ("range", to_range(r.location.line, &r.location.cols))
+        ])),
+        ("message", r.message.as_str().into())
+    ]));
+    object(vec![
+        ("range", to_range(d.location.line, &d.location.cols)),
+        ("severity", Value::Number(severity)),
+        ("code", d.code.as_str().into()),
+        ("source", "yacc".into()),
+        ("message", d.message.as_str().into()),
+        ("relatedInformation", Value::Array(related.collect()))
+    ])
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    object(vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)])
+}
+
+fn response(id: &Value, result: Value) -> Value {
+    object(vec![("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)])
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+    object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", id.clone()),
+        ("error", object(vec![("code", Value::Number(code)), ("message", message.into())]))
+    ])
+}
+
+fn markdown(text: String) -> Value {
+    object(vec![("kind", "markdown".into()), ("value", Value::String(text))])
+}
+
+impl Server {
+    pub fn new(assembler: Assembler) -> Server {
+        Server {
+            assembler,
+            documents: HashMap::new(),
+            shutdown: false
+        }
+    }
+
+    /// Handles one message from the client, returning the messages to send
+    /// back: the response to a request, and any notifications.
+    pub fn handle(&mut self, msg: &Value) -> Vec<Value> {
+        let method = msg.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = msg.get("params").unwrap_or(&Value::Null);
+        let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Value::as_str).unwrap_or("").to_owned();
+        let mut out = Vec::new();
+        if self.shutdown && method != "exit" {
+            if let Some(id) = msg.get("id") {
+                out.push(error_response(id, INVALID_REQUEST, "the server is shutting down"));
+            }
+            return out;
+        }
+
+        let result = match method {
+            "initialize" => Some(self.capabilities()),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            }
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|d| d.get("text")).and_then(Value::as_str).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_owned());
+                out.push(self.publish(&uri));
+                None
+            }
+            "textDocument/didChange" => {
+                // Changes are sent as the whole text of the document.
+                let changes = params.get("contentChanges").and_then(Value::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|c| c.get("text")).and_then(Value::as_str) {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                }
+                None
+            }
+            "textDocument/didSave" => {
+                if let Some(text) = params.get("text").and_then(Value::as_str) {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                }
+                out.push(self.publish(&uri));
+                None
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                out.push(notification("textDocument/publishDiagnostics", object(vec![
+                    ("uri", uri.as_str().into()),
+                    ("diagnostics", Value::Array(Vec::new()))
+                ])));
+                None
+            }
+            "textDocument/definition" | "textDocument/references" | "textDocument/hover" => {
+                let text = self.documents.get(&uri).cloned().unwrap_or_default();
+                match params.get("position").and_then(|p| offset(&text, p)) {
+                    Some(at) => Some(self.query(method, &uri, &text, at, params)),
+                    None => {
+                        if let Some(id) = msg.get("id") {
+                            out.push(error_response(id, INVALID_PARAMS, "no such document or position"));
+                        }
+                        return out;
+                    }
+                }
+            }
+            "textDocument/completion" => Some(self.completion()),
+            _ => {
+                // Unknown notifications, such as `initialized`, are ignored.
+                if let Some(id) = msg.get("id").filter(|_| !method.is_empty() && method != "exit") {
+                    out.push(error_response(id, METHOD_NOT_FOUND, &format!("unknown method {}", method)));
+                }
+                return out;
+            }
+        };
+
+        if let (Some(id), Some(result)) = (msg.get("id"), result) {
+            out.insert(0, response(id, result));
+        }
+        out
+    }
+
+    fn capabilities(&self) -> Value {
+        object(vec![
+            ("capabilities", object(vec![
+                ("textDocumentSync", object(vec![
+                    ("openClose", true.into()),
+                    ("change", Value::Number(1)),
+                    ("save", object(vec![("includeText", true.into())]))
+                ])),
+                ("definitionProvider", true.into()),
+                ("referencesProvider", true.into()),
+                ("hoverProvider", true.into()),
+                ("completionProvider", object(vec![]))
+            ])),
+            ("serverInfo", object(vec![("name", "yacc".into()), ("version", env!("CARGO_PKG_VERSION").into())]))
+        ])
+    }
+
+    fn publish(&self, uri: &str) -> Value {
+        let text = self.documents.get(uri).map_or("", |t| t.as_str());
+        let result = self.assembler.assemble(uri, text);
+        let diagnostics = result.diagnostics.iter().filter(|d| d.location.file == uri).map(|d| diagnostic(text, d));
+        notification("textDocument/publishDiagnostics", object(vec![
+            ("uri", uri.into()),
+            ("diagnostics", Value::Array(diagnostics.collect()))
+        ]))
+    }
+
+    // Answers a definition, references or hover request at byte `at`.
+    fn query(&self, method: &str, uri: &str, text: &str, at: usize, params: &Value) -> Value {
+        let result = self.assembler.assemble(uri, text);
+        let occurrences = occurrences(&result);
+        let location = |span: &Span| object(vec![("uri", uri.into()), ("range", range(text, span))]);
+        let symbol = occurrences.iter().find(|(_, span, _)| span.start <= at && at <= span.end).map(|o| o.0);
+
+        match (method, symbol) {
+            ("textDocument/definition", Some(x)) => {
+                occurrences.iter().find(|(y, _, label)| *label && *y == x).map_or(Value::Null, |o| location(&o.1))
+            }
+            ("textDocument/references", Some(x)) => {
+                let declaration = params.get("context")
+                    .and_then(|c| c.get("includeDeclaration"))
+                    .and_then(Value::as_bool)
+                    .unwrap_or(true);
+                let refs = occurrences.iter().filter(|(y, _, label)| *y == x && (declaration || !label));
+                Value::Array(refs.map(|o| location(&o.1)).collect())
+            }
+            ("textDocument/hover", Some(x)) => match result.symbols.get(x) {
+                Some(p) => {
+                    let what = match p.val {
+                        Some(v) => format!("constant {} (`{:06X}`)", if v & 0x00800000 != 0 { v - 0x01000000 } else { v }, v),
+                        None => format!("address `{:06X}`", p.mem_loc)
+                    };
+                    markdown(format!("**{}**: {}, defined on line {}", x, what, p.line_no))
+                }
+                None => markdown(format!("**{}**: undefined symbol", x))
+            },
+            ("textDocument/hover", None) => {
+                let statement = result.statements.iter().find(|s| s.span.start <= at && at <= s.span.end);
+                match statement.and_then(|s| self.describe_mnemonic(s, at)) {
+                    Some(h) => markdown(h),
+                    None => Value::Null
+                }
+            }
+            ("textDocument/references", None) => Value::Array(Vec::new()),
+            _ => Value::Null
+        }
+    }
+
+    // A description of the mnemonic of `s`, if `at` is on it.
+    fn describe_mnemonic(&self, s: &Statement, at: usize) -> Option<String> {
+        let m = s.mnemonic.as_ref().filter(|m| m.span.start <= at && at <= m.span.end)?;
+        let upper = m.name.to_ascii_uppercase();
+        if let Some(&(name, help)) = DIRECTIVE_HELP.iter().find(|d| d.0 == upper) {
+            return Some(format!("**{}** (directive): {}", name, help));
+        }
+        let def = self.assembler.instruction_set().get(&m.name)?;
+        let format = match def.format {
+            3 if m.extended => "4".to_owned(),
+            3 => "3 (4 with `+`)".to_owned(),
+            f => f.to_string()
+        };
+        Some(format!("**{}**: opcode `{:02X}`, format {}, takes {}", def.name, def.opcode, format, def.operands.describe()))
+    }
+
+    fn completion(&self) -> Value {
+        let mut items: Vec<(String, i64, String)> = self.assembler.instruction_set().iter().map(|d| {
+            let format = if d.format == 3 { "3/4".to_owned() } else { d.format.to_string() };
+            (d.name.clone(), 3, format!("opcode {:02X}, format {}", d.opcode, format))
+        }).collect();
+        items.extend(DIRECTIVES.iter().map(|d| {
+            let help = DIRECTIVE_HELP.iter().find(|h| h.0 == *d).map_or("", |h| h.1);
+            (d.to_string(), 14, format!("directive: {}", help))
+        }));
+        items.sort();
+        Value::Array(items.into_iter().map(|(label, kind, detail)| object(vec![
+            ("label", Value::String(label)),
+            ("kind", Value::Number(kind)),
+            ("detail", Value::String(detail))
+        ])).collect())
+    }
+}
+
+#[test]
+fn language_server() {
+    let msg = |s: &str| Value::parse(s).unwrap();
+    let source = "PROG START 0\\nFIRST LDA LEN\\n J FIRST\\nLEN WORD 3\\n STA NOPE\\n END FIRST\\n";
+    let mut input = String::new();
+    for m in [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#.to_owned(),
+        format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///p.asm","text":"{}"}}}}}}"#, source),
+        r#"{"jsonrpc":"2.0","id":2,"#.to_owned(),
+        r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#.to_owned(),
+        r#"{"jsonrpc":"2.0","method":"exit"}"#.to_owned()
+    ] {
+        input += &format!("Content-Length: {}\r\n\r\n{}", m.len(), m);
+    }
+    let mut output = Vec::new();
+    assert!(serve(Assembler::new(), &mut input.as_bytes(), &mut output).unwrap());
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.matches("Content-Length").count(), 4);
+    assert!(output.contains(r#""id":null,"error":{"code":-32700"#));
+    assert!(output.contains(r#""definitionProvider":true"#));
+    assert!(output.contains(concat!(
+        r#""range":{"start":{"line":4,"character":5},"end":{"line":4,"character":9}},"#,
+        r#""severity":1,"code":"E005","source":"yacc","message":"undefined symbol NOPE""#
+    )));
+
+    let mut server = Server::new(Assembler::new());
+    let text = "PROG START 0\nFIRST LDA LEN\n J FIRST\nLEN WORD 3\n END FIRST\n";
+    server.handle(&object(vec![
+        ("method", "textDocument/didOpen".into()),
+        ("params", object(vec![("textDocument", object(vec![("uri", "p.asm".into()), ("text", text.into())]))]))
+    ]));
+    let mut ask = |method: &str, line: i64, character: i64| {
+        let request = format!(
+            r#"{{"id":9,"method":"textDocument/{}","params":{{"textDocument":{{"uri":"p.asm"}},"position":{{"line":{},"character":{}}},"context":{{"includeDeclaration":false}}}}}}"#,
+            method, line, character
+        );
+        server.handle(&msg(&request))[0].get("result").unwrap().to_string()
+    };
+    assert_eq!(ask("definition", 2, 4), r#"{"uri":"p.asm","range":{"start":{"line":1,"character":0},"end":{"line":1,"character":5}}}"#);
+    assert_eq!(ask("references", 1, 2), concat!(
+        r#"[{"uri":"p.asm","range":{"start":{"line":2,"character":3},"end":{"line":2,"character":8}}},"#,
+        r#"{"uri":"p.asm","range":{"start":{"line":4,"character":5},"end":{"line":4,"character":10}}}]"#
+    ));
+    assert!(ask("hover", 1, 11).contains("**LEN**: address `000006`, defined on line 4"));
+    assert!(ask("hover", 1, 7).contains("**LDA**: opcode `00`, format 3 (4 with `+`), takes a memory operand"));
+    assert!(ask("completion", 0, 0).contains(r#"{"label":"RESW","kind":14,"detail":"directive: reserves a number of words"}"#));
+}
@@ -1,162 +1,440 @@
-#![feature(trace_macros)]
-#![recursion_limit="256"]
+extern crate yacc;
 
-#[macro_use]
-extern crate nom;
+mod cli;
 
-use std::fs::File;
-use std::io::BufReader;
-use std::io::BufRead;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net;
+use std::io::{self, Read, Write};
 use std::env;
+use std::process;
 
-mod nomparse;
-mod line;
+use yacc::coverage;
+use yacc::debugger;
+use yacc::isa;
+use yacc::profile;
+use yacc::sim;
+use yacc::snapshot;
+use yacc::trace;
+use yacc::json::ToJson;
+
+use cli::{Command, Emit, Options};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let infile: File;
-    let infilename: String;
-    let outfile: File;
-    let mut option = String::new();
-    match args.len() {
-        1 => {
-            eprintln!("No input files specified, exiting.");
-            println!("Proper syntax-
-yacc.exe <input file name> [-text]
-
-Options:
--text    - Generate object code as ASCII characters.\n");
-            return
-        }
-        2 => {
-            infilename = args[1].clone();
-            infile = File::open(args.clone().pop().unwrap()).unwrap();
-        }
-        3 => {
-            infilename = args[1].clone();
-            option = args.iter().find(|x| x.contains('-')).unwrap().to_string();
-            infile = File::open(infilename.clone()).unwrap();
-        }
-        _ => return
-    }
-    let input = BufReader::new(infile);
-    let mut intfile = String::new();
-    let mut parsed: File = File::create(infilename.clone() + "_out").unwrap();
-    let mut err_vec: Vec<Result<(), String> > = Vec::new();
-    let mut parse_vec: Vec<line::Line> = Vec::new();
-    let mut curr_line = 0;
-    let mut curr_mem_loc: u32 = 0u32;
-    let mut sym_tab: line::Symtab = line::Symtab::new();
-    for line in input.lines() {
-        let res = nomparse::statement(
-            &(line.unwrap() + "\n").as_bytes(),
-            &mut curr_mem_loc,
-            &mut curr_line,
-            &mut sym_tab,
-            &mut err_vec
-        ).unwrap().1;
-
-        parse_vec.push(res);
-
-    }
-
-    if err_vec.iter().map(|x| {
-        match x { 
-            Ok(_) => x, 
-            Err(ref e) => { 
-                eprintln!("{}", e);
-                x 
-            } 
-        }
-    }).any(|x| x.is_err()) {
-        eprintln!("Errors found, exiting.");
-        return
-    }
-
-    //println!("{:#?}", sym_tab);
-
-    //for mut res in &mut parse_vec {
-    //    nomparse::gen_obj_code(res, &mut sym_tab, &mut base);
-    //    //write!(parsed, "{:<4}{:<8X}{:<8}{:<8}{:<8}{:<8}\n", res.line_no, res.mem_loc, res.label.clone().unwrap_or("".to_owned()), res.operation, display_vec(&res.args), display_vec_nums(&res.obj_code));
-    //}
-    //println!("{}", nomparse::gen_records(&mut parse_vec, &mut sym_tab, &mut intfile));
-
-    use std::io::prelude::*;
-    use std::ops::Deref;
-
-    if option == "-text" {
-        write!(parsed, "{}", nomparse::gen_records(&mut parse_vec, &mut sym_tab, &mut intfile));
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match cli::parse(&args) {
+        Ok(Command::Assemble(opts)) => opts,
+        Ok(Command::Lsp(opts)) => {
+            let served = yacc::lsp::serve(assembler(&opts), &mut io::stdin().lock(), &mut io::stdout().lock());
+            match served {
+                Ok(true) => return,
+                Ok(false) => process::exit(cli::EXIT_ERRORS),
+                Err(e) => {
+                    eprintln!("yacc: lsp: {}", e);
+                    process::exit(cli::EXIT_IO)
+                }
+            }
+        }
+        Ok(Command::Fmt(opts)) => {
+            let assembler = assembler(&opts);
+            let status = opts.inputs.iter().map(|input| format(&assembler, &opts, input)).max();
+            process::exit(status.unwrap_or(0))
+        }
+        Ok(Command::Run(opts)) => {
+            let assembler = assembler(&opts);
+            process::exit(run(&assembler, &opts, &opts.inputs[0]))
+        }
+        Ok(Command::Debug(opts)) => {
+            let assembler = assembler(&opts);
+            process::exit(debug(&assembler, &opts, &opts.inputs[0]))
+        }
+        Ok(Command::Gdb(opts)) => {
+            let assembler = assembler(&opts);
+            process::exit(gdb(&assembler, &opts, &opts.inputs[0]))
+        }
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("yacc {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("yacc: {}", e);
+            eprintln!("Try `yacc --help` for more information.");
+            process::exit(cli::EXIT_USAGE)
+        }
+    };
+
+    let assembler = assembler(&opts);
+    let mut status = 0;
+    for input in &opts.inputs {
+        status = status.max(assemble(&assembler, &opts, input));
+    }
+    process::exit(status)
+}
+
+// The assembler configured by `opts`, with the instruction set definitions
+// loaded. Exits if they cannot be read or are invalid.
+fn assembler(opts: &Options) -> yacc::Assembler {
+    let mut isa = isa::Isa::sic_xe();
+    for path in &opts.isa_files {
+        let defs = match fs::read_to_string(path) {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("yacc: {}: {}", path, e);
+                process::exit(cli::EXIT_IO)
+            }
+        };
+        if let Err(errs) = isa.extend_from_str(&defs, path) {
+            for e in errs {
+                eprintln!("{}", e.render(&|_| Some(&*defs)));
+            }
+            eprintln!("yacc: invalid instruction set definition in {}", path);
+            process::exit(cli::EXIT_ERRORS)
+        }
+    }
+
+    let mut assembler = yacc::Assembler::new()
+        .isa(isa)
+        .warnings(opts.warnings.clone())
+        .source_format(opts.format)
+        .listing_layout(opts.layout)
+        .override_defines(opts.override_defines);
+    for (name, value) in &opts.defines {
+        assembler = assembler.define(name, *value);
+    }
+    assembler
+}
+
+// Reads an input, or standard input for `-`.
+fn read(input: &str) -> io::Result<String> {
+    let mut source = String::new();
+    if input == "-" {
+        io::stdin().read_to_string(&mut source)?;
     } else {
-        parsed.write_all(nomparse::vec_gen_records(&mut parse_vec, &mut sym_tab, &mut intfile).deref());
-
-    }
-
-    let mut intermediate = File::create(infilename.clone() + "__intermediate").unwrap();
-    write!(intermediate, "{}", intfile);
-
-    // let mut curr = line::Line::new()
-    // .operation(
-    //     line::source_op::Instruction(
-    //         line::op_struct { 
-    //             opcode: 0x10,
-    //             name: "STX",
-    //             long: true
-    //         }
-    //     )
-    // ).args(
-    //     vec![
-    //         line::arg_struct{ 
-    //             val: line::arg::Label("LENGTH".to_owned()), 
-    //             reg_code: 255, 
-    //             modifier: line::addr_mod::Direct 
-    //         }
-    //     ]
-    // ).line_no(30)
-    // .mem_loc(0x104E)
-    // .format(line::format::Long);
-
-    // let mut sym_tab = line::Symtab::new();
-    // sym_tab.insert("LENGTH".to_owned(), line::Pos {
-    //     line_no: 38,
-    //     mem_loc: 0x0127
-    // });
-    
-    // nomparse::gen_obj_code(&mut curr, &mut sym_tab, 0xFFFFFFFF);
-    // println!("{:#?}", curr);
+        source = fs::read_to_string(input)?;
+    }
+    Ok(source)
+}
 
+// Formats one input, or checks that it is formatted, returning the exit
+// status.
+fn format(assembler: &yacc::Assembler, opts: &Options, input: &str) -> i32 {
+    let source = match read(input) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("yacc: {}: {}", input, e);
+            return cli::EXIT_IO;
+        }
+    };
+    let name = if input == "-" { "<stdin>" } else { input };
 
+    let formatted = match assembler.format(name, &source) {
+        Ok(f) => f,
+        Err(errs) => {
+            for e in errs {
+                eprintln!("{}", e.render(&|_| Some(&*source)));
+            }
+            eprintln!("yacc: {}: not formatted because of errors", name);
+            return cli::EXIT_ERRORS;
+        }
+    };
+    if opts.check {
+        if formatted == source {
+            return 0;
+        }
+        println!("{}", name);
+        return cli::EXIT_ERRORS;
+    }
+    let written = if input == "-" {
+        io::stdout().write_all(formatted.as_bytes())
+    } else if formatted != source {
+        fs::write(input, formatted)
+    } else {
+        Ok(())
+    };
+    if let Err(e) = written {
+        eprintln!("yacc: {}: {}", input, e);
+        return cli::EXIT_IO;
+    }
+    0
+}
+
+// Assembles one input and gets a machine ready to run it: loaded afresh, or
+// restored from a snapshot, and given the device input. Returns the result,
+// the machine and the name of the input, or the exit status on failure.
+fn prepare(assembler: &yacc::Assembler, opts: &Options, input: &str) -> Result<(yacc::AssemblyResult, sim::Machine, String), i32> {
+    let source = read(input).map_err(|e| {
+        eprintln!("yacc: {}: {}", input, e);
+        cli::EXIT_IO
+    })?;
+    let name = if input == "-" { "<stdin>" } else { input };
+    let result = assembler.assemble(name, &source);
+    eprint!("{}", result.render_diagnostics());
+    if !result.is_ok() {
+        eprintln!("yacc: {}: errors found, not running", name);
+        return Err(cli::EXIT_ERRORS);
+    }
+
+    let mut machine = match opts.run.restore {
+        Some(ref path) => {
+            let text = fs::read_to_string(path).map_err(|e| {
+                eprintln!("yacc: {}: {}", path, e);
+                cli::EXIT_IO
+            })?;
+            snapshot::restore(&text).map_err(|e| {
+                eprintln!("yacc: {}: {}", path, e);
+                cli::EXIT_ERRORS
+            })?
+        }
+        None => {
+            let at = opts.load_address.unwrap_or(result.program.start);
+            let mut machine = sim::Machine::new();
+            if let Err(e) = machine.load(&result.program, at) {
+                eprintln!("yacc: {}: cannot load the program at {:06X}: {}", name, at, e);
+                return Err(cli::EXIT_ERRORS);
+            }
+            machine
+        }
+    };
+    for (dev, path) in &opts.run.inputs {
+        let bytes = fs::read(path).map_err(|e| {
+            eprintln!("yacc: {}: {}", path, e);
+            cli::EXIT_IO
+        })?;
+        machine.device(*dev).input.extend(bytes);
+    }
+    Ok((result, machine, name.to_owned()))
+}
+
+// Writes device output that was not there before the run to the paths
+// given for it, and if `stdout` is set, the rest to standard output.
+// Returns the exit status.
+fn write_devices(machine: &sim::Machine, before: &BTreeMap<u8, usize>, opts: &Options, stdout: bool) -> i32 {
+    let mut status = 0;
+    for (dev, device) in &machine.devices {
+        let output = &device.output[before.get(dev).cloned().unwrap_or(0)..];
+        let path = opts.run.outputs.iter().find(|o| o.0 == *dev).map_or("-", |o| o.1.as_str());
+        if path == "-" && (output.is_empty() || !stdout) {
+            continue;
+        }
+        let written = if path == "-" { io::stdout().write_all(output) } else { fs::write(path, output) };
+        if let Err(e) = written {
+            eprintln!("yacc: {}: {}", path, e);
+            status = cli::EXIT_IO;
+        }
+    }
+    status
+}
+
+// Assembles one input and runs it under the debugger, returning the exit
+// status.
+fn debug(assembler: &yacc::Assembler, opts: &Options, input: &str) -> i32 {
+    let (result, machine, _) = match prepare(assembler, opts, input) {
+        Ok(p) => p,
+        Err(status) => return status
+    };
+    let before: BTreeMap<u8, usize> = machine.devices.iter().map(|(n, d)| (*n, d.output.len())).collect();
+    let at = opts.load_address.unwrap_or(result.program.start);
+    let labels = trace::labels(&result.symbols, at.wrapping_sub(result.program.start));
+    let mut debugger = debugger::Debugger::new(machine, labels).max_steps(opts.run.max_steps).history(opts.run.history);
+    if let Err(e) = debugger::repl(&mut debugger, &mut io::stdin().lock(), &mut io::stdout().lock()) {
+        eprintln!("yacc: debug: {}", e);
+        return cli::EXIT_IO;
+    }
+    // The debugger has already shown the output not sent to a file.
+    write_devices(&debugger.machine, &before, opts, false)
+}
+
+// Assembles one input and lets GDB control the run, returning the exit
+// status.
+fn gdb(assembler: &yacc::Assembler, opts: &Options, input: &str) -> i32 {
+    let (_, machine, _) = match prepare(assembler, opts, input) {
+        Ok(p) => p,
+        Err(status) => return status
+    };
+    let before: BTreeMap<u8, usize> = machine.devices.iter().map(|(n, d)| (*n, d.output.len())).collect();
+    let mut stub = yacc::gdb::Stub::new(machine).max_steps(opts.run.max_steps);
+    let served = match opts.run.port {
+        Some(port) => net::TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+            eprintln!("yacc: waiting for GDB on 127.0.0.1:{}", port);
+            let (stream, _) = listener.accept()?;
+            yacc::gdb::serve(&mut stub, &mut stream.try_clone()?, &mut &stream)
+        }),
+        None => yacc::gdb::serve(&mut stub, &mut io::stdin().lock(), &mut io::stdout().lock())
+    };
+    if let Err(e) = served {
+        eprintln!("yacc: gdb: {}", e);
+        return cli::EXIT_IO;
+    }
+    // GDB has shown the output not sent to a file.
+    write_devices(&stub.machine, &before, opts, false)
+}
+
+// Assembles one input and runs it in the simulator, returning the exit
+// status.
+fn run(assembler: &yacc::Assembler, opts: &Options, input: &str) -> i32 {
+    let (result, mut machine, name) = match prepare(assembler, opts, input) {
+        Ok(p) => p,
+        Err(status) => return status
+    };
+    let before: BTreeMap<u8, usize> = machine.devices.iter().map(|(n, d)| (*n, d.output.len())).collect();
+    let program = &result.program;
+    let at = opts.load_address.unwrap_or(program.start);
+    let offset = at.wrapping_sub(program.start);
+    let labels = trace::labels(&result.symbols, offset);
+    let mut tracer = match opts.run.trace {
+        Some(format) => {
+            let mut t = trace::Tracer::new(format, labels.clone());
+            if let Some((from, to)) = opts.run.trace_range {
+                t = t.range(from, to);
+            }
+            for sub in &opts.run.trace_subroutines {
+                t = match t.subroutine(sub) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        eprintln!("yacc: {}: {}", name, e);
+                        return cli::EXIT_USAGE;
+                    }
+                };
+            }
+            Some(t)
+        }
+        None => None
+    };
+    let mut trace_out: Box<dyn Write> = match opts.run.trace_path {
+        Some(ref path) => match fs::File::create(path) {
+            Ok(f) => Box::new(io::BufWriter::new(f)),
+            Err(e) => {
+                eprintln!("yacc: {}: {}", path, e);
+                return cli::EXIT_IO;
+            }
+        },
+        None => Box::new(io::stderr())
+    };
+
+    let mut profile = opts.run.profile.as_ref().map(|_| profile::Profile::new());
+    let mut coverage = opts.run.coverage.as_ref().map(|_| coverage::Coverage::new());
+    let mut status = 0;
+    let stop = machine.run(opts.run.max_steps, |_, step| {
+        if let Some(p) = profile.as_mut() {
+            p.record(step);
+        }
+        if let Some(c) = coverage.as_mut() {
+            c.record(step);
+        }
+        if let Some(line) = tracer.as_mut().and_then(|t| t.record(step)) {
+            if writeln!(trace_out, "{}", line).is_err() {
+                status = cli::EXIT_IO;
+            }
+        }
+    });
+    if trace_out.flush().is_err() {
+        status = cli::EXIT_IO;
+    }
+    if let (Some(p), Some(path)) = (profile, &opts.run.profile) {
+        let report = format!("{}\n{}", p.annotate(&result, offset), p.summary(&labels));
+        let written = if path == "-" { io::stdout().write_all(report.as_bytes()) } else { fs::write(path, report) };
+        if let Err(e) = written {
+            eprintln!("yacc: {}: {}", path, e);
+            status = cli::EXIT_IO;
+        }
+    }
+    if let (Some(c), Some(path)) = (coverage, &opts.run.coverage) {
+        let report = c.report(&result, offset);
+        eprint!("{}", report.summary());
+        let written = if path == "-" { io::stdout().write_all(report.lcov().as_bytes()) } else { fs::write(path, report.lcov()) };
+        if let Err(e) = written {
+            eprintln!("yacc: {}: {}", path, e);
+            status = cli::EXIT_IO;
+        }
+    }
+
+    if let Some(ref path) = opts.run.snapshot {
+        if let Err(e) = fs::write(path, snapshot::save(&machine)) {
+            eprintln!("yacc: {}: {}", path, e);
+            status = cli::EXIT_IO;
+        }
+    }
+    status = status.max(write_devices(&machine, &before, opts, true));
+    match stop {
+        sim::Stop::Exited | sim::Stop::Halted => status,
+        _ => {
+            eprintln!("yacc: {}: {}", name, stop);
+            status.max(cli::EXIT_ERRORS)
+        }
+    }
+}
+
+// Assembles one input and writes the outputs selected for it, returning the
+// exit status.
+fn assemble(assembler: &yacc::Assembler, opts: &Options, input: &str) -> i32 {
+    let source = match read(input) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("yacc: {}: {}", input, e);
+            return cli::EXIT_IO;
+        }
+    };
+    let name = if input == "-" { "<stdin>" } else { input };
+
+    let result = assembler.assemble(name, &source);
+    eprint!("{}", result.render_diagnostics());
+    let mut status = 0;
+    if !result.is_ok() {
+        eprintln!("yacc: {}: errors found, no object program written", name);
+        status = cli::EXIT_ERRORS;
+    }
+
+    let at = opts.load_address.unwrap_or(result.program.start);
+    for (emit, path) in opts.outputs(input) {
+        if !result.is_ok() && !emit.despite_errors() {
+            continue;
+        }
+        let bytes = match emit {
+            Emit::Binary => result.program.to_binary(),
+            Emit::Text => result.program.to_text().into_bytes(),
+            Emit::IntelHex => yacc::image::intel_hex(&result.program, at).into_bytes(),
+            Emit::Srec => yacc::image::srec(&result.program, at).into_bytes(),
+            Emit::Raw => match opts.raw.image(&result.program, at) {
+                Ok(image) => image,
+                Err(e) => {
+                    eprintln!("yacc: {}: {}", name, e);
+                    status = status.max(cli::EXIT_ERRORS);
+                    continue;
+                }
+            },
+            Emit::Listing => result.listing.clone().into_bytes(),
+            Emit::Json => (result.to_json().to_string() + "\n").into_bytes(),
+            Emit::Xref => yacc::xref::render(&yacc::xref::build(&result.lines, &result.symbols)).into_bytes()
+        };
+        let written = if path == "-" { io::stdout().write_all(&bytes) } else { fs::write(&path, bytes) };
+        if let Err(e) = written {
+            eprintln!("yacc: {}: {}", path, e);
+            status = cli::EXIT_IO;
+        }
+    }
+    status
 }
 
 #[test]
 fn tst() {
-    let mut curr = line::Line::new()
+    let _curr = yacc::line::Line::new()
     .operation(
-        line::source_op::Instruction(
-            line::op_struct { 
-                opcode: 0x10,
-                name: "STX",
-                long: true
-            }
+        yacc::line::source_op::Instruction(
+            yacc::line::op_struct::new(0x10, "STX").shape(3, isa::Operands::Memory).long(true)
         )
     ).args(
         vec![
-            line::arg_struct{ 
-                val: line::arg::Label("LENGTH".to_owned()), 
+            yacc::line::arg_struct{ 
+                val: yacc::line::arg::Label("LENGTH".to_owned()), 
                 reg_code: 255, 
-                modifier: line::addr_mod::Direct 
+                modifier: yacc::line::addr_mod::Direct 
             }
         ]
     ).line_no(30)
     .mem_loc(0x104E)
-    .format(line::format::Long);
-
-    // let mut sym_tab = line::Symtab::new();
-    // sym_tab.insert("LENGTH".to_owned(), line::Pos {
-    //     line_no: 38,
-    //     mem_loc: 0x0127,
-
-    // });
-    // let mut d = 0xFFFFFFFFu32;
-    // nomparse::gen_obj_code(&mut curr, &mut sym_tab, &mut d);
-    // println!("{:#?}", curr);
+    .format(yacc::line::format::Long);
 }
\ No newline at end of file
@@ -0,0 +1,733 @@
+use std::ops::Range;
+
+use super::line::*;
+use super::isa::{Isa, Operands, DIRECTIVES};
+use super::diagnostic::{Diagnostic, Code};
+
+/// A range of bytes in the source text.
+pub type Span = Range<usize>;
+
+/// A label or comment and where it appears.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Name {
+    pub text: String,
+    pub span: Span
+}
+
+/// The operation field: a mnemonic, with or without the `+` selecting format 4.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Mnemonic {
+    /// The mnemonic as written, without the `+`.
+    pub name: String,
+    pub extended: bool,
+    /// Includes the `+`, if any.
+    pub span: Span
+}
+
+/// A single operand: an addressing mode prefix and an expression.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Operand {
+    /// `#`, `@` and `=` select immediate, indirect and literal operands.
+    pub mode: addr_mod,
+    pub expr: Expr,
+    /// Includes the mode prefix, if any.
+    pub span: Span
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ExprKind {
+    /// A decimal number, a hexadecimal number ending in `h`, or `X'..'`.
+    Number(i32),
+    /// A string of characters, `C'..'`.
+    Chars(String),
+    Symbol(String),
+    /// `*`, the address of the current line.
+    Here,
+    Neg(Box<Expr>),
+    /// `+`, `-`, `*` or `/` applied to two expressions.
+    Binary(u8, Box<Expr>, Box<Expr>)
+}
+
+/// One line of source, parsed but not checked against the rules of the
+/// assembler. Every span is a range of bytes in the whole source.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Statement {
+    pub line_no: u32,
+    /// The whole line, without its line ending.
+    pub span: Span,
+    pub label: Option<Name>,
+    pub mnemonic: Option<Mnemonic>,
+    pub operands: Vec<Operand>,
+    /// The text following the `.` of a comment.
+    pub comment: Option<Name>
+}
+
+impl Statement {
+    /// True for lines holding nothing but a comment, or nothing at all.
+    pub fn is_blank(&self) -> bool {
+        self.label.is_none() && self.mnemonic.is_none() && self.operands.is_empty()
+    }
+}
+
+/// How the fields of a source line are laid out.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SourceFormat {
+    /// Fields are separated by whitespace, and comments start with `.`.
+    Free,
+    /// Fields are found by column, as on punched cards: the label in columns
+    /// 1-8, `+` in column 9, the mnemonic in 10-15, `#`, `@` or `=` in 16 and
+    /// the operands from 17. The first blank after the operands starts the
+    /// comment, and lines with `.` in column 1 are comments.
+    Fixed,
+    /// `Fixed` if every line fits the fixed layout, `Free` otherwise.
+    Auto
+}
+
+// Columns of the fixed format, counting from 0.
+const LABEL_END: usize = 8;
+const OPCODE_START: usize = 9;
+const OPCODE_END: usize = 15;
+const OPERAND_START: usize = 16;
+const OPERAND_END: usize = 35;
+
+// True if a non-comment line fits the fixed layout.
+fn fits_fixed(line: &str) -> bool {
+    let b = line.as_bytes();
+    let at = |i: usize| b.get(i).cloned().unwrap_or(b' ');
+    let field = |from: usize, to: usize| &b[from.min(b.len())..to.min(b.len())];
+    let word_then_blanks = |f: &[u8]| {
+        let n = f.iter().take_while(|c| is_ident(**c)).count();
+        f[n..].iter().all(|c| *c == b' ')
+    };
+    let label = field(0, LABEL_END);
+    (at(0) != b' ' || label.iter().all(|c| *c == b' '))
+        && word_then_blanks(label)
+        && matches!(at(LABEL_END), b' ' | b'+')
+        && is_ident_start(at(OPCODE_START))
+        && word_then_blanks(field(OPCODE_START, OPCODE_END))
+        && matches!(at(OPCODE_END), b' ' | b'#' | b'@' | b'=')
+        && (at(OPERAND_START) != b' ' || field(OPERAND_START, OPERAND_END).iter().all(|c| *c == b' '))
+}
+
+/// Decides whether `source` is in the fixed or the free format: it is fixed
+/// if it has no tabs, and every line that is not blank or a comment fits the
+/// fixed layout.
+pub fn detect_format(source: &str) -> SourceFormat {
+    let mut code = 0;
+    for line in source.lines() {
+        if line.contains('\t') {
+            return SourceFormat::Free;
+        }
+        if line.trim().is_empty() || line.starts_with('.') {
+            continue;
+        }
+        if !fits_fixed(line) {
+            return SourceFormat::Free;
+        }
+        code += 1;
+    }
+    if code > 0 { SourceFormat::Fixed } else { SourceFormat::Free }
+}
+
+/// Parses a whole source into one `Statement` per line.
+///
+/// Parsing has no side effects and never gives up: a line with a syntax
+/// error yields a statement holding whatever came before the error, and the
+/// error is returned along with the index of that statement. `isa` is used
+/// to tell labels from mnemonics.
+pub fn parse(source: &str, isa: &Isa, format: SourceFormat) -> (Vec<Statement>, Vec<(usize, Diagnostic)>) {
+    let fixed = match format {
+        SourceFormat::Auto => detect_format(source) == SourceFormat::Fixed,
+        f => f == SourceFormat::Fixed
+    };
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    let mut offset = 0;
+    for (k, raw) in source.split_inclusive('\n').enumerate() {
+        let text = raw.trim_end_matches('\n').trim_end_matches('\r');
+        let mut p = LineParser { text, base: offset, pos: 0, isa, fixed };
+        let mut stmt = Statement {
+            line_no: k as u32 + 1,
+            span: offset..offset + text.len(),
+            label: None,
+            mnemonic: None,
+            operands: Vec::new(),
+            comment: None
+        };
+        let res = if fixed { p.fixed_statement(&mut stmt) } else { p.statement(&mut stmt) };
+        if let Err((cols, msg)) = res {
+            errors.push((k, Diagnostic::error(Code::Syntax, &msg).line(stmt.line_no).cols(cols)));
+        }
+        statements.push(stmt);
+        offset += raw.len();
+    }
+    (statements, errors)
+}
+
+// A syntax error: the columns it covers within the line, and a message.
+type SyntaxError = (Range<usize>, String);
+
+struct LineParser<'a> {
+    text: &'a str,
+    // The offset of the line within the source.
+    base: usize,
+    pos: usize,
+    isa: &'a Isa,
+    // Operands of fixed format lines end at the first blank.
+    fixed: bool
+}
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+impl<'a> LineParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<u8> {
+        self.text.as_bytes().get(self.pos + n).cloned()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.text.len()
+    }
+
+    fn skip_space(&mut self) -> bool {
+        let start = self.pos;
+        while let Some(b' ') | Some(b'\t') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    // Skips whitespace between the parts of an expression, which the fixed
+    // format does not allow.
+    fn skip_inner_space(&mut self) {
+        if !self.fixed {
+            self.skip_space();
+        }
+    }
+
+    fn span(&self, start: usize) -> Span {
+        self.base + start..self.base + self.pos
+    }
+
+    fn error<T>(&self, start: usize, msg: &str) -> Result<T, SyntaxError> {
+        let end = self.pos.max(start + 1).min(self.text.len().max(start + 1));
+        Err((start..end, msg.to_owned()))
+    }
+
+    // The text of the next run of characters up to whitespace or a comment.
+    fn next_word(&self) -> &'a str {
+        self.next_word_at(self.pos)
+    }
+
+    fn next_word_at(&self, pos: usize) -> &'a str {
+        let rest = self.text.get(pos..).unwrap_or("");
+        &rest[..rest.find([' ', '\t', '.']).unwrap_or(rest.len())]
+    }
+
+    fn ident(&mut self) -> Option<Name> {
+        let start = self.pos;
+        if !self.peek().is_some_and(is_ident_start) {
+            return None;
+        }
+        while self.peek().is_some_and(is_ident) {
+            self.pos += 1;
+        }
+        Some(Name { text: self.text[start..self.pos].to_owned(), span: self.span(start) })
+    }
+
+    fn is_mnemonic(&self, word: &str) -> bool {
+        word.starts_with('+') || DIRECTIVES.contains(&&*word.to_ascii_uppercase()) || self.isa.get(word).is_some()
+    }
+
+    fn statement(&mut self, stmt: &mut Statement) -> Result<(), SyntaxError> {
+        let in_first_column = !self.skip_space();
+        if self.at_end() || self.peek() == Some(b'.') {
+            return self.comment(stmt);
+        }
+
+        // In the first column, the first word is a label unless it is a
+        // mnemonic followed by something other than a mnemonic, so `SUB LDA X`
+        // defines SUB. Further in, it is a label only if a mnemonic follows,
+        // so that a misspelt mnemonic is reported as one.
+        let first = self.next_word();
+        let after = self.text[self.pos + first.len()..].trim_start_matches([' ', '\t']);
+        let second = &after[..after.find([' ', '\t', '.']).unwrap_or(after.len())];
+        let label = if in_first_column {
+            !self.is_mnemonic(first) || self.is_mnemonic(second)
+        } else {
+            !self.is_mnemonic(first) && self.is_mnemonic(second)
+        };
+        if label {
+            let start = self.pos;
+            match self.ident() {
+                Some(l) if self.at_end() || self.skip_space() || self.peek() == Some(b'.') => stmt.label = Some(l),
+                _ => {
+                    self.pos = start + first.len();
+                    return self.error(start, &format!("`{}` is not a valid label", first));
+                }
+            }
+            if self.at_end() || self.peek() == Some(b'.') {
+                return self.comment(stmt);
+            }
+        }
+
+        let start = self.pos;
+        let extended = self.peek() == Some(b'+');
+        if extended {
+            self.pos += 1;
+        }
+        let name = match self.ident() {
+            Some(n) => n.text,
+            None => {
+                self.pos = start + self.next_word().len();
+                return self.error(start, "expected a mnemonic");
+            }
+        };
+        if extended && self.isa.get(&name).is_none_or(|d| d.format != 3) {
+            return self.error(start, "`+` can only be used with format 3 instructions");
+        }
+        stmt.mnemonic = Some(Mnemonic { name, extended, span: self.span(start) });
+
+        let spaced = self.skip_space();
+        if self.at_end() || self.peek() == Some(b'.') {
+            return self.comment(stmt);
+        }
+        if !spaced {
+            let start = self.pos;
+            self.pos += self.next_word().len();
+            return self.error(start, "expected whitespace after the mnemonic");
+        }
+
+        self.operands(stmt)?;
+        if self.at_end() || self.peek() == Some(b'.') {
+            return self.comment(stmt);
+        }
+        let start = self.pos;
+        self.pos = self.text.len();
+        self.error(start, "unexpected text after the operands; comments start with `.`")
+    }
+
+    // A comma-separated list of operands. Whitespace may follow the commas in
+    // both formats.
+    fn operands(&mut self, stmt: &mut Statement) -> Result<(), SyntaxError> {
+        loop {
+            let op = self.operand()?;
+            stmt.operands.push(op);
+            self.skip_inner_space();
+            if self.peek() != Some(b',') {
+                return Ok(());
+            }
+            self.pos += 1;
+            self.skip_space();
+        }
+    }
+
+    // The text from `start` to the end of the line, up to `to`.
+    fn field(&self, start: usize, to: usize) -> &'a str {
+        let end = to.min(self.text.len());
+        self.text.get(start.min(end)..end).unwrap_or("")
+    }
+
+    fn fixed_statement(&mut self, stmt: &mut Statement) -> Result<(), SyntaxError> {
+        if self.field(0, self.text.len()).trim().is_empty() || self.peek() == Some(b'.') {
+            return self.comment(stmt);
+        }
+        // Columns are counted in bytes, so only ASCII can go before the
+        // operand field.
+        if let Some((start, c)) = self.text.char_indices().take_while(|(k, _)| *k < OPERAND_START).find(|(_, c)| !c.is_ascii()) {
+            self.pos = start + c.len_utf8();
+            return self.error(start, "columns 1-16 can only hold ASCII characters");
+        }
+
+        if !self.field(0, LABEL_END).trim().is_empty() {
+            let l = self.ident();
+            if l.is_none() || self.pos > LABEL_END || !self.field(self.pos, LABEL_END).trim().is_empty() {
+                self.pos = LABEL_END.min(self.text.len());
+                return self.error(0, "labels must be identifiers in columns 1-8");
+            }
+            stmt.label = l;
+        }
+
+        self.pos = LABEL_END;
+        let extended = match self.peek() {
+            None => return Ok(()),
+            Some(b'+') => true,
+            Some(b' ') => false,
+            Some(_) => {
+                self.pos += 1;
+                return self.error(LABEL_END, "column 9 must be blank or `+`");
+            }
+        };
+        if self.field(OPCODE_START, self.text.len()).trim().is_empty() {
+            return if extended { self.error(LABEL_END, "expected a mnemonic in columns 10-15") } else { Ok(()) };
+        }
+        self.pos = OPCODE_START;
+        let name = match self.ident() {
+            Some(n) if self.pos <= OPCODE_END => n.text,
+            _ => {
+                self.pos = OPCODE_END.min(self.text.len());
+                return self.error(OPCODE_START, "expected a mnemonic in columns 10-15");
+            }
+        };
+        if !self.field(self.pos, OPCODE_END).trim().is_empty() {
+            let start = self.pos;
+            self.pos = OPCODE_END;
+            return self.error(start, "unexpected text after the mnemonic");
+        }
+        let start = if extended { LABEL_END } else { OPCODE_START };
+        if extended && self.isa.get(&name).is_none_or(|d| d.format != 3) {
+            return self.error(start, "`+` can only be used with format 3 instructions");
+        }
+        // Anything in the operand field of an instruction that takes no
+        // operands is a comment.
+        let takes_operands = self.isa.get(&name).is_none_or(|d| d.operands != Operands::None)
+            && !name.eq_ignore_ascii_case("NOBASE");
+        stmt.mnemonic = Some(Mnemonic { name, extended, span: self.span(start) });
+
+        self.pos = OPCODE_END;
+        if self.peek() == Some(b' ') {
+            self.pos += 1;
+        }
+        // Text in the operand field that does not start in column 17 would
+        // otherwise be silently taken as a comment.
+        let rest = self.field(self.pos, OPERAND_END);
+        if takes_operands && self.peek() == Some(b' ') && !rest.trim().is_empty() {
+            let start = self.pos + rest.len() - rest.trim_start().len();
+            self.pos = start + self.next_word_at(start).len();
+            return self.error(start, "operands must start in column 17");
+        }
+        if takes_operands && !self.at_end() && self.peek() != Some(b' ') {
+            self.operands(stmt)?;
+            if !self.at_end() && self.peek() != Some(b' ') {
+                let start = self.pos;
+                self.pos += self.next_word().len().max(1);
+                return self.error(start, "unexpected text in the operand field");
+            }
+        }
+
+        self.skip_space();
+        if !self.at_end() {
+            let start = if self.peek() == Some(b'.') { self.pos + 1 } else { self.pos };
+            self.pos = self.text.len();
+            stmt.comment = Some(Name { text: self.text[start..].to_owned(), span: self.span(start) });
+        }
+        Ok(())
+    }
+
+    fn comment(&mut self, stmt: &mut Statement) -> Result<(), SyntaxError> {
+        if self.peek() == Some(b'.') {
+            let start = self.pos + 1;
+            self.pos = self.text.len();
+            stmt.comment = Some(Name { text: self.text[start..].to_owned(), span: self.span(start) });
+        }
+        Ok(())
+    }
+
+    fn operand(&mut self) -> Result<Operand, SyntaxError> {
+        let start = self.pos;
+        let mode = match self.peek() {
+            Some(b'#') => addr_mod::Immediate,
+            Some(b'@') => addr_mod::Indirect,
+            Some(b'=') => addr_mod::Literal,
+            _ => addr_mod::Direct
+        };
+        if mode != addr_mod::Direct {
+            self.pos += 1;
+        }
+        let expr = self.expr()?;
+        Ok(Operand { mode, expr, span: self.span(start) })
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, SyntaxError> {
+        self.binary(b"+-", LineParser::term)
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Expr, SyntaxError> {
+        self.binary(b"*/", LineParser::unary)
+    }
+
+    fn binary(&mut self, ops: &[u8], next: fn(&mut Self) -> Result<Expr, SyntaxError>) -> Result<Expr, SyntaxError> {
+        let start = self.pos;
+        let mut lhs = next(self)?;
+        loop {
+            let save = self.pos;
+            self.skip_inner_space();
+            let op = match self.peek() {
+                Some(c) if ops.contains(&c) => c,
+                _ => {
+                    self.pos = save;
+                    return Ok(lhs);
+                }
+            };
+            self.pos += 1;
+            self.skip_inner_space();
+            let rhs = next(self)?;
+            lhs = Expr { kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span: self.span(start) };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, SyntaxError> {
+        let start = self.pos;
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                let e = self.unary()?;
+                Ok(Expr { kind: ExprKind::Neg(Box::new(e)), span: self.span(start) })
+            }
+            Some(b'+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, SyntaxError> {
+        let start = self.pos;
+        let kind = match self.peek() {
+            Some(b'*') => {
+                self.pos += 1;
+                ExprKind::Here
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                self.skip_inner_space();
+                let e = self.expr()?;
+                self.skip_inner_space();
+                if self.peek() != Some(b')') {
+                    return self.error(self.pos, "expected `)`");
+                }
+                self.pos += 1;
+                return Ok(Expr { kind: e.kind, span: self.span(start) });
+            }
+            Some(c) if c.is_ascii_digit() => ExprKind::Number(self.number()?),
+            Some(c) if (c == b'X' || c == b'x') && self.peek_at(1) == Some(b'\'') => {
+                let digits = self.quoted()?;
+                if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+                    return self.error(start, "X'..' must contain hexadecimal digits");
+                }
+                ExprKind::Number(self.in_range(start, i64::from_str_radix(&digits, 16).ok())?)
+            }
+            Some(c) if (c == b'C' || c == b'c') && self.peek_at(1) == Some(b'\'') => ExprKind::Chars(self.quoted()?),
+            Some(c) if is_ident_start(c) => ExprKind::Symbol(self.ident().unwrap().text),
+            _ => {
+                self.pos += self.next_word().len().max(1).min(self.text.len() - self.pos);
+                return self.error(start, "expected an operand");
+            }
+        };
+        Ok(Expr { kind, span: self.span(start) })
+    }
+
+    // The text between the quotes of `X'..'` or `C'..'`.
+    fn quoted(&mut self) -> Result<String, SyntaxError> {
+        let start = self.pos;
+        self.pos += 2;
+        match self.text[self.pos..].find('\'') {
+            Some(n) => {
+                let s = self.text[self.pos..self.pos + n].to_owned();
+                self.pos += n + 1;
+                Ok(s)
+            }
+            None => {
+                self.pos = self.text.len();
+                self.error(start, "missing closing `'`")
+            }
+        }
+    }
+
+    // Decimal digits, or hexadecimal ones followed by `h`.
+    fn number(&mut self) -> Result<i32, SyntaxError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            self.pos += 1;
+        }
+        let digits = &self.text[start..self.pos];
+        let value = if let Some(b'h') | Some(b'H') = self.peek() {
+            self.pos += 1;
+            i64::from_str_radix(digits, 16).ok()
+        } else if digits.bytes().all(|c| c.is_ascii_digit()) {
+            digits.parse::<i64>().ok()
+        } else {
+            return self.error(start, &format!("`{}` is not a number; hexadecimal numbers end in `h`", digits));
+        };
+        if self.peek().is_some_and(is_ident) {
+            self.pos += self.next_word().len();
+            return self.error(start, "invalid number");
+        }
+        self.in_range(start, value)
+    }
+
+    fn in_range(&self, start: usize, value: Option<i64>) -> Result<i32, SyntaxError> {
+        match value {
+            Some(v) if v <= 0x00FFFFFF => Ok(v as i32),
+            _ => self.error(start, "number does not fit in 24 bits")
+        }
+    }
+}
+
+fn register_code(name: &str) -> u8 {
+    match name.to_ascii_uppercase().as_str() {
+        "A"  => 0x00,
+        "X"  => 0x01,
+        "L"  => 0x02,
+        "B"  => 0x03,
+        "S"  => 0x04,
+        "T"  => 0x05,
+        "F"  => 0x06,
+        "PC" => 0x08,
+        "SW" => 0x09,
+        _    => 0xFF
+    }
+}
+
+/// Converts an expression to the operand representation used by the passes.
+pub fn lower_expr(e: &Expr) -> arg {
+    match e.kind {
+        ExprKind::Number(n) => arg::IntLit(n & 0x00FFFFFF),
+        ExprKind::Chars(ref s) => arg::StrLit(s.clone()),
+        ExprKind::Symbol(ref s) => arg::Label(s.clone()),
+        ExprKind::Here => arg::Label("*".to_owned()),
+        ExprKind::Neg(ref x) => match x.kind {
+            ExprKind::Number(n) => arg::IntLit(-n & 0x00FFFFFF),
+            _ => arg::Expr(Box::new(expr_struct { lhs: arg::IntLit(0), op: b'-', rhs: lower_expr(x) }))
+        },
+        ExprKind::Binary(op, ref l, ref r) => arg::Expr(Box::new(expr_struct { lhs: lower_expr(l), op, rhs: lower_expr(r) }))
+    }
+}
+
+/// Converts a statement to the `Line` the passes work on.
+pub fn lower(s: &Statement, isa: &Isa) -> Line {
+    let comment = s.comment.as_ref().map_or("", |c| &c.text);
+    if s.is_blank() {
+        return Line::new().format(format::Comment).comment(comment).line_no(s.line_no);
+    }
+    let operation = match s.mnemonic {
+        None => source_op::Error,
+        Some(ref m) => {
+            let upper = m.name.to_ascii_uppercase();
+            match DIRECTIVES.iter().position(|d| *d == upper) {
+                Some(_) if m.extended => source_op::Error,
+                Some(n) => source_op::Directive(op_struct::new(n as u8 + 1, &upper)),
+                None => match isa.get(&m.name) {
+                    Some(def) => source_op::Instruction(def.to_op()).instr_long_mode(m.extended),
+                    None => source_op::Error
+                }
+            }
+        }
+    };
+    let args = s.operands.iter().map(|o| arg_struct {
+        reg_code: match o.expr.kind {
+            ExprKind::Symbol(ref x) => register_code(x),
+            _ => 0xFF
+        },
+        val: lower_expr(&o.expr),
+        modifier: o.mode.clone()
+    }).collect();
+    Line::new()
+        .label(s.label.as_ref().map(|l| l.text.clone()))
+        .operation(operation)
+        .args(args)
+        .comment(comment)
+        .line_no(s.line_no)
+}
+
+#[test]
+fn parse_statements() {
+    let isa = Isa::sic_xe();
+    let src = "COPY START 0\n\tLDA  #BUF-2 , X . load\nSUB LDA *\n. only a comment\n LDA 1FFh junk\n";
+    let (stmts, errors) = parse(src, &isa, SourceFormat::Auto);
+    assert_eq!(stmts.len(), 5);
+
+    let s = &stmts[1];
+    assert!(s.label.is_none());
+    assert_eq!(&src[s.mnemonic.as_ref().unwrap().span.clone()], "LDA");
+    assert_eq!(&src[s.operands[0].span.clone()], "#BUF-2");
+    assert_eq!(s.operands[0].mode, addr_mod::Immediate);
+    match s.operands[0].expr.kind {
+        ExprKind::Binary(b'-', ref l, ref r) => {
+            assert_eq!(l.kind, ExprKind::Symbol("BUF".to_owned()));
+            assert_eq!(r.kind, ExprKind::Number(2));
+        }
+        ref k => panic!("unexpected {:?}", k)
+    }
+    assert_eq!(&src[s.operands[1].span.clone()], "X");
+    assert_eq!(s.comment.as_ref().unwrap().text, " load");
+
+    assert_eq!(stmts[2].label.as_ref().unwrap().text, "SUB");
+    assert_eq!(stmts[2].operands[0].expr.kind, ExprKind::Here);
+    assert!(stmts[3].is_blank());
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, 4);
+    assert_eq!(errors[0].1.location.cols, 10..14);
+    assert_eq!(stmts[4].operands[0].expr.kind, ExprKind::Number(0x1FF));
+}
+
+#[test]
+fn expression_overflow() {
+    let src = "P START 0\nX EQU 8000h*10000h/-1\nY EQU 1000h*1000h/100h\nW WORD 8000h*10000h/-1\nZ EQU -800000h\nV EQU 7FFFFFh+7FFFFFh\n END P\n";
+    let result = super::Assembler::new().assemble("e.asm", src);
+    let errors: Vec<String> = result.diagnostics.iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect();
+    assert_eq!(errors, vec![
+        "e.asm:2:7: error[E008]: the value of the expression does not fit in 24 bits",
+        "e.asm:3:7: error[E008]: the value of the expression does not fit in 24 bits",
+        "e.asm:4:8: error[E008]: the value of the expression does not fit in 24 bits"
+    ]);
+    assert_eq!(result.symbols["Z"].val, Some(0x800000));
+    assert_eq!(result.symbols["V"].val, Some(0xFFFFFE));
+}
+
+#[test]
+fn parse_fixed_columns() {
+    let isa = Isa::sic_xe();
+    let src = "\
+COPY     START  1000h
+. a comment line
+EOF      BYTE   C'E O F'            three bytes, one space each
+LONGLABL+JSUB   RDREC               no space before the +
+         RSUB   return to caller
+         LDA   #3
+";
+    assert_eq!(detect_format(src), SourceFormat::Fixed);
+    assert_eq!(detect_format(" LDA  #3\n"), SourceFormat::Free);
+    let (stmts, errors) = parse(src, &isa, SourceFormat::Auto);
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    assert_eq!(stmts[2].operands[0].expr.kind, ExprKind::Chars("E O F".to_owned()));
+    assert_eq!(stmts[2].comment.as_ref().unwrap().text, "three bytes, one space each");
+    assert_eq!(stmts[3].label.as_ref().unwrap().text, "LONGLABL");
+    assert!(stmts[3].mnemonic.as_ref().unwrap().extended);
+    assert!(stmts[4].operands.is_empty());
+    assert_eq!(stmts[4].comment.as_ref().unwrap().text, "return to caller");
+    assert_eq!(stmts[5].operands[0].mode, addr_mod::Immediate);
+
+    let (_, errors) = parse("         LDA    BUF+ 3\n         LDA     BUF\n", &isa, SourceFormat::Fixed);
+    let codes: Vec<_> = errors.iter().map(|(k, d)| (*k, d.location.cols.clone())).collect();
+    assert_eq!(codes, vec![(0, 20..21), (1, 17..20)]);
+
+    // A character spanning a column boundary is an error, not a panic.
+    let (stmts, errors) = parse("         RSUB é\n        éLDA   #3\n. é comment\n         RSUB       é\n", &isa, SourceFormat::Fixed);
+    let found: Vec<_> = errors.iter().map(|(k, d)| (*k, d.location.cols.clone(), &*d.message)).collect();
+    assert_eq!(found, vec![
+        (0, 14..16, "columns 1-16 can only hold ASCII characters"),
+        (1, 8..10, "columns 1-16 can only hold ASCII characters")
+    ]);
+    assert_eq!(stmts[3].comment.as_ref().unwrap().text, "é");
+}
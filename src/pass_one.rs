@@ -0,0 +1,310 @@
+use super::line::*;
+use super::isa::Operands;
+use super::diagnostic::{Diagnostic, Code, Field};
+
+// An EQU whose operand refers to a symbol that has no value yet: the index of
+// its line, its address, the symbol it defines and its operand.
+struct Deferred {
+    line: usize,
+    here: u32,
+    label: String,
+    value: arg
+}
+
+/// Pass one: gives every line a format and an address, and builds the symbol
+/// table.
+///
+/// Addresses start at the operand of START. EQU definitions that refer to
+/// symbols defined later in the program are resolved once every address is
+/// known. Each problem found is returned with the index of the line it refers
+/// to; problems with the program as a whole have the index one past the last
+/// line.
+///
+/// `predefined` holds constants defined before assembly starts, which are in
+/// the symbol table with line number 0. Defining one of them again is an
+/// error, unless `override_source` is set, in which case EQU lines defining
+/// them are skipped.
+pub fn run(lines: &mut [Line], predefined: &[(String, i32)], override_source: bool) -> (Symtab, Vec<(usize, Diagnostic)>) {
+    let mut symtab = Symtab::new();
+    for (name, v) in predefined {
+        symtab.insert(name.clone(), Pos { line_no: 0, mem_loc: 0, val: Some(v & 0x00FFFFFF) });
+    }
+    let mut found = Vec::new();
+    let mut deferred = Vec::new();
+    let mut loc = 0u32;
+    let mut seen_end = false;
+
+    for (k, curr) in lines.iter_mut().enumerate() {
+        let mut errs = Vec::new();
+        curr.mem_loc = loc;
+        let overridden = override_source
+            && curr.operation.unwrap_as_directive() == "EQU"
+            && curr.label.as_ref().and_then(|l| symtab.get(l)).is_some_and(|p| p.line_no == 0);
+        if !overridden {
+            layout(k, curr, &mut loc, &mut symtab, &mut deferred, &mut errs);
+        }
+        seen_end |= curr.operation.unwrap_as_directive() == "END";
+        found.extend(errs.into_iter().map(|d| (k, d)));
+    }
+
+    found.extend(resolve(lines, deferred, &mut symtab));
+    if !seen_end {
+        let last = lines.last().map_or(0, |l| l.line_no);
+        found.push((
+            lines.len(),
+            Diagnostic::error(Code::MissingEnd, "the program has no END directive").line(last)
+        ));
+    }
+    (symtab, found)
+}
+
+// Adds the label of `curr`, if it has one, to the symbol table.
+fn define(curr: &Line, mem_loc: u32, val: Option<i32>, symtab: &mut Symtab) -> Result<(), Diagnostic> {
+    let l = match curr.label {
+        Some(ref l) if !l.is_empty() => l,
+        _ => return Ok(())
+    };
+    if let Some(first) = symtab.get(l) {
+        if first.line_no == 0 {
+            return Err(
+                Diagnostic::error(Code::DuplicateSymbol, &format!("symbol {} is already defined on the command line", l))
+                    .line(curr.line_no)
+                    .at(Field::Label)
+            );
+        }
+        return Err(
+            Diagnostic::error(Code::DuplicateSymbol, &format!("duplicate definition of symbol {}", l))
+                .line(curr.line_no)
+                .at(Field::Label)
+                .related(first.line_no, l, "first defined here")
+        );
+    }
+    symtab.insert(l.to_owned(), Pos { line_no: curr.line_no, mem_loc, val });
+    Ok(())
+}
+
+fn require_label(curr: &Line, directive: &str) -> Result<(), Diagnostic> {
+    match curr.label {
+        Some(_) => Ok(()),
+        None => Err(
+            Diagnostic::error(Code::MissingLabel, &format!("the {} directive requires a label", directive))
+                .line(curr.line_no)
+                .at(Field::Mnemonic)
+        )
+    }
+}
+
+fn invalid_argument(curr: &Line, a: &arg_struct, msg: &str) -> Diagnostic {
+    Diagnostic::error(Code::InvalidArgument, msg).line(curr.line_no).at(Field::Operand(curr.arg_index(a)))
+}
+
+fn eval_error(curr: &Line, a: &arg_struct, e: EvalError) -> Diagnostic {
+    match e {
+        EvalError::Undefined(x) => Diagnostic::error(Code::UndefinedSymbol, &format!("undefined symbol {}", x))
+            .line(curr.line_no)
+            .at(Field::Symbol(x)),
+        EvalError::NotANumber => invalid_argument(curr, a, "string literals cannot be used in expressions"),
+        EvalError::Relocation => invalid_argument(curr, a, "the expression is neither a constant nor a single address"),
+        EvalError::DivideByZero => invalid_argument(curr, a, "division by zero"),
+        EvalError::Overflow => Diagnostic::error(Code::OperandOutOfRange, "the value of the expression does not fit in 24 bits")
+            .line(curr.line_no)
+            .at(Field::Operand(curr.arg_index(a)))
+    }
+}
+
+// True if `a` refers to no symbol that is still waiting for a value.
+fn is_known(a: &arg, symtab: &Symtab, deferred: &[Deferred]) -> bool {
+    a.symbols().iter().all(|x| symtab.contains_key(*x) && !deferred.iter().any(|d| d.label == *x))
+}
+
+// The number of units RESB or RESW reserves: a constant expression using only
+// symbols defined on earlier lines.
+fn reservation(curr: &Line, symtab: &Symtab, deferred: &[Deferred]) -> Result<u32, Diagnostic> {
+    let a = &curr.args[0];
+    let name = curr.operation.unwrap_as_directive();
+    if !is_known(&a.val, symtab, deferred) {
+        return Err(invalid_argument(curr, a, &format!("the {} count must be a constant defined before it is used", name)));
+    }
+    match a.val.eval(symtab, curr.mem_loc) {
+        Ok((n, false)) if n & 0x00800000 == 0 => Ok(n as u32),
+        Ok(_) => Err(invalid_argument(curr, a, &format!("the {} count must be a positive constant", name))),
+        Err(e) => Err(eval_error(curr, a, e))
+    }
+}
+
+// Adds the label of an EQU with the value of its operand.
+fn define_value(curr: &Line, a: &arg_struct, value: Result<(i32, bool), EvalError>, symtab: &mut Symtab) -> Result<(), Diagnostic> {
+    match value {
+        Ok((v, true)) => define(curr, v as u32, None, symtab),
+        Ok((v, false)) => define(curr, curr.mem_loc, Some(v), symtab),
+        Err(e) => Err(eval_error(curr, a, e))
+    }
+}
+
+fn layout(k: usize, curr: &mut Line, loc: &mut u32, symtab: &mut Symtab, deferred: &mut Vec<Deferred>, errs: &mut Vec<Diagnostic>) {
+    let op = curr.operation.clone();
+    match op {
+        source_op::Neh => curr.format = format::Comment,
+        source_op::Error => errs.push(Diagnostic::error(Code::InvalidOpcode, "invalid opcode").line(curr.line_no)),
+        source_op::Instruction(ref x) => {
+            errs.extend(define(curr, *loc, None, symtab).err());
+            if !x.operands.accepts(&curr.args) {
+                errs.push(
+                    Diagnostic::error(Code::OperandShape, &format!("{} expects {}", x.name, x.operands.describe()))
+                        .line(curr.line_no)
+                        .at(Field::Mnemonic)
+                );
+            }
+            let (f, size) = match x.format {
+                1 => (format::Opless, 1),
+                2 => (format::Register, 2),
+                _ if x.operands == Operands::None => (format::Opless, if x.long { 4 } else { 3 }),
+                _ if x.long => (format::Long, 4),
+                _ => (format::Normal, 3)
+            };
+            curr.format = f;
+            *loc += size;
+        }
+        source_op::Directive(ref x) => {
+            curr.format = format::Directive;
+            let name = &*x.name;
+            if curr.args.is_empty() && ["BYTE", "WORD", "RESB", "RESW", "EQU"].contains(&name) {
+                errs.push(
+                    Diagnostic::error(Code::OperandShape, &format!("the {} directive requires an operand", name))
+                        .line(curr.line_no)
+                        .at(Field::Mnemonic)
+                );
+                return;
+            }
+            match name {
+                "START" => {
+                    match curr.args.first().map(|a| &a.val) {
+                        None => {}
+                        Some(arg::IntLit(x)) if (0..=0x0FFFFF).contains(x) => *loc = *x as u32,
+                        Some(_) => errs.push(invalid_argument(curr, &curr.args[0], "START requires an address between 0 and FFFFF"))
+                    }
+                    curr.mem_loc = *loc;
+                    errs.extend(define(curr, *loc, None, symtab).err());
+                }
+                "BYTE" => {
+                    errs.extend(require_label(curr, name).err());
+                    errs.extend(define(curr, *loc, None, symtab).err());
+                    for a in &curr.args {
+                        match a.val {
+                            arg::StrLit(ref s) => *loc += s.len() as u32,
+                            arg::IntLit(_) => *loc += 1,
+                            _ => errs.push(invalid_argument(curr, a, "the BYTE directive does not accept labels as arguments"))
+                        }
+                    }
+                }
+                "WORD" => {
+                    errs.extend(require_label(curr, name).err());
+                    errs.extend(define(curr, *loc, None, symtab).err());
+                    *loc += 3 * curr.args.len() as u32;
+                }
+                "RESB" | "RESW" => {
+                    errs.extend(require_label(curr, name).err());
+                    errs.extend(define(curr, *loc, None, symtab).err());
+                    match reservation(curr, symtab, deferred) {
+                        Ok(n) => *loc += if name == "RESW" { 3 * n } else { n },
+                        Err(d) => errs.push(d)
+                    }
+                }
+                "EQU" => {
+                    if let Err(d) = require_label(curr, name) {
+                        errs.push(d);
+                        return;
+                    }
+                    let a = &curr.args[0];
+                    let res = if let arg::StrLit(_) = a.val {
+                        Err(invalid_argument(curr, a, "the EQU directive does not accept string literals as arguments"))
+                    } else if is_known(&a.val, symtab, deferred) {
+                        define_value(curr, a, a.val.eval(symtab, curr.mem_loc), symtab)
+                    } else {
+                        // Refers to symbols defined later on, or not at all;
+                        // `resolve` finds out which.
+                        define(curr, *loc, Some(0), symtab).map(|_| deferred.push(Deferred {
+                            line: k,
+                            here: curr.mem_loc,
+                            label: curr.label.clone().unwrap_or_default(),
+                            value: a.val.clone()
+                        }))
+                    };
+                    errs.extend(res.err());
+                }
+                _ => errs.extend(define(curr, *loc, None, symtab).err())
+            }
+        }
+    }
+}
+
+// Gives each deferred EQU the value of its operand, repeating until no more
+// can be resolved. Whatever is left either refers to an undefined symbol or
+// depends on a cycle of definitions.
+fn resolve(lines: &[Line], mut deferred: Vec<Deferred>, symtab: &mut Symtab) -> Vec<(usize, Diagnostic)> {
+    let mut found = Vec::new();
+    loop {
+        let known: Vec<bool> = deferred.iter().map(|d| is_known(&d.value, symtab, &deferred)).collect();
+        let (ready, rest): (Vec<_>, Vec<_>) = deferred.into_iter().zip(known).partition(|(_, k)| *k);
+        deferred = rest.into_iter().map(|(d, _)| d).collect();
+        if ready.is_empty() {
+            break;
+        }
+        for (d, _) in ready {
+            let curr = &lines[d.line];
+            let value = d.value.eval(symtab, d.here);
+            let p = symtab.get_mut(&d.label).unwrap();
+            match value {
+                Ok((v, true)) => {
+                    p.mem_loc = v as u32;
+                    p.val = None;
+                }
+                Ok((v, false)) => p.val = Some(v),
+                Err(e) => found.push((d.line, eval_error(curr, &curr.args[0], e)))
+            }
+        }
+    }
+
+    for d in &deferred {
+        let curr = &lines[d.line];
+        if let Some(x) = d.value.symbols().into_iter().find(|x| !symtab.contains_key(*x)) {
+            found.push((
+                d.line,
+                Diagnostic::error(Code::UndefinedSymbol, &format!("undefined symbol {}", x))
+                    .line(curr.line_no)
+                    .at(Field::Symbol(x.to_owned()))
+            ));
+            continue;
+        }
+        // Symbols that merely depend on a cycle are reported by its members.
+        if let Some(mut cycle) = find_cycle(&d.label, &d.label, &deferred, &mut Vec::new()) {
+            cycle.insert(0, d.label.clone());
+            found.push((
+                d.line,
+                Diagnostic::error(Code::CircularDefinition, &format!("circular definition: {}", cycle.join(" -> ")))
+                    .line(curr.line_no)
+                    .at(Field::Symbol(cycle[1].clone()))
+            ));
+        }
+    }
+    found
+}
+
+// A path of deferred definitions from `from` back to `to`, if there is one.
+fn find_cycle(from: &str, to: &str, deferred: &[Deferred], seen: &mut Vec<String>) -> Option<Vec<String>> {
+    let d = deferred.iter().find(|d| d.label == from)?;
+    for x in d.value.symbols() {
+        if x == to {
+            return Some(vec![x.to_owned()]);
+        }
+        if seen.iter().any(|s| s == x) {
+            continue;
+        }
+        seen.push(x.to_owned());
+        if let Some(mut path) = find_cycle(x, to, deferred, seen) {
+            path.insert(0, x.to_owned());
+            return Some(path);
+        }
+    }
+    None
+}
@@ -0,0 +1,554 @@
+use std::fmt::Write;
+
+use super::line::*;
+use super::isa::Operands;
+use super::diagnostic::{Diagnostic, Code, Field};
+
+/// The object program built by pass two, before it is written out in any
+/// particular record format.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectProgram {
+    /// The label of the START directive.
+    pub name: String,
+    pub start: u32,
+    pub length: u32,
+    /// The address given to END, or the start address if it has none.
+    pub entry: u32,
+    /// Runs of consecutive bytes, at most 30 each, and the address of the first.
+    pub text: Vec<(u32, Vec<u8>)>,
+    /// Fields the loader must add the load address to, in address order.
+    pub modifications: Modtab
+}
+
+/// The operand of a format 3 or 4 instruction as pass two saw it, kept so
+/// that `explain` can show how the object code was worked out.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Encoding {
+    /// The operand as written, without its `#` or `@`.
+    pub operand: String,
+    /// The value of the operand: an address or a constant.
+    pub value: i32,
+    /// The value of register B, if base-relative addressing was used.
+    pub base: Option<u32>
+}
+
+// The most bytes a text record holds.
+const TEXT_RECORD_LEN: usize = 30;
+
+impl ObjectProgram {
+    /// The program as H, T, M and E records, one per line, in hexadecimal.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "H{:<6}{:06X}{:06X}", self.name, self.start, self.length);
+        for (addr, bytes) in &self.text {
+            let _ = write!(out, "T{:06X}{:02X}", addr, bytes.len());
+            for b in bytes {
+                let _ = write!(out, "{:02X}", b);
+            }
+            out.push('\n');
+        }
+        for m in &self.modifications {
+            let _ = writeln!(out, "M{:06X}{:02X}", m.mem_loc, m.length);
+        }
+        let _ = writeln!(out, "E{:06X}", self.entry);
+        out
+    }
+
+    /// The same records as `to_text`, with every field stored in binary: a
+    /// record letter, then addresses and lengths as 3 and 1 bytes.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut obj_code = VecWrapper::new()
+            .push_byte(b'H')
+            .push_str(format!("{:<6}", self.name))
+            .push_word(self.start)
+            .push_word(self.length);
+        for (addr, bytes) in &self.text {
+            obj_code = obj_code.push_byte(b'T').push_word(*addr).push_byte(bytes.len() as u8).push_vec(bytes);
+        }
+        for m in &self.modifications {
+            obj_code = obj_code.push_byte(b'M').push_word(m.mem_loc).push_byte(m.length);
+        }
+        obj_code.push_byte(b'E').push_word(self.entry).vec
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct VecWrapper {
+    pub vec: Vec<u8>
+}
+
+impl VecWrapper {
+    pub fn new() -> VecWrapper {
+        VecWrapper {
+            vec: Vec::new()
+        }
+    }
+
+    pub fn push_word(mut self, word: u32) -> VecWrapper {
+        self.vec.push(((word & 0x00FF0000) >> 16) as u8);
+        self.vec.push(((word & 0x0000FF00) >> 8) as u8);
+        self.vec.push((word & 0x000000FF) as u8);
+        self
+    }
+
+    pub fn push_byte(mut self, byte: u8) -> VecWrapper {
+        self.vec.push(byte);
+        self
+    }
+
+    pub fn push_str(mut self, string: String) -> VecWrapper {
+        self.vec.extend(string.into_bytes());
+        self
+    }
+
+    pub fn push_vec(mut self, v: &[u8]) -> VecWrapper {
+        self.vec.extend_from_slice(v);
+        self
+    }
+}
+
+/// Pass two: generates the object code of every line and collects it into an
+/// `ObjectProgram`.
+///
+/// Expects the lines and symbol table produced by `pass_one::run`. Each
+/// problem found is returned with the index of the line it refers to.
+pub fn encode(lines: &mut [Line], symtab: &Symtab) -> (ObjectProgram, Vec<(usize, Diagnostic)>) {
+    let mut program = ObjectProgram::default();
+    let mut found = Vec::new();
+    let mut base = NO_BASE;
+
+    for (k, curr) in lines.iter_mut().enumerate() {
+        if let Err(d) = gen_obj_code(curr, symtab, &mut base, &mut program.modifications) {
+            found.push((k, d));
+        }
+        match &*curr.operation.unwrap_as_directive() {
+            "START" => {
+                program.name = curr.label.clone().unwrap_or_default();
+                program.start = curr.mem_loc;
+                program.entry = curr.mem_loc;
+            }
+            "END" => program.entry = entry_point(curr, symtab, program.start),
+            _ => {}
+        }
+        add_text(&mut program.text, curr.mem_loc, &curr.obj_code);
+    }
+
+    // END is the last line, and its address is where the program stops.
+    let end = lines.iter().rev().find(|l| l.operation.unwrap_as_directive() == "END").or_else(|| lines.last());
+    program.length = end.map_or(0, |l| (l.mem_loc + l.obj_code.len() as u32).saturating_sub(program.start));
+    program.modifications.sort_by_key(|m| m.mem_loc);
+    (program, found)
+}
+
+// Appends `code` at `addr` to the text runs, starting a new run after a gap
+// or when the current one is full.
+fn add_text(text: &mut Vec<(u32, Vec<u8>)>, mut addr: u32, mut code: &[u8]) {
+    while !code.is_empty() {
+        let contiguous = text.last().is_some_and(|(a, b)| a + b.len() as u32 == addr && b.len() < TEXT_RECORD_LEN);
+        if !contiguous {
+            text.push((addr, Vec::new()));
+        }
+        let run = &mut text.last_mut().unwrap().1;
+        let n = code.len().min(TEXT_RECORD_LEN - run.len());
+        // Keep instructions in one record where possible.
+        if n < code.len() && !run.is_empty() && code.len() <= TEXT_RECORD_LEN {
+            text.push((addr, Vec::new()));
+            continue;
+        }
+        run.extend_from_slice(&code[..n]);
+        addr += n as u32;
+        code = &code[n..];
+    }
+}
+
+// The address given to END, or the start address if it has none.
+fn entry_point(end_line: &Line, symtab: &Symtab, start: u32) -> u32 {
+    end_line.args.first()
+        .and_then(|a| a.val.eval(symtab, end_line.mem_loc).ok())
+        .map_or(start, |(v, _)| v as u32)
+}
+
+const NO_BASE: u32 = 0xFFFFFFFF;
+
+fn invalid_argument(curr: &Line, a: &arg_struct, msg: &str) -> Diagnostic {
+    Diagnostic::error(Code::InvalidArgument, msg).line(curr.line_no).at(Field::Operand(curr.arg_index(a)))
+}
+
+fn out_of_range(curr: &Line, a: &arg_struct, msg: &str) -> Diagnostic {
+    Diagnostic::error(Code::OperandOutOfRange, msg).line(curr.line_no).at(Field::Operand(curr.arg_index(a)))
+}
+
+// The value of an operand, and whether it is an address rather than a
+// constant.
+fn operand_value(curr: &Line, a: &arg_struct, symtab: &Symtab) -> Result<(i32, bool), Diagnostic> {
+    a.val.eval(symtab, curr.mem_loc).map_err(|e| match e {
+        EvalError::Undefined(x) => Diagnostic::error(Code::UndefinedSymbol, &format!("undefined symbol {}", x))
+            .line(curr.line_no)
+            .at(Field::Symbol(x)),
+        EvalError::NotANumber => invalid_argument(curr, a, "string literals cannot be used as instruction operands"),
+        EvalError::Relocation => invalid_argument(curr, a, "the expression is neither a constant nor a single address"),
+        EvalError::DivideByZero => invalid_argument(curr, a, "division by zero"),
+        EvalError::Overflow => Diagnostic::error(Code::OperandOutOfRange, "the value of the expression does not fit in 24 bits")
+            .line(curr.line_no)
+            .at(Field::Operand(curr.arg_index(a)))
+    })
+}
+
+// Picks PC-relative addressing if the target is close enough, base-relative
+// if a base is in effect, and fails otherwise. Returns the x b p e nibble and
+// displacement as the low 16 bits of a format 3 instruction.
+fn relative_disp(curr: &Line, a: &arg_struct, target: i32, base: u32) -> Result<u16, Diagnostic> {
+    let pc = curr.mem_loc as i32 + 3;
+    if (-2048..=2047).contains(&(target - pc)) {
+        return Ok((((target - pc) & 0x0FFF) | 0x2000) as u16); // OR with 0x2000 for PC flag
+    }
+    if base != NO_BASE && (0..=4095).contains(&(target - base as i32)) {
+        return Ok((((target - base as i32) & 0x0FFF) | 0x4000) as u16); // OR with 0x4000 for base flag
+    }
+    Err(out_of_range(
+        curr,
+        a,
+        &format!(
+            "target address {:06X} is out of range of PC-relative{} addressing; use format 4 (+) instead",
+            target,
+            if base == NO_BASE { "" } else { " and base-relative" }
+        )
+    ))
+}
+
+// Checks the optional `,X` of a memory operand. Returns the x flag.
+fn index_flag(curr: &Line) -> Result<bool, Diagnostic> {
+    match curr.args.get(1) {
+        None => Ok(false),
+        Some(a) if a.reg_code != 0x01 => Err(
+            Diagnostic::error(Code::BadIndexRegister, "only register X can be used for indexing")
+                .line(curr.line_no)
+                .at(Field::Operand(1))
+        ),
+        Some(_) if curr.args[0].modifier != addr_mod::Direct => Err(
+            Diagnostic::error(Code::BadIndexRegister, "indexing cannot be combined with immediate or indirect addressing")
+                .line(curr.line_no)
+                .at(Field::Operand(1))
+        ),
+        Some(_) => Ok(true)
+    }
+}
+
+// The name of register `code`, as used in format 2 instructions.
+fn register_name(code: u8) -> Option<&'static str> {
+    let names = ["A", "X", "L", "B", "S", "T", "F", "", "PC", "SW"];
+    names.get(code as usize).cloned().filter(|n| !n.is_empty())
+}
+
+// Explains a format 3 or 4 instruction, from its object code.
+fn explain_memory(curr: &Line) -> Vec<String> {
+    let code = &curr.obj_code;
+    let flags = (code[0] & 0x03) << 4 | code[1] >> 4;
+    let bit = |k: u8| (flags >> k) & 1;
+    let (n, i, x, b, p, e) = (bit(5), bit(4), bit(3), bit(2), bit(1), bit(0));
+    let field = code[1..].iter().fold(0u32, |v, c| v << 8 | *c as u32) & if e == 1 { 0x0FFFFF } else { 0x0FFF };
+    let mut rows = vec![format!(
+        "opcode {:02X} = {:06b}, flags n={} i={} x={} b={} p={} e={}",
+        code[0] & 0xFC, code[0] >> 2, n, i, x, b, p, e
+    )];
+
+    let enc = match curr.encoding {
+        Some(ref enc) => enc,
+        None => {
+            rows.push("no operand: n=1 i=1 and the address field is zero".to_owned());
+            return rows;
+        }
+    };
+    rows.push(match (n, i) {
+        (0, 1) => format!("immediate (n=0 i=1): {} is the operand itself", enc.operand),
+        (1, 0) => format!("indirect (n=1 i=0): {} holds the address of the operand", enc.operand),
+        _ => format!("simple (n=1 i=1): {} is the target address", enc.operand)
+    });
+    let pc = curr.mem_loc + 3;
+    let value = enc.value & 0x00FFFFFF;
+    rows.push(match enc.base {
+        _ if e == 1 => format!("format 4 (e=1): the 20-bit address field holds {:05X} as is", field),
+        _ if p == 1 => format!(
+            "PC-relative (p=1): disp = {:06X} - PC {:06X} = {:03X}, which is within -2048..2047",
+            value, pc, field
+        ),
+        Some(base) => format!(
+            "base-relative (b=1): {:06X} - PC {:06X} is outside -2048..2047, so disp = {:06X} - B {:06X} = {:03X}",
+            value, pc, value, base, field
+        ),
+        None => format!("no relative addressing (b=0 p=0): {} is a constant stored as {:03X}", enc.operand, field)
+    });
+    if x == 1 {
+        rows.push("indexed (x=1): the value of register X is added to the target address".to_owned());
+    }
+    let bits = format!("{:06b} {}{} {}{}{}{} ", code[0] >> 2, n, i, x, b, p, e);
+    let hex: String = code.iter().map(|c| format!("{:02X}", c)).collect();
+    rows.push(format!("{}{:0w$b} = {}", bits, field, hex, w = if e == 1 { 20 } else { 12 }));
+    rows
+}
+
+/// Explains how the object code of an instruction was put together: the
+/// opcode, the flag bits, the addressing mode chosen and the arithmetic
+/// behind the displacement. Gives no rows for other lines.
+pub fn explain(curr: &Line) -> Vec<String> {
+    let name = match curr.operation {
+        source_op::Instruction(ref x) => x.name.clone(),
+        _ => return Vec::new()
+    };
+    let code = &curr.obj_code;
+    match curr.format {
+        _ if code.len() == 1 => vec![format!("format 1: opcode {:02X} ({})", code[0], name)],
+        format::Register if code.len() == 2 => {
+            let (r1, r2) = (code[1] >> 4, code[1] & 0x0F);
+            let named = |r: u8, k: usize| match curr.args.get(k) {
+                Some(a) if a.reg_code != 0xFF => format!("{} ({})", r, register_name(r).unwrap_or("?")),
+                _ => r.to_string()
+            };
+            vec![format!("format 2: opcode {:02X} ({}), r1 = {}, r2 = {}", code[0], name, named(r1, 0), named(r2, 1))]
+        }
+        format::Opless | format::Normal | format::Long if code.len() >= 3 => explain_memory(curr),
+        _ => Vec::new()
+    }
+}
+
+/// Generates the object code of a single line into `curr.obj_code`.
+///
+/// `base` tracks the BASE directives seen so far, and a modification record
+/// is added to `mods` for every field holding a relocatable address.
+pub fn gen_obj_code(curr: &mut Line, symtab: &Symtab, base: &mut u32, mods: &mut Modtab) -> Result<(), Diagnostic> {
+    let op = match curr.operation {
+        source_op::Instruction(ref x) => Some(x.clone()),
+        _ => None
+    };
+    let wrong_operands = |curr: &Line, x: &op_struct| {
+        Diagnostic::error(Code::OperandShape, &format!("{} expects {}", x.name, x.operands.describe()))
+            .line(curr.line_no)
+            .at(Field::Mnemonic)
+    };
+    match curr.format {
+        format::Opless => {
+            if let Some(x) = op {
+                if x.format == 3 {
+                    curr.obj_code.push(x.opcode | 0x03);
+                    if x.long { curr.obj_code.push(0x10); }
+                    curr.obj_code.push(0x00);
+                    curr.obj_code.push(0x00);
+                } else {
+                    curr.obj_code.push(x.opcode)
+                }
+            }
+        },
+        format::Register => {
+            let x = match op {
+                Some(x) => x,
+                None => return Ok(())
+            };
+            if !x.operands.accepts(&curr.args) {
+                return Err(wrong_operands(curr, &x));
+            }
+            let second = match x.operands {
+                Operands::Register => curr.args[0].reg_code << 4,
+                Operands::RegisterPair => (curr.args[0].reg_code << 4) | (curr.args[1].reg_code),
+                // SHIFTL/SHIFTR store the shift count minus one
+                Operands::RegisterNumber => {
+                    let n = curr.args[1].val.unwrap_as_int().unwrap_or(0);
+                    if !(1..=16).contains(&n) {
+                        return Err(out_of_range(curr, &curr.args[1], "shift counts must be between 1 and 16"));
+                    }
+                    (curr.args[0].reg_code << 4) | ((n - 1) as u8)
+                }
+                Operands::Number => {
+                    let n = curr.args[0].val.unwrap_as_int().unwrap_or(0);
+                    if !(0..=15).contains(&n) {
+                        return Err(out_of_range(curr, &curr.args[0], &format!("{} takes a number between 0 and 15", x.name)));
+                    }
+                    (n as u8) << 4
+                }
+                _ => return Err(wrong_operands(curr, &x))
+            };
+            curr.obj_code.push(x.opcode);
+            curr.obj_code.push(second);
+        },
+        // opcode n i | x b p e offset
+        // 000000 0 0 | 0 0 0 0 0000 0000 0000
+        format::Normal => {
+            let x = match op {
+                Some(x) => x,
+                None => return Ok(())
+            };
+            if curr.args.is_empty() || curr.args.len() > 2 {
+                return Err(wrong_operands(curr, &x));
+            }
+            let a = &curr.args[0];
+            let (value, is_address) = operand_value(curr, a, symtab)?;
+            let mut disp = match a.modifier {
+                // Negative values are 24 bits wide; keep the low 12 bits of
+                // those that fit.
+                addr_mod::Immediate if !is_address && value >= 0x01000000 - 0x800 => (value & 0x0FFF) as u16,
+                addr_mod::Immediate if !is_address => {
+                    if !(0..=0x0FFF).contains(&value) {
+                        return Err(out_of_range(
+                            curr,
+                            a,
+                            &format!("immediate value {} does not fit in 12 bits; use format 4 (+) instead", value)
+                        ));
+                    }
+                    value as u16
+                }
+                addr_mod::Literal => return Err(invalid_argument(curr, a, "literals are not supported")),
+                // A small constant address can be used as is.
+                _ if !is_address && (0..=0x0FFF).contains(&value) => value as u16,
+                _ => relative_disp(curr, a, value, *base)?
+            };
+            if index_flag(curr)? {
+                disp |= 0x8000u16;
+            }
+            curr.encoding = Some(Encoding {
+                operand: a.val.to_string().trim().to_owned(),
+                value,
+                base: if disp & 0x4000 != 0 { Some(*base) } else { None }
+            });
+            curr.obj_code.push(x.opcode | a.modifier.clone() as u8);
+            curr.obj_code.push((disp >> 8) as u8);
+            curr.obj_code.push((disp & 0x00FF) as u8);
+        },
+        // opcode n i | x b p e addr |           |
+        // 000000 0 0 | 0 n n 1 0000 | 0000 0000 | 0000 0000
+        //              8 4 2 1  0      0    0      0    0
+        format::Long => {
+            let x = match op {
+                Some(x) => x,
+                None => return Ok(())
+            };
+            if curr.args.is_empty() || curr.args.len() > 2 {
+                return Err(wrong_operands(curr, &x));
+            }
+            let a = &curr.args[0];
+            if a.modifier == addr_mod::Literal {
+                return Err(invalid_argument(curr, a, "literals are not supported"));
+            }
+            let (mut value, is_address) = operand_value(curr, a, symtab)?;
+            if a.modifier == addr_mod::Immediate && !is_address && value >= 0x01000000 - 0x80000 {
+                value &= 0x000FFFFF;
+            }
+            if !(0..=0x000FFFFF).contains(&value) {
+                return Err(out_of_range(curr, a, &format!("{} does not fit in a 20-bit address", value)));
+            }
+            let mut ta = 0x00100000u32 | value as u32;
+            if index_flag(curr)? {
+                ta |= 0x800000u32;
+            }
+            curr.encoding = Some(Encoding { operand: a.val.to_string().trim().to_owned(), value, base: None });
+            curr.obj_code.push(x.opcode | a.modifier.clone() as u8);
+            curr.obj_code.push(((ta & 0x00FF0000) >> 16) as u8);
+            curr.obj_code.push(((ta & 0x0000FF00) >> 8) as u8);
+            curr.obj_code.push((ta & 0x000000FF) as u8);
+            // Only addresses move when the program is loaded elsewhere.
+            if is_address {
+                mods.push(mod_rec::new().length(5).mem_loc(curr.mem_loc + 1));
+            }
+        },
+        format::Directive => {
+            match &*curr.operation.unwrap_as_directive() {
+                "BYTE" => {
+                    let mut code = Vec::new();
+                    for a in &curr.args {
+                        match a.val {
+                            arg::StrLit(ref s) => code.extend(s.bytes()),
+                            // Values are 24 bits wide, so negative ones have bit 23 set.
+                            arg::IntLit(x) if (-128..=255).contains(&(x << 8 >> 8)) => code.push(x as u8),
+                            arg::IntLit(_) => return Err(out_of_range(curr, a, "BYTE values must fit in a byte")),
+                            _ => return Err(invalid_argument(curr, a, "the BYTE directive does not accept labels as arguments"))
+                        }
+                    }
+                    curr.obj_code = code;
+                }
+                "WORD" => {
+                    let mut code = Vec::new();
+                    for a in &curr.args {
+                        if let arg::StrLit(_) = a.val {
+                            return Err(invalid_argument(curr, a, "the WORD directive cannot store strings"));
+                        }
+                        let (x, is_address) = operand_value(curr, a, symtab)?;
+                        if is_address {
+                            mods.push(mod_rec::new().length(6).mem_loc(curr.mem_loc + code.len() as u32));
+                        }
+                        code.extend(&[
+                            ((x & 0x00FF0000) >> 16) as u8,
+                            ((x & 0x0000FF00) >> 8) as u8,
+                            (x & 0x000000FF) as u8
+                        ]);
+                    }
+                    curr.obj_code = code;
+                }
+                "BASE" => {
+                    let a = match curr.args.first() {
+                        Some(a) => a,
+                        None => return Err(
+                            Diagnostic::error(Code::OperandShape, "BASE expects an address").line(curr.line_no).at(Field::Mnemonic)
+                        )
+                    };
+                    *base = operand_value(curr, a, symtab)?.0 as u32;
+                }
+                "NOBASE" => *base = NO_BASE,
+                _ => return Ok(())
+            }
+        }
+        _ => return Ok(())
+    }
+    Ok(())
+}
+
+#[test]
+fn explain_encoding() {
+    let source = "\
+COPY START 0
+FIRST STL RETADR
+ BASE LENGTH
+ LDB #LENGTH
+ +JSUB RDREC
+ STCH BUFFER,X
+ CLEAR X
+ LDA #3
+ RSUB
+RETADR RESW 1
+GAP RESB 2048
+LENGTH RESW 1
+BUFFER RESB 10
+RDREC RSUB
+ END FIRST
+";
+    let result = super::Assembler::new().assemble("copy.asm", source);
+    let explained: Vec<Vec<String>> = result.lines.iter().map(explain).collect();
+    assert_eq!(explained[1], vec![
+        "opcode 14 = 000101, flags n=1 i=1 x=0 b=0 p=1 e=0",
+        "simple (n=1 i=1): RETADR is the target address",
+        "PC-relative (p=1): disp = 000015 - PC 000003 = 012, which is within -2048..2047",
+        "000101 11 0010 000000010010 = 172012"
+    ]);
+    assert!(explained[2].is_empty());
+    assert_eq!(&explained[3][1..3], [
+        "immediate (n=0 i=1): LENGTH is the operand itself",
+        "base-relative (b=1): 000818 - PC 000006 is outside -2048..2047, so disp = 000818 - B 000818 = 000"
+    ]);
+    assert_eq!(&explained[4][2..], [
+        "format 4 (e=1): the 20-bit address field holds 00825 as is",
+        "010010 11 0001 00000000100000100101 = 4B100825"
+    ]);
+    assert_eq!(&explained[5][3..], [
+        "indexed (x=1): the value of register X is added to the target address",
+        "010101 11 1100 000000000011 = 57C003"
+    ]);
+    assert_eq!(explained[6], vec!["format 2: opcode B4 (CLEAR), r1 = 1 (X), r2 = 0"]);
+    assert_eq!(explained[7][2], "no relative addressing (b=0 p=0): 3 is a constant stored as 003");
+    assert_eq!(explained[8][1], "no operand: n=1 i=1 and the address field is zero");
+}
+
+#[test]
+fn byte_values() {
+    let result = super::Assembler::new().assemble("b.asm", "B START 0\nLO BYTE -1\nHI BYTE 255\nMIN BYTE -128\nBAD BYTE 256\nNEG BYTE -129\n END B\n");
+    let bytes: Vec<Vec<u8>> = result.lines[1..4].iter().map(|l| l.obj_code.clone()).collect();
+    assert_eq!(bytes, vec![vec![0xFF], vec![0xFF], vec![0x80]]);
+    let bad: Vec<_> = result.diagnostics.iter().filter(|d| d.is_error()).map(|d| (d.location.line, d.code.as_str())).collect();
+    assert_eq!(bad, vec![(5, "E008"), (6, "E008")]);
+}
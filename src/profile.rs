@@ -0,0 +1,213 @@
+//! Execution profiles of simulated programs.
+//!
+//! A `Profile` counts what the steps of a run did: instructions executed at
+//! each address, instructions and cycles spent in each subroutine, memory
+//! reads and writes, and device operations. It is printed as a summary, or as
+//! the program's listing with the counts next to each line.
+//!
+//! Cycles follow a simple model of a machine limited by its memory: an
+//! instruction takes one cycle for every byte it fetches, reads or writes.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::line::format;
+use super::sim::{Io, Step};
+use super::AssemblyResult;
+
+/// What ran in one subroutine.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct Counts {
+    pub calls: u64,
+    /// Instructions executed in the subroutine itself.
+    pub instructions: u64,
+    /// Instructions executed in the subroutine and the subroutines it called.
+    pub total: u64,
+    /// Cycles spent in the subroutine itself.
+    pub cycles: u64
+}
+
+/// The operations on one device.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct DeviceCounts {
+    pub tests: u64,
+    pub reads: u64,
+    pub writes: u64
+}
+
+/// Counts collected over a run, one step at a time.
+#[derive(Debug, Default, Clone)]
+pub struct Profile {
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Instructions executed, by address.
+    pub hits: BTreeMap<u32, u64>,
+    /// Data reads and writes, by the address of their first byte. Fetching
+    /// instructions is not counted.
+    pub reads: BTreeMap<u32, u64>,
+    pub writes: BTreeMap<u32, u64>,
+    /// Counts by the address a subroutine was called at. The code run before
+    /// the first call is counted under the address the run started at.
+    pub subroutines: BTreeMap<u32, Counts>,
+    pub devices: BTreeMap<u8, DeviceCounts>,
+    // The addresses of the subroutines called and not yet returned from.
+    calls: Vec<u32>
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// Adds what `step` did.
+    pub fn record(&mut self, step: &Step) {
+        if self.calls.is_empty() {
+            self.calls.push(step.address);
+            self.subroutines.entry(step.address).or_default().calls += 1;
+        }
+        let cycles = step.reads.iter().map(|r| r.1 as u64).sum::<u64>()
+            + step.writes.iter().map(|w| w.2.len() as u64).sum::<u64>();
+        self.instructions += 1;
+        self.cycles += cycles;
+        *self.hits.entry(step.address).or_default() += 1;
+        for (addr, _) in step.reads.iter().skip(1) {
+            *self.reads.entry(*addr).or_default() += 1;
+        }
+        for (addr, _, _) in &step.writes {
+            *self.writes.entry(*addr).or_default() += 1;
+        }
+
+        let current = self.subroutines.entry(*self.calls.last().unwrap()).or_default();
+        current.instructions += 1;
+        current.cycles += cycles;
+        // A recursive subroutine is only counted once per instruction.
+        let mut active = self.calls.clone();
+        active.sort_unstable();
+        active.dedup();
+        for addr in active {
+            self.subroutines.entry(addr).or_default().total += 1;
+        }
+        if step.is_call() {
+            let target = step.target.unwrap_or(0);
+            self.calls.push(target);
+            self.subroutines.entry(target).or_default().calls += 1;
+        }
+        if step.is_return() && self.calls.len() > 1 {
+            self.calls.pop();
+        }
+
+        match step.io {
+            Some(Io::Test(dev)) => self.devices.entry(dev).or_default().tests += 1,
+            Some(Io::Read(dev, _)) | Some(Io::ReadPastEnd(dev)) => self.devices.entry(dev).or_default().reads += 1,
+            Some(Io::Write(dev, _)) => self.devices.entry(dev).or_default().writes += 1,
+            None => {}
+        }
+    }
+
+    /// The totals, a table of subroutines named with `labels`, most
+    /// instructions first, and a table of devices.
+    pub fn summary(&self, labels: &BTreeMap<u32, String>) -> String {
+        let mut out = String::new();
+        let reads: u64 = self.reads.values().sum();
+        let writes: u64 = self.writes.values().sum();
+        let _ = writeln!(out, "Instructions  {}", self.instructions);
+        let _ = writeln!(out, "Cycles        {}", self.cycles);
+        let _ = writeln!(out, "Memory reads  {}", reads);
+        let _ = writeln!(out, "Memory writes {}", writes);
+
+        let mut subs: Vec<(&u32, &Counts)> = self.subroutines.iter().collect();
+        subs.sort_by_key(|(addr, c)| (std::cmp::Reverse(c.total), **addr));
+        let _ = writeln!(out, "\nSubroutine  Calls  Instructions    Total   Cycles");
+        for (addr, c) in subs {
+            let name = labels.get(addr).cloned().unwrap_or_else(|| format!("{:06X}", addr));
+            let _ = writeln!(out, "{:<10} {:>6} {:>13} {:>8} {:>8}", name, c.calls, c.instructions, c.total, c.cycles);
+        }
+        if !self.devices.is_empty() {
+            let _ = writeln!(out, "\nDevice  Tests  Reads  Writes");
+            for (dev, d) in &self.devices {
+                let _ = writeln!(out, "{:02X}     {:>6} {:>6} {:>7}", dev, d.tests, d.reads, d.writes);
+            }
+        }
+        out
+    }
+
+    /// The lines of `result`, loaded `offset` bytes from where they were
+    /// assembled, each with the number of times its instruction was executed
+    /// and the number of reads and writes of its bytes.
+    pub fn annotate(&self, result: &AssemblyResult, offset: u32) -> String {
+        let mut out = String::from("   Hits  Reads Writes   Line  Loc     Source\n");
+        let lines = &result.lines;
+        for (k, l) in lines.iter().enumerate() {
+            let text = result.sources[result.origin[k]].1.lines().nth(l.line_no as usize - 1).unwrap_or("");
+            if l.format == format::Comment {
+                let _ = writeln!(out, "{:>28}          {}", l.line_no, text);
+                continue;
+            }
+            // A line covers its object code, or for RESB and RESW, the bytes
+            // up to the next line's location.
+            let next = lines[k + 1..].iter().find(|n| n.format != format::Comment).map_or(l.mem_loc, |n| n.mem_loc);
+            let end = (l.mem_loc + l.obj_code.len() as u32).max(if next > l.mem_loc { next } else { l.mem_loc });
+            let range = l.mem_loc.wrapping_add(offset)..end.wrapping_add(offset);
+            let count = |m: &BTreeMap<u32, u64>| m.range(range.clone()).map(|(_, n)| n).sum::<u64>();
+            let show = |n: u64| if n > 0 { n.to_string() } else { String::new() };
+            let hits = count(&self.hits);
+            let _ = writeln!(
+                out,
+                "{:>7} {:>6} {:>6} {:>6}  {:06X}  {}",
+                if l.is_instruction() { hits.to_string() } else { show(hits) },
+                show(count(&self.reads)),
+                show(count(&self.writes)),
+                l.line_no,
+                l.mem_loc.wrapping_add(offset),
+                text
+            );
+        }
+        out.lines().map(|l| l.trim_end().to_owned() + "\n").collect()
+    }
+}
+
+#[test]
+fn profile_counts() {
+    let source = "\
+MAIN  START 0
+      STL   RET
+      LDA   #2
+      JSUB  TWICE
+      JSUB  TWICE
+      TD    DEV
+      LDL   RET
+      RSUB
+. doubles A
+TWICE ADD   VALUE
+      STA   VALUE
+      RSUB
+VALUE WORD  1
+RET   RESW  1
+DEV   BYTE  X'05'
+      END   MAIN
+";
+    let result = super::Assembler::new().assemble("main.asm", source);
+    let mut m = super::sim::Machine::new();
+    m.load(&result.program, 0).unwrap();
+    let mut profile = Profile::new();
+    m.run(100, |_, s| profile.record(s));
+
+    assert_eq!(profile.instructions, 13);
+    assert_eq!(profile.hits[&0x15], 2);
+    assert_eq!(profile.subroutines[&0], Counts { calls: 1, instructions: 7, total: 13, cycles: 28 });
+    assert_eq!(profile.subroutines[&0x15], Counts { calls: 2, instructions: 6, total: 6, cycles: 30 });
+    assert_eq!(profile.devices[&5], DeviceCounts { tests: 1, reads: 0, writes: 0 });
+
+    let labels = super::trace::labels(&result.symbols, 0);
+    let summary = profile.summary(&labels);
+    assert!(summary.contains("Cycles        58\n"));
+    assert!(summary.contains("MAIN            1             7       13       28\n"));
+    assert!(summary.contains("TWICE           2             6        6       30\n"));
+
+    let listing = profile.annotate(&result, 0);
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[4], "      1                    4  000006        JSUB  TWICE");
+    assert_eq!(lines[9], "                           9          . doubles A");
+    assert_eq!(lines[13], "             2      2     13  00001E  VALUE WORD  1");
+    assert_eq!(lines[14], "             1      1     14  000021  RET   RESW  1");
+}
@@ -0,0 +1,1247 @@
+//! A simulator for SIC/XE programs.
+//!
+//! A `Machine` has 1 MiB of memory, the registers A, X, L, B, S, T, F, PC and
+//! SW, and byte-wide devices. It runs one instruction per `step`, and each
+//! step returns a `Step` telling what the instruction did: the registers it
+//! changed and the memory it read and wrote, with the values before and after.
+//!
+//! Programs are started in supervisor mode with register L holding
+//! `EXIT_ADDRESS`, so the RSUB that ends the main routine stops the run, as
+//! does a jump to itself (`HALT J HALT`).
+//!
+//! The machine also models the SIC/XE interrupt system. There are four
+//! classes of interrupt: SVC, program, timer and I/O. Taking one stores SW,
+//! PC and the other registers in the work area of its class, at 100h, 130h,
+//! 160h or 190h, and loads SW and PC from the first six bytes there:
+//!
+//! ```text
+//! +00 new SW   +03 new PC   +06 SW   +09 PC   +0C A   +0F X
+//! +12 L        +15 B        +18 S    +1B T    +1E F (6 bytes)
+//! ```
+//!
+//! The stored SW holds the interrupt code: the SVC number, the cause of a
+//! program interrupt or the channel of an I/O interrupt. `LPS` loads the
+//! registers from the same layout, so `LPS 106h` resumes a process after an
+//! SVC. Timer and I/O interrupts wait while their bit of the SW mask is 0, as
+//! does a processor in the idle state.
+//!
+//! In user mode, the privileged instructions (`LPS`, `STI`, `SSK`, `SIO`,
+//! `TIO`, `HIO`, `RD`, `WD` and `TD`) and faults cause program interrupts, as
+//! do writes to a 2 KiB block whose storage key is neither 0 nor the process
+//! ID in SW. The interval timer set by `STI` counts instructions run in user
+//! mode, and I/O started by `SIO` completes `IO_TIME` instructions later;
+//! channel programs are not run.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+use super::image;
+use super::pass_two::ObjectProgram;
+
+/// The number of bytes of memory.
+pub const MEMORY_SIZE: usize = 1 << 20;
+
+/// The address in register L when a program starts. Returning to it ends the
+/// run.
+pub const EXIT_ADDRESS: u32 = 0xFFFFFF;
+
+/// The condition code bits of SW.
+const CC_MASK: u32 = 0x030000;
+
+/// The bits of SW: supervisor mode, the idle state, the process ID, the
+/// interrupt mask and the interrupt code.
+pub const MODE: u32 = 0x800000;
+pub const IDLE: u32 = 0x400000;
+pub const ID_MASK: u32 = 0x3C0000;
+pub const INTERRUPT_MASK: u32 = 0x00F000;
+pub const ICODE_MASK: u32 = 0x0000FF;
+
+/// The size of the memory blocks storage keys protect.
+pub const KEY_BLOCK: u32 = 0x800;
+
+/// The number of instructions it takes I/O started by SIO to complete.
+pub const IO_TIME: u32 = 16;
+
+/// The interrupt codes of program interrupts.
+pub const ILLEGAL_INSTRUCTION: u8 = 0x00;
+pub const PRIVILEGED_INSTRUCTION: u8 = 0x01;
+pub const ADDRESS_OUT_OF_RANGE: u8 = 0x02;
+pub const PROTECTION_VIOLATION: u8 = 0x03;
+pub const ARITHMETIC_OVERFLOW: u8 = 0x04;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum Register {
+    A,
+    X,
+    L,
+    B,
+    S,
+    T,
+    F,
+    PC,
+    SW
+}
+
+impl Register {
+    /// Every register, in the order of their numbers.
+    pub const ALL: [Register; 9] = [
+        Register::A, Register::X, Register::L, Register::B, Register::S,
+        Register::T, Register::F, Register::PC, Register::SW
+    ];
+
+    /// The register numbered `n` in format 2 instructions.
+    pub fn from_number(n: u8) -> Option<Register> {
+        match n {
+            0..=6 => Some(Register::ALL[n as usize]),
+            8 => Some(Register::PC),
+            9 => Some(Register::SW),
+            _ => None
+        }
+    }
+
+    pub fn number(self) -> u8 {
+        match self {
+            Register::PC => 8,
+            Register::SW => 9,
+            r => Register::ALL.iter().position(|x| *x == r).unwrap_or(0) as u8
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        ["A", "X", "L", "B", "S", "T", "F", "PC", "SW"][Register::ALL.iter().position(|x| *x == self).unwrap_or(0)]
+    }
+
+    /// The register called `name`, in any case.
+    pub fn from_name(name: &str) -> Option<Register> {
+        Register::ALL.iter().find(|r| r.name().eq_ignore_ascii_case(name)).cloned()
+    }
+}
+
+/// The condition code, set by comparisons and TD.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Cc {
+    Lt,
+    Eq,
+    Gt
+}
+
+impl Cc {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Cc::Lt => "<",
+            Cc::Eq => "=",
+            Cc::Gt => ">"
+        }
+    }
+
+    fn bits(self) -> u32 {
+        match self {
+            Cc::Lt => 0x000000,
+            Cc::Eq => 0x010000,
+            Cc::Gt => 0x020000
+        }
+    }
+
+    fn from_bits(sw: u32) -> Cc {
+        match sw & CC_MASK {
+            0x000000 => Cc::Lt,
+            0x010000 => Cc::Eq,
+            _ => Cc::Gt
+        }
+    }
+}
+
+/// A byte-wide device: RD takes bytes from `input`, and WD appends them to
+/// `output`. Devices are always ready.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Device {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>
+}
+
+/// An interrupt, with its interrupt code.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Interrupt {
+    /// Class I, from SVC n.
+    Svc(u8),
+    /// Class II, with one of the program interrupt codes.
+    Program(u8),
+    /// Class III, when the interval timer runs out.
+    Timer,
+    /// Class IV, when I/O on a channel completes.
+    Io(u8)
+}
+
+impl Interrupt {
+    fn class(self) -> u32 {
+        match self {
+            Interrupt::Svc(_) => 0,
+            Interrupt::Program(_) => 1,
+            Interrupt::Timer => 2,
+            Interrupt::Io(_) => 3
+        }
+    }
+
+    /// The address of the work area of the interrupt's class.
+    pub fn work_area(self) -> u32 {
+        0x100 + 0x30 * self.class()
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Interrupt::Svc(n) | Interrupt::Program(n) | Interrupt::Io(n) => n,
+            Interrupt::Timer => 0
+        }
+    }
+
+    // The bit of the SW mask that allows the interrupt.
+    fn mask(self) -> u32 {
+        0x8000 >> self.class()
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Interrupt::Svc(n) => write!(f, "SVC {}", n),
+            Interrupt::Program(code) => write!(f, "program interrupt {:02X}", code),
+            Interrupt::Timer => write!(f, "timer interrupt"),
+            Interrupt::Io(ch) => write!(f, "I/O interrupt from channel {}", ch)
+        }
+    }
+}
+
+/// The state of the timer and channels, and the interrupts waiting to be
+/// taken.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Interrupts {
+    /// The instructions left to run in user mode before a timer interrupt,
+    /// or 0 if the timer is not running.
+    pub timer: u32,
+    /// The channels running I/O, with the instructions left until it
+    /// completes.
+    pub channels: BTreeMap<u8, u32>,
+    /// Timer and I/O interrupts that happened while masked, oldest first.
+    pub pending: Vec<Interrupt>
+}
+
+/// Why an instruction could not be executed.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Fault {
+    InvalidOpcode(u8),
+    /// An address outside memory.
+    Address(u32),
+    InvalidRegister(u8),
+    /// Both the b and p flags, or an immediate operand for an instruction
+    /// that stores or jumps to an address.
+    InvalidAddressing,
+    DivideByZero,
+    /// An instruction the simulator does not implement.
+    Unsupported(&'static str),
+    /// A privileged instruction in user mode.
+    Privileged(&'static str),
+    /// A write, in user mode, to memory protected by a storage key.
+    Protection(u32),
+    /// The processor is idle and no interrupt can come to wake it.
+    Idle
+}
+
+impl Fault {
+    // The interrupt code of the program interrupt the fault causes in user
+    // mode.
+    fn code(&self) -> u8 {
+        match self {
+            Fault::Address(_) => ADDRESS_OUT_OF_RANGE,
+            Fault::DivideByZero => ARITHMETIC_OVERFLOW,
+            Fault::Privileged(_) => PRIVILEGED_INSTRUCTION,
+            Fault::Protection(_) => PROTECTION_VIOLATION,
+            _ => ILLEGAL_INSTRUCTION
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::InvalidOpcode(op) => write!(f, "invalid opcode {:02X}", op),
+            Fault::Address(a) => write!(f, "address {:06X} is outside memory", a),
+            Fault::InvalidRegister(r) => write!(f, "invalid register number {}", r),
+            Fault::InvalidAddressing => write!(f, "invalid addressing mode"),
+            Fault::DivideByZero => write!(f, "division by zero"),
+            Fault::Unsupported(name) => write!(f, "{} is not supported by the simulator", name),
+            Fault::Privileged(name) => write!(f, "{} is privileged", name),
+            Fault::Protection(a) => write!(f, "address {:06X} is protected by a storage key", a),
+            Fault::Idle => write!(f, "the processor is idle with no interrupt to come")
+        }
+    }
+}
+
+/// Why a run stopped.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Stop {
+    /// The main routine returned to `EXIT_ADDRESS`.
+    Exited,
+    /// An instruction jumped to itself.
+    Halted,
+    /// The run reached its step limit.
+    StepLimit,
+    /// An instruction could not be executed; PC is left pointing at it.
+    Fault(u32, Fault)
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Exited => write!(f, "the program returned"),
+            Stop::Halted => write!(f, "the program halted"),
+            Stop::StepLimit => write!(f, "the step limit was reached"),
+            Stop::Fault(at, e) => write!(f, "{} at {:06X}", e, at)
+        }
+    }
+}
+
+/// How a format 3 or 4 instruction finds its operand.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Mode {
+    /// n=1 i=1, or the SIC format with n=0 i=0.
+    Simple,
+    Immediate,
+    Indirect
+}
+
+/// The operand of a decoded instruction.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Operand {
+    None,
+    /// The two register fields of a format 2 instruction.
+    Registers(u8, u8),
+    Memory {
+        mode: Mode,
+        /// The address or value before indexing and indirection.
+        address: u32,
+        indexed: bool,
+        extended: bool
+    }
+}
+
+/// A decoded instruction.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub name: &'static str,
+    pub format: u8,
+    pub operand: Operand
+}
+
+impl Instruction {
+    /// The length of the instruction in bytes.
+    pub fn size(&self) -> u32 {
+        match (self.format, &self.operand) {
+            (3, Operand::Memory { extended: true, .. }) => 4,
+            (f, _) => f as u32
+        }
+    }
+
+    /// The instruction in assembler syntax, with `name_of` giving a name to
+    /// show for an address, if it has one. Immediate operands are shown as
+    /// numbers.
+    pub fn disassemble(&self, name_of: &dyn Fn(u32) -> Option<String>) -> String {
+        match self.operand {
+            Operand::None => self.name.to_owned(),
+            // RSUB is written without an operand, which it ignores.
+            Operand::Memory { extended, .. } if self.opcode == 0x4C => format!("{}{}", if extended { "+" } else { "" }, self.name),
+            Operand::Registers(r1, r2) => {
+                let reg = |r: u8| Register::from_number(r).map_or(r.to_string(), |r| r.name().to_owned());
+                match self.name {
+                    "CLEAR" | "TIXR" => format!("{} {}", self.name, reg(r1)),
+                    "SVC" => format!("{} {}", self.name, r1),
+                    "SHIFTL" | "SHIFTR" => format!("{} {},{}", self.name, reg(r1), r2 + 1),
+                    _ => format!("{} {},{}", self.name, reg(r1), reg(r2))
+                }
+            }
+            Operand::Memory { mode, address, indexed, extended } => {
+                let prefix = match mode {
+                    Mode::Simple => "",
+                    Mode::Immediate => "#",
+                    Mode::Indirect => "@"
+                };
+                let operand = match name_of(address) {
+                    _ if mode == Mode::Immediate => address.to_string(),
+                    Some(name) => name,
+                    None => format!("{:06X}", address)
+                };
+                format!(
+                    "{}{} {}{}{}",
+                    if extended { "+" } else { "" },
+                    self.name,
+                    prefix,
+                    operand,
+                    if indexed { ",X" } else { "" }
+                )
+            }
+        }
+    }
+}
+
+/// A device operation.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Io {
+    Test(u8),
+    Read(u8, u8),
+    /// RD from a device with no input left, which reads 0.
+    ReadPastEnd(u8),
+    Write(u8, u8)
+}
+
+/// What one instruction did.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Step {
+    /// The address of the instruction.
+    pub address: u32,
+    pub instruction: Instruction,
+    /// The address of the operand after indexing and indirection, for
+    /// instructions with a memory operand that is not immediate.
+    pub target: Option<u32>,
+    /// Registers that changed, with their values before and after.
+    pub registers: Vec<(Register, u64, u64)>,
+    /// Memory read, as an address and a number of bytes, including the
+    /// instruction itself.
+    pub reads: Vec<(u32, usize)>,
+    /// Memory written: the address, the bytes before and the bytes after.
+    pub writes: Vec<(u32, Vec<u8>, Vec<u8>)>,
+    pub io: Option<Io>,
+    /// The condition code after the instruction.
+    pub cc: Cc,
+    /// The interrupt taken: before the instruction for timer and I/O
+    /// interrupts, in which case the instruction is the first of the
+    /// handler, or by the instruction for SVC and program interrupts.
+    pub interrupt: Option<Interrupt>,
+    /// The timer, channels and pending interrupts before the step.
+    pub interrupts: Interrupts,
+    /// A storage key set by SSK: the block's address, the old key and the
+    /// new.
+    pub key: Option<(u32, u8, u8)>
+}
+
+impl Step {
+    /// True if the instruction was JSUB.
+    pub fn is_call(&self) -> bool {
+        self.instruction.opcode == 0x48
+    }
+
+    /// True if the instruction was RSUB.
+    pub fn is_return(&self) -> bool {
+        self.instruction.opcode == 0x4C
+    }
+
+    /// True for the conditional jumps, JEQ, JGT and JLT.
+    pub fn is_branch(&self) -> bool {
+        matches!(self.instruction.opcode, 0x30 | 0x34 | 0x38)
+    }
+}
+
+// The instructions the simulator executes: opcode, name and format.
+const OPCODES: &[(u8, &str, u8)] = &[
+    (0x18, "ADD", 3), (0x90, "ADDR", 2), (0x40, "AND", 3), (0xB4, "CLEAR", 2),
+    (0x28, "COMP", 3), (0xA0, "COMPR", 2), (0x24, "DIV", 3), (0x9C, "DIVR", 2),
+    (0xF4, "HIO", 1), (0x3C, "J", 3), (0x30, "JEQ", 3), (0x34, "JGT", 3),
+    (0x38, "JLT", 3), (0x48, "JSUB", 3), (0x00, "LDA", 3), (0x68, "LDB", 3),
+    (0x50, "LDCH", 3), (0x08, "LDL", 3), (0x6C, "LDS", 3), (0x74, "LDT", 3),
+    (0x04, "LDX", 3), (0xD0, "LPS", 3), (0x20, "MUL", 3), (0x98, "MULR", 2),
+    (0x44, "OR", 3), (0xD8, "RD", 3), (0xAC, "RMO", 2), (0x4C, "RSUB", 3),
+    (0xA4, "SHIFTL", 2), (0xA8, "SHIFTR", 2), (0xF0, "SIO", 1), (0xEC, "SSK", 3),
+    (0x0C, "STA", 3), (0x78, "STB", 3), (0x54, "STCH", 3), (0xD4, "STI", 3),
+    (0x14, "STL", 3), (0x7C, "STS", 3), (0xE8, "STSW", 3), (0x84, "STT", 3),
+    (0x10, "STX", 3), (0x1C, "SUB", 3), (0x94, "SUBR", 2), (0xB0, "SVC", 2),
+    (0xE0, "TD", 3), (0xF8, "TIO", 1), (0x2C, "TIX", 3), (0xB8, "TIXR", 2),
+    (0xDC, "WD", 3)
+];
+
+// A 24-bit word as a signed number.
+fn signed(x: u32) -> i32 {
+    ((x << 8) as i32) >> 8
+}
+
+const WORD: u32 = 0x00FFFFFF;
+
+/// A SIC/XE machine.
+#[derive(Debug, Clone)]
+pub struct Machine {
+    memory: Vec<u8>,
+    // Indexed by register number; F is kept as its 48 bits.
+    registers: [u64; 10],
+    // The storage key of each block of memory.
+    keys: Vec<u8>,
+    pub devices: BTreeMap<u8, Device>,
+    pub interrupts: Interrupts
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    /// A machine in supervisor mode, with every interrupt masked, zeroed
+    /// memory and other registers, and no devices.
+    pub fn new() -> Machine {
+        let mut m = Machine {
+            memory: vec![0; MEMORY_SIZE],
+            registers: [0; 10],
+            keys: vec![0; MEMORY_SIZE / KEY_BLOCK as usize],
+            devices: BTreeMap::new(),
+            interrupts: Interrupts::default()
+        };
+        m.set_register(Register::SW, MODE as u64);
+        m
+    }
+
+    /// Loads `program` at `at`, relocating it, and gets ready to run it from
+    /// its entry point.
+    pub fn load(&mut self, program: &ObjectProgram, at: u32) -> Result<(), Fault> {
+        for (addr, bytes) in image::load(program, at) {
+            let end = addr as usize + bytes.len();
+            if end > MEMORY_SIZE {
+                return Err(Fault::Address(end as u32 - 1));
+            }
+            self.memory[addr as usize..end].copy_from_slice(&bytes);
+        }
+        self.set_register(Register::PC, program.entry.wrapping_sub(program.start).wrapping_add(at) as u64);
+        self.set_register(Register::L, EXIT_ADDRESS as u64);
+        Ok(())
+    }
+
+    pub fn register(&self, r: Register) -> u64 {
+        self.registers[r.number() as usize]
+    }
+
+    /// Sets a register, keeping 24 bits, or 48 for F.
+    pub fn set_register(&mut self, r: Register, v: u64) {
+        let mask = if r == Register::F { 0xFFFF_FFFF_FFFF } else { WORD as u64 };
+        self.registers[r.number() as usize] = v & mask;
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.register(Register::PC) as u32
+    }
+
+    pub fn cc(&self) -> Cc {
+        Cc::from_bits(self.register(Register::SW) as u32)
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Writes `bytes` at `addr`, as a debugger would.
+    pub fn write_memory(&mut self, addr: u32, bytes: &[u8]) -> Result<(), Fault> {
+        let end = addr as usize + bytes.len();
+        if end > MEMORY_SIZE {
+            return Err(Fault::Address(addr));
+        }
+        self.memory[addr as usize..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// The storage key of the block holding `addr`.
+    pub fn key(&self, addr: u32) -> u8 {
+        self.keys.get((addr / KEY_BLOCK) as usize).cloned().unwrap_or(0)
+    }
+
+    /// Sets the storage key of the block holding `addr`.
+    pub fn set_key(&mut self, addr: u32, key: u8) {
+        if let Some(k) = self.keys.get_mut((addr / KEY_BLOCK) as usize) {
+            *k = key & 0x0F;
+        }
+    }
+
+    /// True in supervisor mode.
+    pub fn supervisor(&self) -> bool {
+        self.register(Register::SW) as u32 & MODE != 0
+    }
+
+    /// The device numbered `n`, added if it does not exist yet.
+    pub fn device(&mut self, n: u8) -> &mut Device {
+        self.devices.entry(n).or_default()
+    }
+
+    fn fetch(&self, addr: u32, n: usize) -> Result<&[u8], Fault> {
+        self.memory.get(addr as usize..addr as usize + n).ok_or(Fault::Address(addr))
+    }
+
+    /// Decodes the instruction at `addr` without running it.
+    pub fn decode(&self, addr: u32) -> Result<Instruction, Fault> {
+        let b0 = self.fetch(addr, 1)?[0];
+        let opcode = b0 & 0xFC;
+        let &(_, name, format) = OPCODES.iter().find(|o| o.0 == opcode).ok_or(Fault::InvalidOpcode(opcode))?;
+        let operand = match format {
+            1 => Operand::None,
+            2 => {
+                let b1 = self.fetch(addr, 2)?[1];
+                Operand::Registers(b1 >> 4, b1 & 0x0F)
+            }
+            _ => {
+                let b = self.fetch(addr, 3)?;
+                let (b1, b2) = (b[1] as u32, b[2] as u32);
+                let indexed = b1 & 0x80 != 0;
+                if b0 & 0x03 == 0 {
+                    // The SIC format: a 15-bit address.
+                    Operand::Memory { mode: Mode::Simple, address: (b1 & 0x7F) << 8 | b2, indexed, extended: false }
+                } else {
+                    let mode = match b0 & 0x03 {
+                        1 => Mode::Immediate,
+                        2 => Mode::Indirect,
+                        _ => Mode::Simple
+                    };
+                    let (base, pc, extended) = (b1 & 0x40 != 0, b1 & 0x20 != 0, b1 & 0x10 != 0);
+                    let address = if extended {
+                        (b1 & 0x0F) << 16 | b2 << 8 | self.fetch(addr, 4)?[3] as u32
+                    } else {
+                        let disp = (b1 & 0x0F) << 8 | b2;
+                        match (base, pc) {
+                            (true, true) => return Err(Fault::InvalidAddressing),
+                            (false, true) => (addr + 3 + if disp & 0x800 != 0 { disp | 0xFFF000 } else { disp }) & WORD,
+                            (true, false) => (self.register(Register::B) as u32 + disp) & WORD,
+                            (false, false) => disp
+                        }
+                    };
+                    Operand::Memory { mode, address, indexed, extended }
+                }
+            }
+        };
+        Ok(Instruction { opcode, name, format, operand })
+    }
+
+    // The first pending interrupt the mask allows.
+    fn allowed(&self) -> Option<usize> {
+        let sw = self.register(Register::SW) as u32;
+        let mut pending: Vec<(usize, &Interrupt)> = self.interrupts.pending.iter().enumerate().collect();
+        pending.sort_by_key(|(k, i)| (i.class(), *k));
+        pending.into_iter().find(|(_, i)| sw & i.mask() != 0).map(|(k, _)| k)
+    }
+
+    // Moves time on by `n` instructions: the timer, if in user mode, and the
+    // channels.
+    fn tick(&mut self, n: u32, user: bool) {
+        let ints = &mut self.interrupts;
+        if user && ints.timer > 0 {
+            ints.timer = ints.timer.saturating_sub(n);
+            if ints.timer == 0 {
+                ints.pending.push(Interrupt::Timer);
+            }
+        }
+        for (ch, left) in ints.channels.iter_mut() {
+            *left = left.saturating_sub(n);
+            if *left == 0 {
+                ints.pending.push(Interrupt::Io(*ch));
+            }
+        }
+        ints.channels.retain(|_, left| *left > 0);
+    }
+
+    /// Takes an allowed pending interrupt, if there is one, then runs the
+    /// instruction at PC. An idle processor waits for the channels until an
+    /// interrupt it allows comes.
+    pub fn step(&mut self) -> Result<Step, Fault> {
+        let before = self.interrupts.clone();
+        if self.register(Register::SW) as u32 & IDLE != 0 {
+            while self.allowed().is_none() {
+                match self.interrupts.channels.values().min() {
+                    Some(&n) => self.tick(n, false),
+                    None => {
+                        self.interrupts = before;
+                        return Err(Fault::Idle);
+                    }
+                }
+            }
+        }
+        let mut run = Run { machine: self, registers: Vec::new(), reads: Vec::new(), writes: Vec::new(), io: None, key: None, raised: None, taken: None };
+        let taken = run.machine.allowed().map(|k| run.machine.interrupts.pending.remove(k));
+        if let Some(i) = taken {
+            let pc = run.get(Register::PC);
+            run.interrupt(i, pc);
+        }
+
+        let address = run.machine.pc();
+        let user = !run.machine.supervisor();
+        let decoded = run.machine.decode(address);
+        let instruction = match decoded {
+            Ok(ref i) => i.clone(),
+            Err(_) => Instruction { opcode: run.machine.memory.get(address as usize).map_or(0, |b| b & 0xFC), name: "?", format: 1, operand: Operand::None }
+        };
+        run.reads.push((address, instruction.size() as usize));
+        let next = address + instruction.size();
+        run.set(Register::PC, next as u64);
+        let executed = decoded.and_then(|i| run.execute(&i));
+        let target = match executed {
+            Ok(target) => target,
+            Err(fault) if user => {
+                // The instruction is undone and replaced by the interrupt.
+                run.rollback(false);
+                run.raised = Some(Interrupt::Program(fault.code()));
+                run.set(Register::PC, address as u64);
+                None
+            }
+            Err(fault) => {
+                run.rollback(true);
+                run.machine.interrupts = before;
+                return Err(fault);
+            }
+        };
+        if let Some(i) = run.raised {
+            let pc = run.get(Register::PC);
+            run.interrupt(i, pc);
+        }
+        let interrupt = run.raised.or(taken);
+        let (registers, reads, writes, io, key) = (run.registers, run.reads, run.writes, run.io, run.key);
+        self.tick(1, user);
+        Ok(Step { address, instruction, target, registers, reads, writes, io, cc: self.cc(), interrupt, interrupts: before, key })
+    }
+
+    /// Undoes `step`, the last step run, putting back the registers, memory
+    /// and storage key it changed, the byte it read from a device or the byte
+    /// it wrote, and the state of the timer, channels and pending interrupts.
+    pub fn undo(&mut self, step: &Step) {
+        self.interrupts = step.interrupts.clone();
+        if let Some((block, old, _)) = step.key {
+            self.set_key(block, old);
+        }
+        match step.io {
+            Some(Io::Read(dev, b)) => self.device(dev).input.push_front(b),
+            Some(Io::Write(dev, _)) => {
+                self.device(dev).output.pop();
+            }
+            _ => {}
+        }
+        for (r, old, _) in step.registers.iter().rev() {
+            self.set_register(*r, *old);
+        }
+        for (addr, old, _) in step.writes.iter().rev() {
+            let _ = self.write_memory(*addr, old);
+        }
+    }
+
+    /// Runs until the program stops or `max_steps` instructions have run,
+    /// calling `each` after every instruction.
+    pub fn run(&mut self, max_steps: u64, mut each: impl FnMut(&Machine, &Step)) -> Stop {
+        for _ in 0..max_steps {
+            let at = self.pc();
+            match self.step() {
+                Ok(step) => {
+                    each(self, &step);
+                    if self.pc() == EXIT_ADDRESS {
+                        return Stop::Exited;
+                    }
+                    if self.pc() == at {
+                        return Stop::Halted;
+                    }
+                }
+                Err(e) => {
+                    self.set_register(Register::PC, at as u64);
+                    return Stop::Fault(at, e);
+                }
+            }
+        }
+        Stop::StepLimit
+    }
+}
+
+// One instruction in progress, recording what it changes.
+struct Run<'a> {
+    machine: &'a mut Machine,
+    registers: Vec<(Register, u64, u64)>,
+    reads: Vec<(u32, usize)>,
+    writes: Vec<(u32, Vec<u8>, Vec<u8>)>,
+    io: Option<Io>,
+    key: Option<(u32, u8, u8)>,
+    // An SVC or program interrupt caused by the instruction.
+    raised: Option<Interrupt>,
+    // The registers and the number of writes after taking an interrupt
+    // before the instruction.
+    taken: Option<([u64; 10], usize)>
+}
+
+impl Run<'_> {
+    fn get(&self, r: Register) -> u32 {
+        self.machine.register(r) as u32
+    }
+
+    fn set(&mut self, r: Register, v: u64) {
+        let old = self.machine.register(r);
+        self.machine.set_register(r, v);
+        let new = self.machine.register(r);
+        match self.registers.iter_mut().find(|c| c.0 == r) {
+            Some(c) => c.2 = new,
+            None if old != new => self.registers.push((r, old, new)),
+            None => {}
+        }
+        self.registers.retain(|c| c.1 != c.2);
+    }
+
+    fn set_cc(&mut self, cc: Cc) {
+        let sw = self.get(Register::SW) & !CC_MASK | cc.bits();
+        self.set(Register::SW, sw as u64);
+    }
+
+    fn compare(&mut self, a: u32, b: u32) {
+        let cc = match signed(a).cmp(&signed(b)) {
+            std::cmp::Ordering::Less => Cc::Lt,
+            std::cmp::Ordering::Equal => Cc::Eq,
+            std::cmp::Ordering::Greater => Cc::Gt
+        };
+        self.set_cc(cc);
+    }
+
+    fn read(&mut self, addr: u32, n: usize) -> Result<u32, Fault> {
+        let bytes = self.machine.fetch(addr, n)?;
+        let v = bytes.iter().fold(0u32, |v, b| v << 8 | *b as u32);
+        self.reads.push((addr, n));
+        Ok(v)
+    }
+
+    fn write(&mut self, addr: u32, n: usize, v: u32) -> Result<(), Fault> {
+        let sw = self.get(Register::SW);
+        if sw & MODE == 0 {
+            let id = ((sw & ID_MASK) >> 18) as u8;
+            if let Some(a) = (addr..addr + n as u32).find(|a| ![0, id].contains(&self.machine.key(*a))) {
+                return Err(Fault::Protection(a));
+            }
+        }
+        self.put(addr, n, v)
+    }
+
+    // Writes without checking storage keys.
+    fn put(&mut self, addr: u32, n: usize, v: u32) -> Result<(), Fault> {
+        let old = self.machine.fetch(addr, n)?.to_vec();
+        let new: Vec<u8> = (0..n).map(|k| (v >> (8 * (n - 1 - k))) as u8).collect();
+        self.machine.memory[addr as usize..addr as usize + n].copy_from_slice(&new);
+        self.writes.push((addr, old, new));
+        Ok(())
+    }
+
+    // Puts back what the instruction changed so far, and with `all`, the
+    // interrupt taken before it too.
+    fn rollback(&mut self, all: bool) {
+        let (registers, keep_writes) = match self.taken {
+            Some(t) if !all => t,
+            _ => {
+                let mut registers = self.machine.registers;
+                for (r, old, _) in &self.registers {
+                    registers[r.number() as usize] = *old;
+                }
+                (registers, 0)
+            }
+        };
+        self.machine.registers = registers;
+        for c in self.registers.iter_mut() {
+            c.2 = self.machine.register(c.0);
+        }
+        self.registers.retain(|c| c.1 != c.2);
+        while self.writes.len() > keep_writes {
+            let (addr, old, _) = self.writes.pop().unwrap();
+            self.machine.memory[addr as usize..addr as usize + old.len()].copy_from_slice(&old);
+        }
+        match self.io.take() {
+            Some(Io::Read(dev, b)) => self.machine.device(dev).input.push_front(b),
+            Some(Io::Write(dev, _)) => {
+                self.machine.device(dev).output.pop();
+            }
+            _ => {}
+        }
+        if let Some((block, old, _)) = self.key.take() {
+            self.machine.set_key(block, old);
+        }
+        self.reads.truncate(1);
+        self.raised = None;
+    }
+
+    // Takes interrupt `i`, saving `pc` as the address to resume at.
+    fn interrupt(&mut self, i: Interrupt, pc: u32) {
+        use self::Register::*;
+        let area = i.work_area();
+        let sw = self.get(SW) & !ICODE_MASK | i.code() as u32;
+        let saved = [(6, sw), (9, pc), (0x0C, self.get(A)), (0x0F, self.get(X)), (0x12, self.get(L)),
+            (0x15, self.get(B)), (0x18, self.get(S)), (0x1B, self.get(T))];
+        for (offset, v) in saved.iter() {
+            let _ = self.put(area + offset, 3, *v);
+        }
+        let f = self.machine.register(F);
+        let _ = self.put(area + 0x1E, 3, (f >> 24) as u32);
+        let _ = self.put(area + 0x21, 3, f as u32 & WORD);
+        let word = |m: &Machine, a: u32| m.memory[a as usize..a as usize + 3].iter().fold(0u32, |v, b| v << 8 | *b as u32);
+        let (new_sw, new_pc) = (word(self.machine, area), word(self.machine, area + 3));
+        self.set(SW, new_sw as u64);
+        self.set(PC, new_pc as u64);
+        self.taken = Some((self.machine.registers, self.writes.len()));
+    }
+
+    fn privileged(&self, ins: &Instruction) -> Result<(), Fault> {
+        if self.machine.supervisor() { Ok(()) } else { Err(Fault::Privileged(ins.name)) }
+    }
+
+    fn register_field(r: u8) -> Result<Register, Fault> {
+        Register::from_number(r).ok_or(Fault::InvalidRegister(r))
+    }
+
+    // Executes `ins`, returning its target address.
+    fn execute(&mut self, ins: &Instruction) -> Result<Option<u32>, Fault> {
+        use self::Register::*;
+
+        let (r1, r2) = match ins.operand {
+            Operand::Registers(r1, r2) => (r1, r2),
+            _ => (0, 0)
+        };
+        let (mode, target) = match ins.operand {
+            Operand::Memory { mode, address, indexed, .. } if ins.opcode != 0x4C => {
+                let mut target = if indexed { (address + self.get(X)) & WORD } else { address };
+                if mode == Mode::Indirect {
+                    target = self.read(target, 3)?;
+                }
+                (Some(mode), Some(target))
+            }
+            _ => (None, None)
+        };
+        let immediate = mode == Some(Mode::Immediate);
+        // The operand: the target itself when immediate, or `n` bytes there.
+        let value = |run: &mut Run, n: usize| -> Result<u32, Fault> {
+            let t = target.unwrap_or(0);
+            if immediate { Ok(t & if n == 1 { 0xFF } else { WORD }) } else { run.read(t, n) }
+        };
+        let store = |run: &mut Run, n: usize, v: u32| -> Result<(), Fault> {
+            if immediate {
+                return Err(Fault::InvalidAddressing);
+            }
+            run.write(target.unwrap_or(0), n, v)
+        };
+        let jump = |run: &mut Run| {
+            run.set(PC, target.unwrap_or(0) as u64);
+        };
+        let arith = |run: &mut Run, r: Register, f: &dyn Fn(i32, i32) -> Option<i32>, v: u32| -> Result<(), Fault> {
+            let x = f(signed(run.get(r)), signed(v)).ok_or(Fault::DivideByZero)?;
+            run.set(r, x as u32 as u64);
+            Ok(())
+        };
+        let add = |a: i32, b: i32| Some(a.wrapping_add(b));
+        let sub = |a: i32, b: i32| Some(a.wrapping_sub(b));
+        let mul = |a: i32, b: i32| Some(a.wrapping_mul(b));
+        let div = |a: i32, b: i32| if b == 0 { None } else { Some(a.wrapping_div(b)) };
+
+        match ins.opcode {
+            0x00 => { let v = value(self, 3)?; self.set(A, v as u64) }
+            0x04 => { let v = value(self, 3)?; self.set(X, v as u64) }
+            0x08 => { let v = value(self, 3)?; self.set(L, v as u64) }
+            0x68 => { let v = value(self, 3)?; self.set(B, v as u64) }
+            0x6C => { let v = value(self, 3)?; self.set(S, v as u64) }
+            0x74 => { let v = value(self, 3)?; self.set(T, v as u64) }
+            0x50 => {
+                let v = value(self, 1)?;
+                let a = self.get(A) & 0xFFFF00 | v;
+                self.set(A, a as u64);
+            }
+            0x0C => { let v = self.get(A); store(self, 3, v)? }
+            0x10 => { let v = self.get(X); store(self, 3, v)? }
+            0x14 => { let v = self.get(L); store(self, 3, v)? }
+            0x78 => { let v = self.get(B); store(self, 3, v)? }
+            0x7C => { let v = self.get(S); store(self, 3, v)? }
+            0x84 => { let v = self.get(T); store(self, 3, v)? }
+            0xE8 => { let v = self.get(SW); store(self, 3, v)? }
+            0x54 => { let v = self.get(A) & 0xFF; store(self, 1, v)? }
+            0x18 => { let v = value(self, 3)?; arith(self, A, &add, v)? }
+            0x1C => { let v = value(self, 3)?; arith(self, A, &sub, v)? }
+            0x20 => { let v = value(self, 3)?; arith(self, A, &mul, v)? }
+            0x24 => { let v = value(self, 3)?; arith(self, A, &div, v)? }
+            0x40 => { let v = value(self, 3)?; let a = self.get(A) & v; self.set(A, a as u64) }
+            0x44 => { let v = value(self, 3)?; let a = self.get(A) | v; self.set(A, a as u64) }
+            0x28 => { let v = value(self, 3)?; let a = self.get(A); self.compare(a, v) }
+            0x2C => {
+                let x = (self.get(X) + 1) & WORD;
+                self.set(X, x as u64);
+                let v = value(self, 3)?;
+                self.compare(x, v);
+            }
+            0x3C => jump(self),
+            0x30 => if self.machine.cc() == Cc::Eq { jump(self) },
+            0x34 => if self.machine.cc() == Cc::Gt { jump(self) },
+            0x38 => if self.machine.cc() == Cc::Lt { jump(self) },
+            0x48 => {
+                let pc = self.get(PC);
+                self.set(L, pc as u64);
+                jump(self);
+            }
+            0x4C => { let l = self.get(L); self.set(PC, l as u64) }
+            0x90 | 0x94 | 0x98 | 0x9C => {
+                let (a, b) = (Self::register_field(r1)?, Self::register_field(r2)?);
+                let f: &dyn Fn(i32, i32) -> Option<i32> = match ins.opcode {
+                    0x90 => &add,
+                    0x94 => &sub,
+                    0x98 => &mul,
+                    _ => &div
+                };
+                let v = self.get(a);
+                arith(self, b, f, v)?;
+            }
+            0xA0 => {
+                let (a, b) = (Self::register_field(r1)?, Self::register_field(r2)?);
+                let (x, y) = (self.get(a), self.get(b));
+                self.compare(x, y);
+            }
+            0xAC => {
+                let (a, b) = (Self::register_field(r1)?, Self::register_field(r2)?);
+                let v = self.machine.register(a);
+                self.set(b, v);
+            }
+            0xB4 => { let r = Self::register_field(r1)?; self.set(r, 0) }
+            0xB8 => {
+                let r = Self::register_field(r1)?;
+                let x = (self.get(X) + 1) & WORD;
+                self.set(X, x as u64);
+                let v = self.get(r);
+                self.compare(x, v);
+            }
+            0xA4 => {
+                // A circular shift.
+                let r = Self::register_field(r1)?;
+                let (v, n) = (self.get(r), r2 as u32 + 1);
+                self.set(r, ((v << n | v >> (24 - n)) & WORD) as u64);
+            }
+            0xA8 => {
+                // Vacated bits are filled with copies of the leftmost bit.
+                let r = Self::register_field(r1)?;
+                let (v, n) = (self.get(r), r2 as u32 + 1);
+                self.set(r, (signed(v) >> n) as u32 as u64);
+            }
+            0xE0 => {
+                self.privileged(ins)?;
+                let dev = value(self, 1)? as u8;
+                self.machine.device(dev);
+                self.io = Some(Io::Test(dev));
+                self.set_cc(Cc::Lt);
+            }
+            0xD8 => {
+                self.privileged(ins)?;
+                let dev = value(self, 1)? as u8;
+                let b = self.machine.device(dev).input.pop_front();
+                self.io = Some(b.map_or(Io::ReadPastEnd(dev), |b| Io::Read(dev, b)));
+                let b = b.unwrap_or(0);
+                let a = self.get(A) & 0xFFFF00 | b as u32;
+                self.set(A, a as u64);
+            }
+            0xDC => {
+                self.privileged(ins)?;
+                let dev = value(self, 1)? as u8;
+                let b = self.get(A) as u8;
+                self.machine.device(dev).output.push(b);
+                self.io = Some(Io::Write(dev, b));
+            }
+            0xB0 => self.raised = Some(Interrupt::Svc(r1)),
+            0xD0 => {
+                // The registers, in the order of an interrupt work area.
+                self.privileged(ins)?;
+                let at = target.unwrap_or(0);
+                let words = [(SW, 0), (PC, 3), (A, 6), (X, 9), (L, 0x0C), (B, 0x0F), (S, 0x12), (T, 0x15)];
+                let mut values = Vec::new();
+                for (r, offset) in words.iter() {
+                    values.push((*r, self.read(at + offset, 3)? as u64));
+                }
+                let f = (self.read(at + 0x18, 3)? as u64) << 24 | self.read(at + 0x1B, 3)? as u64;
+                values.push((F, f));
+                for (r, v) in values {
+                    self.set(r, v);
+                }
+            }
+            0xD4 => {
+                self.privileged(ins)?;
+                let v = value(self, 3)?;
+                self.machine.interrupts.timer = v;
+            }
+            0xEC => {
+                self.privileged(ins)?;
+                let at = target.unwrap_or(0);
+                let (old, new) = (self.machine.key(at), self.get(A) as u8 & 0x0F);
+                self.machine.set_key(at, new);
+                self.key = Some((at - at % KEY_BLOCK, old, new));
+            }
+            0xF0 | 0xF4 | 0xF8 => {
+                // SIO, HIO and TIO, on the channel in A.
+                self.privileged(ins)?;
+                let ch = self.get(A) as u8 & 0x0F;
+                let ints = &mut self.machine.interrupts;
+                match ins.opcode {
+                    0xF0 => {
+                        ints.channels.insert(ch, IO_TIME);
+                    }
+                    0xF4 => {
+                        ints.channels.remove(&ch);
+                        ints.pending.retain(|i| *i != Interrupt::Io(ch));
+                    }
+                    _ => {
+                        let busy = ints.channels.contains_key(&ch);
+                        self.set_cc(if busy { Cc::Eq } else { Cc::Lt });
+                    }
+                }
+            }
+            _ => return Err(Fault::Unsupported(ins.name))
+        }
+        Ok(target.filter(|_| !immediate))
+    }
+}
+
+#[test]
+fn run_program() {
+    // Copies the input of device F1 to device 05, a byte at a time, until a
+    // zero byte, then doubles the count with a subroutine.
+    let source = "\
+ECHO  START 1000h
+      CLEAR X
+LOOP  TD    #241
+      RD    #241
+      COMP  #0
+      JEQ   DONE
+      WD    OUT
+      TIX   #100
+      J     LOOP
+DONE  STX   COUNT
+      STL   RET
+      JSUB  DOUBLE
+      LDL   RET
+      RSUB
+DOUBLE LDA  COUNT
+      ADD   COUNT
+      STA   COUNT,X
+      SHIFTR A,1
+      RSUB
+OUT   BYTE  X'05'
+RET   RESW  1
+COUNT RESW  1
+      END   ECHO
+";
+    let result = super::Assembler::new().assemble("echo.asm", source);
+    assert!(result.is_ok(), "{}", result.render_diagnostics());
+    let mut m = Machine::new();
+    m.load(&result.program, 0x2000).unwrap();
+    m.device(0xF1).input.extend(b"hi\0");
+
+    let mut steps = Vec::new();
+    assert_eq!(m.run(1000, |_, s| steps.push(s.clone())), Stop::Exited);
+    assert_eq!(m.devices[&0x05].output, b"hi");
+    assert_eq!(m.register(Register::A), 2);
+    assert_eq!(m.register(Register::X), 2);
+
+    // COUNT is at 1038h, relocated to 2038h, and STA COUNT,X wrote 4 two
+    // bytes past it.
+    let sta = steps.iter().find(|s| s.instruction.name == "STA").unwrap();
+    assert_eq!(sta.target, Some(0x203A));
+    assert_eq!(sta.writes, vec![(0x203A, vec![2, 0, 0], vec![0, 0, 4])]);
+    assert_eq!(sta.instruction.disassemble(&|a| if a == 0x2038 { Some("COUNT".to_owned()) } else { None }), "STA COUNT,X");
+    let comp = steps.iter().rev().find(|s| s.instruction.name == "COMP").unwrap();
+    assert_eq!((comp.cc, comp.registers.clone()), (Cc::Eq, vec![(Register::PC, 0x2008, 0x200B), (Register::SW, 0x800000, 0x810000)]));
+
+    // Undoing every step restores the machine as loaded.
+    for s in steps.iter().rev() {
+        m.undo(s);
+    }
+    assert_eq!(m.pc(), 0x2000);
+    assert_eq!(m.register(Register::L) as u32, EXIT_ADDRESS);
+    assert_eq!(m.memory()[0x2035..0x203D], [0; 8]);
+
+    m.write_memory(0x2000, &[0xFC]).unwrap();
+    assert_eq!(m.run(10, |_, _| {}), Stop::Fault(0x2000, Fault::InvalidOpcode(0xFC)));
+
+    // An extended RSUB is four bytes long, and its operand is not used.
+    m.write_memory(0x2000, &[0x4E, 0x1F, 0xFF, 0xFF]).unwrap();
+    let rsub = m.decode(0x2000).unwrap();
+    assert_eq!((rsub.size(), rsub.disassemble(&|_| None)), (4, "+RSUB".to_owned()));
+    assert_eq!(m.step().map(|s| (s.target, s.reads)), Ok((None, vec![(0x2000, 4)])));
+}
+
+#[test]
+fn interrupts() {
+    // A kernel that protects its memory, starts a user process with the
+    // timer set, and takes the SVC, timer and program interrupts it causes.
+    let source = "\
+OS     START 0
+       J     BOOT
+LOW    RESB  253
+SVCNEW WORD  8388608
+SVCPTR WORD  SVCH
+SVCSW  RESW  1
+SVCREG RESB  39
+PRGNEW WORD  8388608
+PRGPTR WORD  PRGH
+PRGSW  RESW  1
+PRGREG RESB  39
+TIMNEW WORD  8388608
+TIMPTR WORD  TIMH
+TIMSW  RESW  1
+TIMPC  RESW  1
+TIMREG RESB  36
+BOOT   LDA   KEY
+       SSK   KVAR
+       STI   TEN
+       LPS   USER
+SVCH   LDA   SVCSW
+       AND   #255
+       STA   SVCNUM
+       LPS   SVCSW
+TIMH   LDA   #BAD
+       STA   TIMPC
+       LPS   TIMSW
+PRGH   LDA   PRGSW
+       AND   #255
+       STA   PCODE
+HALT   J     HALT
+USER   WORD  8192
+USERPC WORD  UPROG
+USERRG RESW  8
+UPROG  LDA   #5
+       SVC   7
+SPIN   TIX   #0
+       J     SPIN
+BAD    STA   KVAR
+       WD    DEV
+KEY    WORD  1
+TEN    WORD  10
+SVCNUM RESW  1
+PCODE  RESW  1
+KVAR   RESW  1
+DEV    BYTE  X'05'
+       END   OS
+";
+    let result = super::Assembler::new().assemble("os.asm", source);
+    assert!(result.is_ok(), "{}", result.render_diagnostics());
+    let symbol = |name: &str| result.symbols[name].mem_loc;
+    let word = |m: &Machine, name: &str| m.memory()[symbol(name) as usize..symbol(name) as usize + 3].iter().fold(0u32, |v, b| v << 8 | *b as u32);
+
+    let mut m = Machine::new();
+    m.load(&result.program, 0).unwrap();
+    let mut steps = Vec::new();
+    assert_eq!(m.run(1000, |_, s| steps.push(s.clone())), Stop::Halted);
+    let taken: Vec<Interrupt> = steps.iter().filter_map(|s| s.interrupt).collect();
+    assert_eq!(taken, vec![Interrupt::Svc(7), Interrupt::Timer, Interrupt::Program(PROTECTION_VIOLATION)]);
+    assert_eq!((word(&m, "SVCNUM"), word(&m, "PCODE")), (7, PROTECTION_VIOLATION as u32));
+    // The SVC saved the user's registers, and LPS put them back.
+    let svc = steps.iter().position(|s| s.interrupt == Some(Interrupt::Svc(7))).unwrap();
+    assert_eq!(steps[svc].registers.iter().find(|r| r.0 == Register::PC).unwrap().2, symbol("SVCH") as u64);
+    assert_eq!(steps[svc + 4].registers.iter().find(|r| r.0 == Register::A).unwrap().2, 5);
+    assert_eq!(m.register(Register::SW) as u32 & MODE, MODE);
+    assert_eq!(word(&m, "KVAR"), 0);
+
+    // Undoing every step puts back the storage key and the timer.
+    for s in steps.iter().rev() {
+        m.undo(s);
+    }
+    assert_eq!((m.key(symbol("KVAR")), m.interrupts.timer, m.pc()), (0, 0, 0));
+
+    // Without the storage key, the write goes through and the privileged WD
+    // is stopped instead.
+    m.write_memory(symbol("KEY"), &[0, 0, 0]).unwrap();
+    assert_eq!(m.run(1000, |_, _| {}), Stop::Halted);
+    assert_eq!(word(&m, "PCODE"), PRIVILEGED_INSTRUCTION as u32);
+    assert_eq!(m.devices.get(&5).map(|d| d.output.len()), None);
+
+    // A processor idle with everything masked waits forever.
+    let mut m = Machine::new();
+    m.set_register(Register::SW, (MODE | IDLE) as u64);
+    assert_eq!(m.run(10, |_, _| {}), Stop::Fault(0, Fault::Idle));
+}
@@ -0,0 +1,173 @@
+//! Snapshots of simulated machines.
+//!
+//! A snapshot is a text file holding everything needed to carry on a run
+//! later: the registers, the non-zero parts of memory, and for each device
+//! the input it has not read yet and the output it has written. The state of
+//! the interval timer, busy channels, pending interrupts and non-zero storage
+//! keys is kept too. The same
+//! machine always gives the same snapshot, so snapshots can be compared with
+//! `diff` and kept as expected results in tests.
+//!
+//! ```text
+//! yacc snapshot 1
+//! A 000003
+//! ...
+//! PC 001012
+//! SW 800000
+//! timer 000010
+//! channel 02 000005
+//! pending io 01
+//! key 001000 3
+//! device F1 input 0A
+//! device 05 output 6869
+//! memory 001000 17202D...
+//! ```
+
+use std::fmt::Write;
+
+use super::sim::{Interrupt, Machine, Register, KEY_BLOCK, MEMORY_SIZE};
+
+const HEADER: &str = "yacc snapshot 1";
+
+// The number of bytes on each memory line.
+const MEMORY_LINE: usize = 32;
+
+// `bytes` in hexadecimal, in upper or lower case.
+pub(crate) fn hex(bytes: &[u8], upper: bool) -> String {
+    bytes.iter().map(|b| if upper { format!("{:02X}", b) } else { format!("{:02x}", b) }).collect()
+}
+
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|k| u8::from_str_radix(&text[k..k + 2], 16).ok()).collect()
+}
+
+/// The snapshot of `machine`.
+pub fn save(machine: &Machine) -> String {
+    let mut out = format!("{}\n", HEADER);
+    for r in Register::ALL.iter() {
+        let width = if *r == Register::F { 12 } else { 6 };
+        let _ = writeln!(out, "{} {:0w$X}", r.name(), machine.register(*r), w = width);
+    }
+    if machine.interrupts.timer != 0 {
+        let _ = writeln!(out, "timer {:06X}", machine.interrupts.timer);
+    }
+    for (ch, left) in &machine.interrupts.channels {
+        let _ = writeln!(out, "channel {:02X} {:06X}", ch, left);
+    }
+    for i in &machine.interrupts.pending {
+        let _ = match i {
+            Interrupt::Io(ch) => writeln!(out, "pending io {:02X}", ch),
+            _ => writeln!(out, "pending timer")
+        };
+    }
+    for addr in (0..MEMORY_SIZE as u32).step_by(KEY_BLOCK as usize) {
+        if machine.key(addr) != 0 {
+            let _ = writeln!(out, "key {:06X} {:X}", addr, machine.key(addr));
+        }
+    }
+    for (n, d) in &machine.devices {
+        if !d.input.is_empty() {
+            let (a, b) = d.input.as_slices();
+            let _ = writeln!(out, "device {:02X} input {}{}", n, hex(a, true), hex(b, true));
+        }
+        if !d.output.is_empty() {
+            let _ = writeln!(out, "device {:02X} output {}", n, hex(&d.output, true));
+        }
+    }
+    for (k, chunk) in machine.memory().chunks(MEMORY_LINE).enumerate() {
+        if chunk.iter().any(|b| *b != 0) {
+            let _ = writeln!(out, "memory {:06X} {}", k * MEMORY_LINE, hex(chunk, true));
+        }
+    }
+    out
+}
+
+/// The machine saved in `snapshot`, or a message naming the first line that
+/// is not valid.
+pub fn restore(snapshot: &str) -> Result<Machine, String> {
+    let mut lines = snapshot.lines().enumerate();
+    if lines.next().map(|(_, l)| l.trim_end()) != Some(HEADER) {
+        return Err("not a snapshot".to_owned());
+    }
+    let mut machine = Machine::new();
+    for (k, line) in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let bad = || format!("line {}: invalid snapshot line `{}`", k + 1, line);
+        match fields[..] {
+            [] => {}
+            ["timer", left] => machine.interrupts.timer = u32::from_str_radix(left, 16).map_err(|_| bad())?,
+            ["channel", ch, left] => {
+                let ch = u8::from_str_radix(ch, 16).map_err(|_| bad())?;
+                machine.interrupts.channels.insert(ch, u32::from_str_radix(left, 16).map_err(|_| bad())?);
+            }
+            ["pending", "timer"] => machine.interrupts.pending.push(Interrupt::Timer),
+            ["pending", "io", ch] => machine.interrupts.pending.push(Interrupt::Io(u8::from_str_radix(ch, 16).map_err(|_| bad())?)),
+            ["key", addr, key] => {
+                let addr = u32::from_str_radix(addr, 16).map_err(|_| bad())?;
+                let key = u8::from_str_radix(key, 16).map_err(|_| bad())?;
+                if addr as usize >= MEMORY_SIZE || key > 0x0F {
+                    return Err(bad());
+                }
+                machine.set_key(addr, key);
+            }
+            ["device", n, what, data] => {
+                let n = u8::from_str_radix(n, 16).map_err(|_| bad())?;
+                let data = from_hex(data).ok_or_else(bad)?;
+                match what {
+                    "input" => machine.device(n).input.extend(data),
+                    "output" => machine.device(n).output.extend(data),
+                    _ => return Err(bad())
+                }
+            }
+            ["memory", addr, data] => {
+                let addr = u32::from_str_radix(addr, 16).map_err(|_| bad())?;
+                let data = from_hex(data).ok_or_else(bad)?;
+                if addr as usize + data.len() > MEMORY_SIZE {
+                    return Err(bad());
+                }
+                machine.write_memory(addr, &data).map_err(|_| bad())?;
+            }
+            [name, value] => {
+                let r = Register::from_name(name).ok_or_else(bad)?;
+                machine.set_register(r, u64::from_str_radix(value, 16).map_err(|_| bad())?);
+            }
+            _ => return Err(bad())
+        }
+    }
+    Ok(machine)
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mut m = Machine::new();
+    m.set_register(Register::A, 0x123);
+    m.set_register(Register::F, 0x4010_0000_0000);
+    m.write_memory(0x1000, &[0x17, 0x20, 0x2D]).unwrap();
+    m.write_memory(MEMORY_SIZE as u32 - 1, &[0xFF]).unwrap();
+    m.device(0xF1).input.extend(b"ab");
+    m.device(0xF1).input.pop_front();
+    m.device(5).output.extend(b"hi");
+    m.interrupts.timer = 0x10;
+    m.interrupts.channels.insert(2, 5);
+    m.interrupts.pending = vec![Interrupt::Io(1), Interrupt::Timer];
+    m.set_key(0x1234, 3);
+
+    let saved = save(&m);
+    assert!(saved.starts_with("yacc snapshot 1\nA 000123\nX 000000\n"));
+    assert!(saved.contains("\nF 401000000000\n"));
+    assert!(saved.contains("\nSW 800000\ntimer 000010\nchannel 02 000005\npending io 01\npending timer\nkey 001000 3\n"));
+    assert!(saved.contains("\ndevice 05 output 6869\ndevice F1 input 62\nmemory 001000 17202D00"));
+    assert!(saved.ends_with("00FF\n"));
+
+    let restored = restore(&saved).unwrap();
+    assert_eq!(save(&restored), saved);
+    assert_eq!(restored.devices[&0xF1].input, vec![b'b']);
+    assert_eq!((&restored.interrupts, restored.key(0x1000)), (&m.interrupts, 3));
+
+    assert_eq!(restore("A 000000\n").unwrap_err(), "not a snapshot");
+    assert_eq!(restore("yacc snapshot 1\nQ 1\n").unwrap_err(), "line 2: invalid snapshot line `Q 1`");
+    assert!(restore("yacc snapshot 1\nmemory FFFFFF 0102\n").is_err());
+}
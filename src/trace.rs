@@ -0,0 +1,204 @@
+//! Instruction traces of simulated programs.
+//!
+//! A `Tracer` turns the steps of a run into trace lines, in a table for
+//! reading or as one JSON object per line for tools. Addresses are shown with
+//! the labels of the assembled program, and the trace can be limited to a
+//! range of addresses or to the time spent in some subroutines.
+
+use std::collections::BTreeMap;
+
+use super::json::{object, Value};
+use super::line::Symtab;
+use super::sim::{Io, Register, Step};
+
+/// The format of a trace.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Format {
+    /// A line per instruction: address, label, instruction, target address,
+    /// changes and condition code.
+    Text,
+    /// A JSON object per instruction, one per line.
+    Json
+}
+
+/// The labels of a program loaded `offset` bytes from where it was
+/// assembled, by address. Where several labels share an address, the first
+/// one defined is used.
+pub fn labels(symtab: &Symtab, offset: u32) -> BTreeMap<u32, String> {
+    let mut by_line: Vec<(&String, &super::line::Pos)> = symtab.iter().filter(|(_, p)| p.val.is_none()).collect();
+    by_line.sort_by_key(|(name, p)| (p.line_no, name.as_str()));
+    let mut out = BTreeMap::new();
+    for (name, p) in by_line {
+        out.entry(p.mem_loc.wrapping_add(offset) & 0x00FFFFFF).or_insert_with(|| name.clone());
+    }
+    out
+}
+
+/// Turns steps into trace lines, keeping those that pass its filters.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    format: Format,
+    labels: BTreeMap<u32, String>,
+    range: Option<(u32, u32)>,
+    subroutines: Vec<u32>,
+    // The addresses of the subroutines called and not yet returned from.
+    calls: Vec<u32>
+}
+
+impl Tracer {
+    /// Traces every instruction, naming addresses with `labels`.
+    pub fn new(format: Format, labels: BTreeMap<u32, String>) -> Tracer {
+        Tracer { format, labels, range: None, subroutines: Vec::new(), calls: Vec::new() }
+    }
+
+    /// Only traces instructions at addresses from `from` up to, but not
+    /// including, `to`.
+    pub fn range(mut self, from: u32, to: u32) -> Self {
+        self.range = Some((from, to));
+        self
+    }
+
+    /// Only traces instructions run while the subroutine labelled `name` is
+    /// active: from the JSUB calling it to the RSUB returning from it, and
+    /// including the subroutines it calls. Can be given several times.
+    pub fn subroutine(mut self, name: &str) -> Result<Self, String> {
+        let addr = self.labels.iter().find(|(_, l)| *l == name).map(|(a, _)| *a);
+        self.subroutines.push(addr.ok_or_else(|| format!("there is no label {} to trace", name))?);
+        Ok(self)
+    }
+
+    /// The trace line for `step`, if it passes the filters.
+    pub fn record(&mut self, step: &Step) -> Option<String> {
+        if step.is_call() {
+            self.calls.push(step.target.unwrap_or(0));
+        }
+        let active = self.subroutines.is_empty() || self.calls.iter().any(|c| self.subroutines.contains(c));
+        if step.is_return() {
+            self.calls.pop();
+        }
+        let in_range = self.range.is_none_or(|(from, to)| (from..to).contains(&step.address));
+        if !active || !in_range {
+            return None;
+        }
+        Some(match self.format {
+            Format::Text => self.text(step),
+            Format::Json => self.json(step).to_string()
+        })
+    }
+
+    fn label(&self, addr: u32) -> Option<String> {
+        self.labels.get(&addr).cloned()
+    }
+
+    // The registers worth showing, in order: all but PC, unless the
+    // instruction jumped.
+    fn changed(&self, step: &Step) -> Vec<(Register, u64)> {
+        let next = (step.address + step.instruction.size()) as u64;
+        let mut out: Vec<(Register, u64)> = step.registers.iter()
+            .filter(|(r, _, new)| *r != Register::PC || *new != next)
+            .map(|(r, _, new)| (*r, *new))
+            .collect();
+        out.sort();
+        out
+    }
+
+    fn text(&self, step: &Step) -> String {
+        let mut changes: Vec<String> = self.changed(step).into_iter().map(|(r, new)| {
+            if r == Register::F { format!("F={:012X}", new) } else { format!("{}={:06X}", r.name(), new) }
+        }).collect();
+        for (addr, _, bytes) in &step.writes {
+            let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            changes.push(format!("[{:06X}]={}", addr, hex));
+        }
+        match step.io {
+            Some(Io::Read(dev, b)) => changes.push(format!("read {:02X} from {:02X}", b, dev)),
+            Some(Io::ReadPastEnd(dev)) => changes.push(format!("read past the end of {:02X}", dev)),
+            Some(Io::Write(dev, b)) => changes.push(format!("wrote {:02X} to {:02X}", b, dev)),
+            _ => {}
+        }
+        if let Some(i) = step.interrupt {
+            changes.push(format!("took {}", i));
+        }
+        let line = format!(
+            "{:06X}  {:<8} {:<20} {:<9} CC={}  {}",
+            step.address,
+            self.label(step.address).unwrap_or_default(),
+            step.instruction.disassemble(&|a| self.label(a)),
+            step.target.map_or(String::new(), |t| format!("ea={:06X}", t)),
+            step.cc.symbol(),
+            changes.join(" ")
+        );
+        line.trim_end().to_owned()
+    }
+
+    fn json(&self, step: &Step) -> Value {
+        let registers = self.changed(step).into_iter().map(|(r, new)| (r.name(), Value::Number(new as i64))).collect();
+        let writes = step.writes.iter().map(|(addr, _, bytes)| object(vec![
+            ("address", (*addr).into()),
+            ("bytes", Value::String(bytes.iter().map(|b| format!("{:02X}", b)).collect()))
+        ])).collect();
+        let io = match step.io {
+            Some(Io::Test(dev)) => object(vec![("device", (dev as u32).into()), ("op", "test".into())]),
+            Some(Io::Read(dev, b)) => object(vec![("device", (dev as u32).into()), ("op", "read".into()), ("byte", (b as u32).into())]),
+            Some(Io::ReadPastEnd(dev)) => object(vec![("device", (dev as u32).into()), ("op", "read".into()), ("byte", Value::Null)]),
+            Some(Io::Write(dev, b)) => object(vec![("device", (dev as u32).into()), ("op", "write".into()), ("byte", (b as u32).into())]),
+            None => Value::Null
+        };
+        object(vec![
+            ("address", step.address.into()),
+            ("label", self.label(step.address).map_or(Value::Null, Value::String)),
+            ("instruction", Value::String(step.instruction.disassemble(&|a| self.label(a)))),
+            ("target", step.target.map_or(Value::Null, |t| t.into())),
+            ("registers", object(registers)),
+            ("writes", Value::Array(writes)),
+            ("io", io),
+            ("interrupt", step.interrupt.map_or(Value::Null, |i| Value::String(i.to_string()))),
+            ("cc", step.cc.symbol().into())
+        ])
+    }
+}
+
+#[test]
+fn trace_filters() {
+    let source = "\
+MAIN  START 0
+      STL   RET
+      LDA   #2
+      JSUB  TWICE
+      JSUB  TWICE
+      LDL   RET
+      RSUB
+TWICE ADD   VALUE
+      STA   VALUE
+      RSUB
+VALUE WORD  1
+RET   RESW  1
+      END   MAIN
+";
+    let result = super::Assembler::new().assemble("main.asm", source);
+    let run = |tracer: &mut Tracer| {
+        let mut m = super::sim::Machine::new();
+        m.load(&result.program, 0).unwrap();
+        let mut out = Vec::new();
+        m.run(100, |_, s| out.extend(tracer.record(s)));
+        out
+    };
+    let labels = labels(&result.symbols, 0);
+
+    let all = run(&mut Tracer::new(Format::Text, labels.clone()));
+    assert_eq!(all.len(), 12);
+    assert_eq!(all[0], "000000  MAIN     STL RET              ea=00001E CC=<  [00001E]=FFFFFF");
+    assert_eq!(all[2], "000006           JSUB TWICE           ea=000012 CC=<  L=000009 PC=000012");
+    assert_eq!(all[4], "000015           STA VALUE            ea=00001B CC=<  [00001B]=000003");
+
+    let twice = run(&mut Tracer::new(Format::Text, labels.clone()).subroutine("TWICE").unwrap());
+    assert_eq!(twice.len(), 8);
+    assert!(twice[0].contains("JSUB TWICE") && twice[3].contains("RSUB"));
+    assert!(Tracer::new(Format::Text, labels.clone()).subroutine("NOPE").is_err());
+
+    let json = run(&mut Tracer::new(Format::Json, labels).range(0x15, 0x16));
+    assert_eq!(json, vec![
+        r#"{"address":21,"label":null,"instruction":"STA VALUE","target":27,"registers":{},"writes":[{"address":27,"bytes":"000003"}],"io":null,"interrupt":null,"cc":"<"}"#,
+        r#"{"address":21,"label":null,"instruction":"STA VALUE","target":27,"registers":{},"writes":[{"address":27,"bytes":"000006"}],"io":null,"interrupt":null,"cc":"<"}"#
+    ]);
+}
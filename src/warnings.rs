@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+
+use super::line::*;
+use super::diagnostic::{Diagnostic, Code, Field, Severity};
+use super::parser::Statement;
+
+/// A kind of suspicious but legal construct the assembler can warn about.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum Warning {
+    /// A label that no operand refers to.
+    UnreferencedLabel,
+    /// A label on a `BASE` or `NOBASE` directive, which has no address of its own.
+    LabelOnBase,
+    /// A `BASE` directive with no `LDB #value` loading the same value.
+    BaseWithoutLdb,
+    /// An instruction other than `J` or `RSUB` directly followed by data, so
+    /// execution runs into it.
+    FallIntoData,
+    /// A jump or subroutine call whose target is a data directive.
+    DataInCode,
+    /// A negative immediate operand stored as a truncated two's complement value.
+    TruncatedImmediate,
+    /// A mnemonic written in a mix of upper and lower case.
+    MixedCase
+}
+
+pub const ALL: &[Warning] = &[
+    Warning::UnreferencedLabel,
+    Warning::LabelOnBase,
+    Warning::BaseWithoutLdb,
+    Warning::FallIntoData,
+    Warning::DataInCode,
+    Warning::TruncatedImmediate,
+    Warning::MixedCase
+];
+
+impl Warning {
+    /// The name used to refer to this warning in `-W` options and pragmas.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Warning::UnreferencedLabel  => "unreferenced-label",
+            Warning::LabelOnBase        => "label-on-base",
+            Warning::BaseWithoutLdb     => "base-without-ldb",
+            Warning::FallIntoData       => "fall-into-data",
+            Warning::DataInCode         => "data-in-code",
+            Warning::TruncatedImmediate => "truncated-immediate",
+            Warning::MixedCase          => "mixed-case"
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::UnreferencedLabel  => "W001",
+            Warning::LabelOnBase        => "W002",
+            Warning::BaseWithoutLdb     => "W003",
+            Warning::FallIntoData       => "W004",
+            Warning::DataInCode         => "W005",
+            Warning::TruncatedImmediate => "W006",
+            Warning::MixedCase          => "W007"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Warning> {
+        ALL.iter().cloned().find(|w| w.name() == name)
+    }
+}
+
+/// Which warnings are reported, and which of those are treated as errors.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct WarningSet {
+    disabled: HashSet<Warning>,
+    errors: HashSet<Warning>,
+    all_errors: bool
+}
+
+impl WarningSet {
+    /// All warnings enabled, none of them errors.
+    pub fn new() -> WarningSet {
+        WarningSet::default()
+    }
+
+    pub fn enabled(&self, w: Warning) -> bool {
+        !self.disabled.contains(&w)
+    }
+
+    pub fn is_error(&self, w: Warning) -> bool {
+        self.all_errors || self.errors.contains(&w)
+    }
+
+    /// Applies a command line style option:
+    ///
+    /// * `-W<name>` / `-Wno-<name>` enable or disable a warning
+    /// * `-Wall` / `-Wnone` enable or disable every warning
+    /// * `-Werror` / `-Wno-error` turn every enabled warning into an error, or stop doing so
+    /// * `-Werror=<name>` / `-Wno-error=<name>` do the same for a single warning
+    pub fn apply(&mut self, opt: &str) -> Result<(), String> {
+        let body = match opt.strip_prefix("-W") {
+            Some(b) => b,
+            None => return Err(format!("`{}` is not a warning option", opt))
+        };
+        let lookup = |name: &str| Warning::from_name(name).ok_or_else(|| format!("unknown warning `{}`", name));
+        match body {
+            "all" => self.disabled.clear(),
+            "none" => self.disabled.extend(ALL.iter().cloned()),
+            "error" => self.all_errors = true,
+            "no-error" => {
+                self.all_errors = false;
+                self.errors.clear();
+            }
+            _ => {
+                if let Some(name) = body.strip_prefix("error=") {
+                    self.errors.insert(lookup(name)?);
+                } else if let Some(name) = body.strip_prefix("no-error=") {
+                    self.errors.remove(&lookup(name)?);
+                } else if let Some(name) = body.strip_prefix("no-") {
+                    self.disabled.insert(lookup(name)?);
+                } else {
+                    self.disabled.remove(&lookup(body)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The options in a `.pragma` comment, if `comment` is one.
+///
+/// A pragma is a comment of the form `.pragma -Wno-unreferenced-label ...`;
+/// its options apply from that line to the end of the source it appears in.
+pub fn pragma_options(comment: &str) -> Option<Vec<&str>> {
+    let mut words = comment.split_whitespace();
+    match words.next() {
+        Some("pragma") => Some(words.collect()),
+        _ => None
+    }
+}
+
+fn is_data(l: &Line) -> bool {
+    match l.operation {
+        source_op::Directive(ref x) => ["BYTE", "WORD", "RESB", "RESW"].contains(&&*x.name),
+        _ => false
+    }
+}
+
+fn instruction(l: &Line) -> Option<&op_struct> {
+    match l.operation {
+        source_op::Instruction(ref x) => Some(x),
+        _ => None
+    }
+}
+
+fn value_of(a: &arg, symtab: &Symtab) -> Option<i32> {
+    match a {
+        arg::Label(ref x) => symtab.get(x).map(|p| p.val.unwrap_or(p.mem_loc as i32)),
+        arg::IntLit(x) => Some(*x),
+        _ => None
+    }
+}
+
+/// Looks for the constructs described by `Warning` in an assembled program.
+///
+/// `origin[k]` is the source each line came from and `statements[k]` its
+/// syntax tree. Pragmas are applied on top of `settings`, which is restored at the
+/// start of every source. Each diagnostic is returned with the index of the
+/// line it refers to; its line number and field are set, but not its file.
+pub fn check(lines: &[Line], origin: &[usize], statements: &[Statement], symtab: &Symtab, settings: &WarningSet) -> Vec<(usize, Diagnostic)> {
+    let mut out = Vec::new();
+
+    // The warning settings in effect on each line.
+    let mut active = Vec::with_capacity(lines.len());
+    let mut curr = settings.clone();
+    for (k, l) in lines.iter().enumerate() {
+        if k > 0 && origin[k] != origin[k - 1] {
+            curr = settings.clone();
+        }
+        if let Some(opts) = pragma_options(&l.comment).filter(|_| l.format == format::Comment) {
+            for o in opts {
+                if let Err(e) = curr.apply(o) {
+                    let at = o.as_ptr() as usize - l.comment.as_ptr() as usize;
+                    out.push((k, Diagnostic::error(Code::InvalidPragma, &e).line(l.line_no).at(Field::Comment(at..at + o.len()))));
+                }
+            }
+        }
+        active.push(curr.clone());
+    }
+
+    let mut warn = |k: usize, w: Warning, field: Field, msg: String| {
+        if active[k].enabled(w) {
+            let severity = if active[k].is_error(w) { Severity::Error } else { Severity::Warning };
+            out.push((k, Diagnostic::new(severity, Code::Warning(w), &msg).line(lines[k].line_no).at(field)));
+        }
+    };
+
+    let mut referenced = HashSet::new();
+    for l in lines {
+        for a in &l.args {
+            referenced.extend(a.val.symbols());
+        }
+    }
+
+    for (k, l) in lines.iter().enumerate() {
+        let directive = l.operation.unwrap_as_directive();
+
+        if let Some(ref label) = l.label {
+            if directive != "START" && !label.is_empty() && !referenced.contains(&**label) {
+                warn(k, Warning::UnreferencedLabel, Field::Label, format!("label {} is never referenced", label));
+            }
+            if directive == "BASE" || directive == "NOBASE" {
+                warn(k, Warning::LabelOnBase, Field::Label, format!("the label {} on {} has no address of its own", label, directive));
+            }
+        }
+
+        if directive == "BASE" {
+            let base = l.args.first().and_then(|a| value_of(&a.val, symtab));
+            let loaded = lines.iter().filter_map(|m| instruction(m).map(|x| (m, x))).any(|(m, x)| {
+                x.name == "LDB"
+                    && m.args.first().is_some_and(|a| a.modifier == addr_mod::Immediate && value_of(&a.val, symtab) == base)
+            });
+            if base.is_some() && !loaded {
+                warn(k, Warning::BaseWithoutLdb, Field::Operand(0), "no LDB # loads register B with this value".to_owned());
+            }
+        }
+
+        if let Some(x) = instruction(l) {
+            if let Some(next) = lines[k + 1..].iter().find(|m| m.format != format::Comment) {
+                if is_data(next) && x.name != "J" && x.name != "RSUB" {
+                    warn(k, Warning::FallIntoData, Field::Mnemonic, format!("execution falls through from {} into data", x.name));
+                }
+            }
+
+            if x.name.starts_with('J') {
+                if let Some(arg::Label(ref target)) = l.args.first().map(|a| &a.val) {
+                    let target_line = lines.iter().find(|m| m.label.as_ref() == Some(target));
+                    if l.args[0].modifier != addr_mod::Indirect && target_line.is_some_and(is_data) {
+                        warn(k, Warning::DataInCode, Field::Operand(0), format!("{} jumps to {}, which is data", x.name, target));
+                    }
+                }
+            }
+
+            if let Some(a) = l.args.first().filter(|a| a.modifier == addr_mod::Immediate) {
+                let bits = if x.long { 20 } else { 12 };
+                match value_of(&a.val, symtab) {
+                    // Values are 24 bits wide, so negative ones have bit 23 set.
+                    Some(v) if v & 0x00800000 != 0 && v >= 0x01000000 - (1 << (bits - 1)) => {
+                        warn(
+                            k,
+                            Warning::TruncatedImmediate,
+                            Field::Operand(0),
+                            format!("negative immediate value {} stored as {:X} in {} bits", v - 0x01000000, v & ((1 << bits) - 1), bits)
+                        );
+                    }
+                    _ => {}
+                }
+            }
+
+            let written = statements[k].mnemonic.as_ref().map_or("", |m| &*m.name);
+            if written.chars().any(|c| c.is_ascii_uppercase()) && written.chars().any(|c| c.is_ascii_lowercase()) {
+                warn(k, Warning::MixedCase, Field::Mnemonic, format!("mnemonic {} is written in mixed case", written));
+            }
+        }
+    }
+
+    out.sort_by_key(|&(k, _)| k);
+    out
+}
+
+#[test]
+fn warning_options() {
+    let mut set = WarningSet::new();
+    assert!(set.enabled(Warning::MixedCase) && !set.is_error(Warning::MixedCase));
+    set.apply("-Wno-mixed-case").unwrap();
+    set.apply("-Werror=fall-into-data").unwrap();
+    assert!(!set.enabled(Warning::MixedCase));
+    assert!(set.is_error(Warning::FallIntoData) && !set.is_error(Warning::LabelOnBase));
+    set.apply("-Wall").unwrap();
+    assert!(set.enabled(Warning::MixedCase));
+    assert!(set.apply("-Wbogus").is_err());
+    assert_eq!(pragma_options(" pragma -Wnone -Werror"), Some(vec!["-Wnone", "-Werror"]));
+    assert_eq!(pragma_options(" ordinary comment"), None);
+
+    // Only the mnemonic itself is checked, not a label holding the same
+    // letters.
+    let src = "PROG  START 0\nxLda  LDA   BUF\nLDAx  Lda   BUF\nBUF   RESW  1\n      END   PROG\n";
+    let result = super::Assembler::new().warnings(set).assemble("case.asm", src);
+    let mixed: Vec<String> = result.diagnostics.iter().filter(|d| d.code == Code::Warning(Warning::MixedCase)).map(|d| d.to_string()).collect();
+    assert_eq!(mixed, vec!["case.asm:3:7: warning[W007]: mnemonic Lda is written in mixed case [-Wmixed-case]"]);
+}
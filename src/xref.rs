@@ -0,0 +1,132 @@
+use std::fmt::Write;
+
+use super::line::*;
+
+/// How an operand uses the symbol it names.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Access {
+    /// Read as data, or any use by a directive.
+    Read,
+    /// Stored to by one of the `ST` instructions.
+    Write,
+    /// The target of a jump or subroutine call.
+    Jump,
+    /// Used as an immediate (`#`) value.
+    Immediate
+}
+
+impl Access {
+    pub fn letter(&self) -> char {
+        match self {
+            Access::Read      => 'r',
+            Access::Write     => 'w',
+            Access::Jump      => 'j',
+            Access::Immediate => 'i'
+        }
+    }
+}
+
+/// Everything the cross-reference listing shows about one symbol.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub value: u32,
+    /// True for addresses, which move with the program, and false for
+    /// constants defined by `EQU`.
+    pub relative: bool,
+    pub defined: u32,
+    /// The line number of every reference, in source order.
+    pub references: Vec<(u32, Access)>
+}
+
+const STORES: &[&str] = &["STA", "STB", "STCH", "STF", "STL", "STS", "STSW", "STT", "STX"];
+
+// How the operands of `l` use the symbols they name. Indirect operands only
+// read the symbol, whatever the instruction does with the address it holds.
+fn access(l: &Line, a: &arg_struct) -> Access {
+    let name = match l.operation {
+        source_op::Instruction(ref x) => &*x.name,
+        _ => return Access::Read
+    };
+    match a.modifier {
+        addr_mod::Immediate => Access::Immediate,
+        addr_mod::Indirect | addr_mod::Literal => Access::Read,
+        addr_mod::Direct if name.starts_with('J') => Access::Jump,
+        addr_mod::Direct if STORES.contains(&name) => Access::Write,
+        addr_mod::Direct => Access::Read
+    }
+}
+
+/// Builds the cross-reference listing of every symbol in `symtab`, sorted by
+/// name.
+pub fn build(lines: &[Line], symtab: &Symtab) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = symtab.iter().map(|(name, p)| Entry {
+        name: name.clone(),
+        value: p.val.map_or(p.mem_loc, |v| v as u32 & 0x00FFFFFF),
+        relative: p.val.is_none(),
+        defined: p.line_no,
+        references: Vec::new()
+    }).collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for l in lines {
+        for (k, a) in l.args.iter().enumerate() {
+            // Register operands and index registers are not symbols.
+            if a.reg_code != 0xFF && (l.format == format::Register || k > 0) {
+                continue;
+            }
+            for x in a.val.symbols() {
+                if let Ok(i) = entries.binary_search_by(|e| (*e.name).cmp(x)) {
+                    entries[i].references.push((l.line_no, access(l, a)));
+                }
+            }
+        }
+    }
+    entries
+}
+
+/// Formats `entries` as a table, one symbol per line.
+pub fn render(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Symbol    Value   Type  Defined  References (r read, w write, j jump, i immediate)");
+    for e in entries {
+        let refs: Vec<String> = e.references.iter().map(|(n, a)| format!("{}{}", n, a.letter())).collect();
+        let row = format!(
+            "{:<8}  {:06X}  {:<4}  {:>7}  {}",
+            e.name,
+            e.value,
+            if e.relative { "R" } else { "A" },
+            e.defined,
+            refs.join(" ")
+        );
+        let _ = writeln!(out, "{}", row.trim_end());
+    }
+    out
+}
+
+#[test]
+fn cross_reference() {
+    let source = "\
+PROG     START   0
+FIRST    LDA    #LEN
+         STA     COUNT
+         LDX     COUNT
+         JSUB    FIRST
+         J      @PTR
+COUNT    RESW    1
+PTR      WORD    FIRST
+LEN      EQU     3
+         END     FIRST
+";
+    let result = super::Assembler::new().assemble("x.asm", source);
+    let xref = build(&result.lines, &result.symbols);
+    let find = |name: &str| xref.iter().find(|e| e.name == name).unwrap().clone();
+
+    let first = find("FIRST");
+    assert_eq!((first.value, first.relative, first.defined), (0, true, 2));
+    assert_eq!(first.references, vec![(5, Access::Jump), (8, Access::Read), (10, Access::Read)]);
+    assert_eq!(find("COUNT").references, vec![(3, Access::Write), (4, Access::Read)]);
+    assert_eq!(find("PTR").references, vec![(6, Access::Read)]);
+    let len = find("LEN");
+    assert_eq!((len.value, len.relative, len.references), (3, false, vec![(2, Access::Immediate)]));
+}